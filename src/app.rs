@@ -1,10 +1,14 @@
 #![allow(clippy::new_without_default)]
 
 use mut_rc::MutRc;
+use std::rc::Rc;
 use {egui_miniquad as egui_mq, miniquad as mq};
 
 use crate::darkroom::Darkroom;
-use crate::lighttable::LightTable;
+use crate::lighttable::{
+    db::{Database, ImageKey},
+    LightTable,
+};
 
 #[derive(Debug, Clone, Default)]
 pub enum CurrentView {
@@ -16,6 +20,8 @@ pub enum CurrentView {
 #[derive(Debug, Clone, Default)]
 pub struct EmulseState {
     pub selected_image_path: String,
+    /// Which version of the image, see [`crate::lighttable::db::ImageKey`]
+    pub selected_version: u32,
     pub current_view: CurrentView,
 }
 
@@ -29,6 +35,9 @@ pub struct App {
     light_table: LightTable,
     darkroom: Option<Darkroom>,
 
+    /// The catalog, shared by the views
+    db: Rc<Database>,
+
     state: MutRc<EmulseState>,
 }
 
@@ -39,7 +48,8 @@ impl App {
 
         let state = MutRc::new(EmulseState::default());
 
-        let light_table = LightTable::new(state.clone());
+        let db = Rc::new(Database::new());
+        let light_table = LightTable::new(state.clone(), db.clone());
         let darkroom = None;
 
         Self {
@@ -47,6 +57,7 @@ impl App {
             mq_ctx,
             darkroom,
             light_table,
+            db,
             state,
         }
     }
@@ -58,13 +69,23 @@ impl mq::EventHandler for App {
             CurrentView::Darkroom => match &self.darkroom {
                 Some(_) => {}
                 None => {
+                    let state = self.state.get_clone().unwrap();
                     let handle = self
                         .light_table
                         .texture_map
-                        .get(&self.state.get_clone().unwrap().selected_image_path)
+                        .get(&state.selected_image_path)
                         .unwrap();
-
-                    self.darkroom = Some(Darkroom::new(self.mq_ctx.as_mut(), handle.to_owned()));
+                    let key = ImageKey {
+                        path: state.selected_image_path.clone(),
+                        version: state.selected_version,
+                    };
+
+                    self.darkroom = Some(Darkroom::new(
+                        self.mq_ctx.as_mut(),
+                        self.db.clone(),
+                        key,
+                        handle.to_owned(),
+                    ));
                     self.darkroom.as_mut().unwrap().update(self.mq_ctx.as_mut());
                 }
             },
@@ -80,6 +101,7 @@ impl mq::EventHandler for App {
             },
             CurrentView::LightTable => {}
         }
+        let mut closed_darkroom = None;
         self.egui_mq.run(self.mq_ctx.as_mut(), |_, ctx| {
             egui_extras::install_image_loaders(ctx);
 
@@ -103,7 +125,12 @@ impl mq::EventHandler for App {
                             let lighttable =
                                 ui.add(egui::Button::new(egui::RichText::new("Lighttable")));
                             if lighttable.clicked() {
-                                self.darkroom = None;
+                                if let Some(darkroom) = self.darkroom.take() {
+                                    darkroom.save();
+                                    self.light_table.reload_versions();
+                                    self.light_table.reload_presets();
+                                    closed_darkroom = Some(darkroom);
+                                }
                                 let _ = self
                                     .state
                                     .with_mut(|state| state.current_view = CurrentView::LightTable);
@@ -122,6 +149,11 @@ impl mq::EventHandler for App {
         });
 
         self.egui_mq.draw(&mut *self.mq_ctx);
+
+        // Freed once egui is done with the frame, which doesn't lend the context
+        if let Some(darkroom) = closed_darkroom {
+            darkroom.delete(&mut *self.mq_ctx);
+        }
         self.mq_ctx.commit_frame();
     }
 
@@ -152,4 +184,10 @@ impl mq::EventHandler for App {
     fn key_up_event(&mut self, keycode: mq::KeyCode, keymods: mq::KeyMods) {
         self.egui_mq.key_up_event(keycode, keymods);
     }
+
+    fn quit_requested_event(&mut self) {
+        if let Some(darkroom) = &self.darkroom {
+            darkroom.save();
+        }
+    }
 }
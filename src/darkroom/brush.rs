@@ -0,0 +1,207 @@
+use miniquad as mq;
+use serde::{Deserialize, Serialize};
+
+/// The mask is smooth anyway, so there's no need to match the image size
+const MASK_MAX_SIZE: u32 = 1024;
+
+/// How far the mask can lighten or darken, in stops
+const MAX_STOPS: f32 = 2.0;
+
+/// How much a single dab at full flow changes the exposure, in stops
+const DAB_STOPS: f32 = 0.05;
+
+/// Distance between two dabs, relative to the radius of the brush
+const DAB_SPACING: f32 = 0.25;
+
+#[derive(Debug, Copy, Clone, PartialEq, Serialize, Deserialize)]
+pub enum BrushMode {
+    /// Holds back light from the paper, so it lightens
+    Dodge,
+    /// Gives more light to the paper, so it darkens
+    Burn,
+}
+
+impl BrushMode {
+    fn sign(&self) -> f32 {
+        match self {
+            BrushMode::Dodge => 1.0,
+            BrushMode::Burn => -1.0,
+        }
+    }
+}
+
+/// How the brush paints, shared by every dab of a stroke
+#[derive(Debug, Copy, Clone, PartialEq, Serialize, Deserialize)]
+pub struct BrushSettings {
+    pub mode: BrushMode,
+    /// Radius, relative to the width of the image
+    pub size: f32,
+    /// How soft the edge is, from 0 to 1
+    pub feather: f32,
+    /// How much every dab adds up, from 0 to 1
+    pub flow: f32,
+}
+
+impl Default for BrushSettings {
+    fn default() -> Self {
+        Self {
+            mode: BrushMode::Dodge,
+            size: 0.05,
+            feather: 0.5,
+            flow: 0.5,
+        }
+    }
+}
+
+/// A single stroke of the brush. Points are relative to the image, from 0 to 1
+/// with the origin on the top left corner, so they don't depend on its size.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct BrushStroke {
+    pub settings: BrushSettings,
+    pub points: Vec<[f32; 2]>,
+}
+
+/// The strokes replayed into a texture the shader can sample. Every texel
+/// holds the exposure change in stops, encoded around 0.5.
+pub struct DodgeBurnMask {
+    pub texture: mq::TextureId,
+
+    width: u32,
+    height: u32,
+    stops: Vec<f32>,
+
+    /// Distance left until the next dab of the stroke being painted
+    carry: f32,
+    dirty: bool,
+}
+
+impl DodgeBurnMask {
+    pub fn new(mq_ctx: &mut mq::Context, image_size: (u32, u32)) -> Self {
+        let scale = (MASK_MAX_SIZE as f32 / image_size.0.max(image_size.1) as f32).min(1.0);
+        let width = ((image_size.0 as f32 * scale) as u32).max(1);
+        let height = ((image_size.1 as f32 * scale) as u32).max(1);
+
+        let stops = vec![0.0; width as usize * height as usize];
+        let texture = mq_ctx.new_texture_from_rgba8(width as u16, height as u16, &encode(&stops));
+
+        Self {
+            texture,
+            width,
+            height,
+            stops,
+            carry: 0.0,
+            dirty: false,
+        }
+    }
+
+    pub fn delete(self, mq_ctx: &mut mq::Context) {
+        mq_ctx.delete_texture(self.texture);
+    }
+
+    /// Paints every stroke again from scratch
+    pub fn rebuild(&mut self, strokes: &[BrushStroke]) {
+        self.stops.fill(0.0);
+        self.dirty = true;
+
+        for stroke in strokes {
+            self.begin_stroke(stroke);
+            for points in stroke.points.windows(2) {
+                self.paint_segment(&stroke.settings, points[0], points[1]);
+            }
+        }
+    }
+
+    /// Paints the first dab of a stroke
+    pub fn begin_stroke(&mut self, stroke: &BrushStroke) {
+        self.carry = 0.0;
+
+        if let Some(first) = stroke.points.first() {
+            self.dab(&stroke.settings, *first);
+        }
+    }
+
+    /// Paints the dabs between two consecutive points of a stroke
+    pub fn paint_segment(&mut self, settings: &BrushSettings, from: [f32; 2], to: [f32; 2]) {
+        let from = self.to_texels(from);
+        let to = self.to_texels(to);
+        let length = ((to[0] - from[0]).powi(2) + (to[1] - from[1]).powi(2)).sqrt();
+        let spacing = (settings.size * self.width as f32 * DAB_SPACING).max(1.0);
+
+        let mut distance = spacing - self.carry;
+        while distance <= length {
+            let t = distance / length;
+            let texel = [
+                from[0] + (to[0] - from[0]) * t,
+                from[1] + (to[1] - from[1]) * t,
+            ];
+            self.dab_texels(settings, texel);
+            distance += spacing;
+        }
+
+        self.carry = length - (distance - spacing);
+    }
+
+    /// Sends the mask to the GPU if it changed since the last time, returns
+    /// whether it did
+    pub fn upload(&mut self, mq_ctx: &mut mq::Context) -> bool {
+        let dirty = std::mem::take(&mut self.dirty);
+        if dirty {
+            mq_ctx.texture_update(self.texture, &encode(&self.stops));
+        }
+
+        dirty
+    }
+
+    fn to_texels(&self, uv: [f32; 2]) -> [f32; 2] {
+        [uv[0] * self.width as f32, uv[1] * self.height as f32]
+    }
+
+    fn dab(&mut self, settings: &BrushSettings, uv: [f32; 2]) {
+        let texel = self.to_texels(uv);
+        self.dab_texels(settings, texel);
+    }
+
+    fn dab_texels(&mut self, settings: &BrushSettings, center: [f32; 2]) {
+        let radius = (settings.size * self.width as f32).max(0.5);
+        let inner = radius * (1.0 - settings.feather);
+        let amount = settings.mode.sign() * settings.flow * DAB_STOPS;
+
+        let min_x = (center[0] - radius).floor().max(0.0) as u32;
+        let max_x = ((center[0] + radius).ceil() as u32).min(self.width);
+        let min_y = (center[1] - radius).floor().max(0.0) as u32;
+        let max_y = ((center[1] + radius).ceil() as u32).min(self.height);
+
+        for y in min_y..max_y {
+            for x in min_x..max_x {
+                let dx = x as f32 + 0.5 - center[0];
+                let dy = y as f32 + 0.5 - center[1];
+                let d = (dx * dx + dy * dy).sqrt();
+                if d >= radius {
+                    continue;
+                }
+
+                let falloff = if d <= inner {
+                    1.0
+                } else {
+                    let t = (d - inner) / (radius - inner);
+                    1.0 - t * t * (3.0 - 2.0 * t)
+                };
+
+                let i = (y * self.width + x) as usize;
+                self.stops[i] = (self.stops[i] + amount * falloff).clamp(-MAX_STOPS, MAX_STOPS);
+            }
+        }
+
+        self.dirty = true;
+    }
+}
+
+fn encode(stops: &[f32]) -> Vec<u8> {
+    stops
+        .iter()
+        .flat_map(|s| {
+            let v = ((s / (2.0 * MAX_STOPS) + 0.5) * 255.0).round() as u8;
+            [v, v, v, 255]
+        })
+        .collect()
+}
@@ -0,0 +1,364 @@
+//! Profiling a scanner from a scan of a color target. The patches of the
+//! scan are measured, and a matrix mapping them to the reference values of
+//! the target is fitted and saved as an ICC profile.
+
+use std::{fs, io, path::Path};
+
+use cgmath::{InnerSpace, Matrix3, SquareMatrix, Vector3, Zero};
+use image::RgbImage;
+
+use crate::darkroom::{
+    color::{self, Transfer},
+    icc, sampler,
+};
+
+/// The white of the reference values, targets are measured under D50
+const D50_XYZ: [f32; 3] = [0.9642, 1.0, 0.8249];
+
+/// Tone curves tried when fitting, the one that fits best is kept
+const TRANSFERS: [Transfer; 4] = [
+    Transfer::Gamma(1.0),
+    Transfer::Gamma(1.8),
+    Transfer::Srgb,
+    Transfer::Gamma(2.2),
+];
+
+/// How much of a patch is measured, leaving its edges out
+const PATCH_COVERAGE: f32 = 0.5;
+
+/// L*a*b* values of the ColorChecker Classic under D50, row by row, as
+/// published by X-Rite for targets made after November 2014
+const COLORCHECKER_LAB: [[f32; 3]; 24] = [
+    [37.54, 14.37, 14.92],
+    [64.66, 19.27, 17.50],
+    [49.32, -3.82, -22.54],
+    [43.46, -12.74, 22.72],
+    [54.94, 9.61, -24.79],
+    [70.48, -32.26, -0.37],
+    [62.73, 35.83, 56.50],
+    [39.43, 10.75, -45.17],
+    [50.57, 48.64, 16.67],
+    [30.10, 22.54, -20.87],
+    [71.77, -24.13, 58.19],
+    [71.51, 18.24, 67.37],
+    [28.37, 15.42, -49.80],
+    [54.38, -39.72, 32.27],
+    [42.43, 51.05, 28.62],
+    [81.80, 2.67, 80.41],
+    [50.63, 51.28, -14.12],
+    [49.57, -29.71, -28.32],
+    [95.19, -1.03, 2.93],
+    [81.29, -0.57, 0.44],
+    [66.89, -0.75, -0.06],
+    [50.76, -0.13, 0.14],
+    [35.63, -0.46, -0.48],
+    [20.64, 0.07, -0.46],
+];
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum TargetLayout {
+    /// IT8.7/1 or IT8.7/2, without the grey scale at the bottom
+    It8,
+    ColorChecker,
+}
+
+impl TargetLayout {
+    pub const ALL: [TargetLayout; 2] = [TargetLayout::It8, TargetLayout::ColorChecker];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            TargetLayout::It8 => "IT8.7",
+            TargetLayout::ColorChecker => "ColorChecker",
+        }
+    }
+
+    /// Rows and columns of patches
+    pub fn size(&self) -> (usize, usize) {
+        match self {
+            TargetLayout::It8 => (12, 22),
+            TargetLayout::ColorChecker => (4, 6),
+        }
+    }
+}
+
+/// Where the patches are on the scan, given by the centers of the corner
+/// patches. Positions are relative to the image, from 0 to 1 with the origin
+/// on the top left corner.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct PatchGrid {
+    /// Top left, top right, bottom right and bottom left
+    pub corners: [[f32; 2]; 4],
+}
+
+impl Default for PatchGrid {
+    fn default() -> Self {
+        Self {
+            corners: [[0.2, 0.2], [0.8, 0.2], [0.8, 0.8], [0.2, 0.8]],
+        }
+    }
+}
+
+impl PatchGrid {
+    /// The center of a patch, the grid can be skewed by perspective or a
+    /// crooked scan
+    pub fn patch_center(&self, layout: TargetLayout, row: usize, column: usize) -> [f32; 2] {
+        let (rows, columns) = layout.size();
+        let u = column as f32 / (columns - 1) as f32;
+        let v = row as f32 / (rows - 1) as f32;
+        let [tl, tr, br, bl] = self.corners;
+
+        let lerp =
+            |a: [f32; 2], b: [f32; 2], t: f32| [a[0] + (b[0] - a[0]) * t, a[1] + (b[1] - a[1]) * t];
+        lerp(lerp(tl, tr, u), lerp(bl, br, u), v)
+    }
+}
+
+/// A fitted scanner profile
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Calibration {
+    /// Linear scanner RGB to XYZ, relative to D50
+    pub to_xyz_d50: Matrix3<f32>,
+    pub transfer: Transfer,
+    /// Color differences between the corrected patches and the reference,
+    /// in delta E 1976
+    pub mean_error: f32,
+    pub max_error: f32,
+}
+
+impl Calibration {
+    pub fn icc(&self, name: &str) -> Vec<u8> {
+        icc::matrix_profile(name, self.to_xyz_d50, self.transfer)
+    }
+}
+
+/// A correction for a camera and light source, fitted from a ColorChecker
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct CorrectionFit {
+    /// Linear working space RGB to corrected linear working space RGB
+    pub matrix: Matrix3<f32>,
+    /// Color differences left after the correction, in delta E 1976
+    pub mean_error: f32,
+    pub max_error: f32,
+}
+
+/// Reads the XYZ values of the patches from a CGATS reference file, the
+/// format target vendors ship them in. Files with only L*a*b* values are
+/// converted, assuming they're relative to D50 like the targets are.
+pub fn read_reference(path: &Path) -> io::Result<Vec<[f32; 3]>> {
+    let invalid = |what: &str| io::Error::new(io::ErrorKind::InvalidData, what.to_string());
+    let text = fs::read_to_string(path)?;
+
+    let section = |start: &str, end: &str| -> Option<Vec<&str>> {
+        let (_, rest) = text.split_once(start)?;
+        let (section, _) = rest.split_once(end)?;
+        Some(section.split_whitespace().collect())
+    };
+    let fields = section("BEGIN_DATA_FORMAT", "END_DATA_FORMAT")
+        .ok_or_else(|| invalid("no data format in the reference file"))?;
+    // The data section name is a prefix of the format one, so look after it
+    let data: Vec<&str> = text
+        .split_once("END_DATA_FORMAT")
+        .and_then(|(_, rest)| rest.split_once("BEGIN_DATA"))
+        .and_then(|(_, rest)| rest.split_once("END_DATA"))
+        .map(|(data, _)| data.split_whitespace().collect())
+        .ok_or_else(|| invalid("no data in the reference file"))?;
+
+    let column = |name: &str| fields.iter().position(|field| *field == name);
+    let (columns, lab) = match (column("XYZ_X"), column("XYZ_Y"), column("XYZ_Z")) {
+        (Some(x), Some(y), Some(z)) => ([x, y, z], false),
+        _ => match (column("LAB_L"), column("LAB_A"), column("LAB_B")) {
+            (Some(l), Some(a), Some(b)) => ([l, a, b], true),
+            _ => {
+                return Err(invalid(
+                    "the reference file has neither XYZ nor L*a*b* values",
+                ))
+            }
+        },
+    };
+
+    data.chunks_exact(fields.len())
+        .map(|row| {
+            let mut values = [0.0; 3];
+            for (value, column) in values.iter_mut().zip(columns) {
+                *value = row[column]
+                    .parse()
+                    .map_err(|_| invalid("a value in the reference file isn't a number"))?;
+            }
+
+            Ok(if lab {
+                lab_to_xyz(values)
+            } else {
+                values.map(|c| c / 100.0)
+            })
+        })
+        .collect()
+}
+
+/// The average color of every patch, row by row, from 0 to 1
+pub fn measure(image: &RgbImage, grid: &PatchGrid, layout: TargetLayout) -> Vec<[f32; 3]> {
+    let (rows, columns) = layout.size();
+    let (width, height) = (image.width() as f32, image.height() as f32);
+
+    // Patches are about as far apart as they are wide
+    let [tl, tr, _, bl] = grid.corners;
+    let across = (tr[0] - tl[0]).hypot(tr[1] - tl[1]) * width / (columns - 1) as f32;
+    let down = (bl[0] - tl[0]).hypot(bl[1] - tl[1]) * height / (rows - 1) as f32;
+    let half = (across.min(down) * PATCH_COVERAGE / 2.0).max(1.0) as i64;
+
+    let mut patches = Vec::with_capacity(rows * columns);
+    for row in 0..rows {
+        for column in 0..columns {
+            let [u, v] = grid.patch_center(layout, row, column);
+            let (cx, cy) = ((u * width) as i64, (v * height) as i64);
+
+            let mut sum = [0.0; 3];
+            let mut count = 0;
+            for y in (cy - half).max(0)..(cy + half).min(image.height() as i64) {
+                for x in (cx - half).max(0)..(cx + half).min(image.width() as i64) {
+                    let pixel = image.get_pixel(x as u32, y as u32);
+                    for (s, c) in sum.iter_mut().zip(pixel.0) {
+                        *s += c as f32 / 255.0;
+                    }
+                    count += 1;
+                }
+            }
+
+            patches.push(sum.map(|s| s / count.max(1) as f32));
+        }
+    }
+
+    patches
+}
+
+/// Finds the matrix that takes the measured patches closest to the
+/// reference, trying every tone curve. Needs at least as many patches as
+/// the matrix has unknowns.
+pub fn fit(measured: &[[f32; 3]], reference: &[[f32; 3]]) -> Option<Calibration> {
+    if measured.len() < 9 || reference.len() < measured.len() {
+        return None;
+    }
+
+    TRANSFERS
+        .into_iter()
+        .filter_map(|transfer| fit_matrix(measured, reference, transfer))
+        .min_by(|a, b| a.mean_error.total_cmp(&b.mean_error))
+}
+
+/// Least squares fit of XYZ = M * RGB, through the normal equations
+fn fit_matrix(
+    measured: &[[f32; 3]],
+    reference: &[[f32; 3]],
+    transfer: Transfer,
+) -> Option<Calibration> {
+    let linear: Vec<Vector3<f32>> = measured
+        .iter()
+        .map(|rgb| Vector3::from(rgb.map(|c| transfer.decode(c))))
+        .collect();
+
+    let reference: Vec<Vector3<f32>> = reference.iter().map(|xyz| Vector3::from(*xyz)).collect();
+    let to_xyz_d50 = least_squares(&linear, &reference)?;
+
+    let fitted: Vec<Vector3<f32>> = linear.iter().map(|rgb| to_xyz_d50 * *rgb).collect();
+    let (mean_error, max_error) = errors(&fitted, &reference);
+
+    Some(Calibration {
+        to_xyz_d50,
+        transfer,
+        mean_error,
+        max_error,
+    })
+}
+
+/// Fits a correction from the patches of a ColorChecker, measured in the
+/// working space. The correction is scaled so it leaves the exposure alone,
+/// as the reference frame is rarely exposed like the negatives are.
+pub fn fit_camera_correction(measured: &[[f32; 3]]) -> Option<CorrectionFit> {
+    if measured.len() != COLORCHECKER_LAB.len() {
+        return None;
+    }
+
+    let working = color::WORKING_SPACE;
+    let to_xyz = working.to_xyz_d50();
+    let to_rgb = to_xyz.invert()?;
+
+    let linear: Vec<Vector3<f32>> = measured
+        .iter()
+        .map(|rgb| Vector3::from(rgb.map(|c| working.transfer.decode(c))))
+        .collect();
+    let reference: Vec<Vector3<f32>> = COLORCHECKER_LAB
+        .iter()
+        .map(|lab| to_rgb * Vector3::from(lab_to_xyz(*lab)))
+        .collect();
+    let matrix = least_squares(&linear, &reference)?;
+
+    let fitted: Vec<Vector3<f32>> = linear.iter().map(|rgb| to_xyz * (matrix * *rgb)).collect();
+    let reference: Vec<Vector3<f32>> = reference.iter().map(|rgb| to_xyz * *rgb).collect();
+    let (mean_error, max_error) = errors(&fitted, &reference);
+
+    // How much brighter a neutral comes out, in luminance
+    let gain = (to_xyz * (matrix * Vector3::new(1.0, 1.0, 1.0))).y;
+    if gain <= 0.0 {
+        return None;
+    }
+
+    Some(CorrectionFit {
+        matrix: matrix / gain,
+        mean_error,
+        max_error,
+    })
+}
+
+/// The matrix M that brings M * source closest to the target, through the
+/// normal equations
+fn least_squares(source: &[Vector3<f32>], target: &[Vector3<f32>]) -> Option<Matrix3<f32>> {
+    let mut source_source = Matrix3::zero();
+    let mut target_source = Matrix3::zero();
+    for (s, t) in source.iter().zip(target) {
+        source_source += outer(*s, *s);
+        target_source += outer(*t, *s);
+    }
+
+    Some(target_source * source_source.invert()?)
+}
+
+/// Mean and maximum delta E between fitted and reference XYZ colors
+fn errors(fitted: &[Vector3<f32>], reference: &[Vector3<f32>]) -> (f32, f32) {
+    let errors: Vec<f32> = fitted
+        .iter()
+        .zip(reference)
+        .map(|(fitted, expected)| {
+            let fitted = sampler::xyz_to_lab((*fitted).into(), D50_XYZ);
+            let expected = sampler::xyz_to_lab((*expected).into(), D50_XYZ);
+            (Vector3::from(fitted) - Vector3::from(expected)).magnitude()
+        })
+        .collect();
+
+    (
+        errors.iter().sum::<f32>() / errors.len() as f32,
+        errors.iter().copied().fold(0.0, f32::max),
+    )
+}
+
+/// a * b transposed
+fn outer(a: Vector3<f32>, b: Vector3<f32>) -> Matrix3<f32> {
+    Matrix3::from_cols(a * b.x, a * b.y, a * b.z)
+}
+
+fn lab_to_xyz([l, a, b]: [f32; 3]) -> [f32; 3] {
+    const EPSILON: f32 = 216.0 / 24389.0;
+    const KAPPA: f32 = 24389.0 / 27.0;
+
+    let fy = (l + 16.0) / 116.0;
+    let fx = fy + a / 500.0;
+    let fz = fy - b / 200.0;
+    let inverse = |f: f32| {
+        if f.powi(3) > EPSILON {
+            f.powi(3)
+        } else {
+            (116.0 * f - 16.0) / KAPPA
+        }
+    };
+
+    let [xw, yw, zw] = D50_XYZ;
+    [inverse(fx) * xw, inverse(fy) * yw, inverse(fz) * zw]
+}
@@ -0,0 +1,459 @@
+use std::hash::Hash;
+
+use egui::{
+    emath::Rot2, load::SizedTexture, Color32, ImageOptions, PointerButton, Pos2, Rect, Response,
+    Sense, Shape, Stroke, Ui, Vec2,
+};
+
+use crate::darkroom::{
+    calibration::{PatchGrid, TargetLayout},
+    sampler::SamplePoint,
+    uniform::{MaskKind, MasksUniform},
+};
+
+/// How many points to use when drawing the outline of a radial mask
+const ELLIPSE_SEGMENTS: usize = 48;
+
+/// How far the view can zoom out and in, in screen points per image pixel
+const MIN_ZOOM: f32 = 0.01;
+const MAX_ZOOM: f32 = 32.0;
+
+/// How much one notch of the mouse wheel zooms
+const WHEEL_ZOOM_SPEED: f32 = 1.0 / 400.0;
+
+/// The long edge of the navigator, in points
+const NAVIGATOR_SIZE: f32 = 160.0;
+
+/// Ways of showing the original image next to the developed one
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum CompareMode {
+    Off,
+    SideBySide,
+    /// The original on the left of a line that can be dragged around
+    Split,
+    /// The original is shown while a key is held down
+    Hold,
+}
+
+impl CompareMode {
+    pub const ALL: [CompareMode; 4] = [
+        CompareMode::Off,
+        CompareMode::SideBySide,
+        CompareMode::Split,
+        CompareMode::Hold,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            CompareMode::Off => "Off",
+            CompareMode::SideBySide => "Side by side",
+            CompareMode::Split => "Split",
+            CompareMode::Hold => "Hold \\ for original",
+        }
+    }
+}
+
+/// Zoom levels that can be picked from the toolbar
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum ZoomPreset {
+    /// The whole image is visible
+    Fit,
+    /// The image covers the whole view
+    Fill,
+    /// One image pixel per screen pixel
+    Actual,
+    /// Two screen pixels per image pixel
+    Double,
+}
+
+impl ZoomPreset {
+    pub const ALL: [ZoomPreset; 4] = [
+        ZoomPreset::Fit,
+        ZoomPreset::Fill,
+        ZoomPreset::Actual,
+        ZoomPreset::Double,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            ZoomPreset::Fit => "Fit",
+            ZoomPreset::Fill => "Fill",
+            ZoomPreset::Actual => "100%",
+            ZoomPreset::Double => "200%",
+        }
+    }
+}
+
+/// Where the image sits in the view and how big it is
+#[derive(Debug, Copy, Clone)]
+pub struct ImageView {
+    /// Screen points per image pixel
+    zoom: f32,
+
+    /// Offset of the center of the image from the center of the view
+    pan: Vec2,
+
+    /// Fit and fill follow the size of the view until the user zooms or pans
+    preset: Option<ZoomPreset>,
+}
+
+impl Default for ImageView {
+    fn default() -> Self {
+        Self {
+            zoom: 1.0,
+            pan: Vec2::ZERO,
+            preset: Some(ZoomPreset::Fit),
+        }
+    }
+}
+
+impl ImageView {
+    pub fn set_preset(&mut self, preset: ZoomPreset) {
+        self.preset = Some(preset);
+        self.pan = Vec2::ZERO;
+    }
+
+    /// How much the image is magnified, where 1 is one image pixel per screen pixel
+    pub fn magnification(&self, pixels_per_point: f32) -> f32 {
+        self.zoom * pixels_per_point
+    }
+
+    /// Updates the zoom of the preset, if any, for the current view. The size
+    /// is the one of the image as shown, after rotating it.
+    pub fn update(&mut self, viewport: Rect, image_size: Vec2, pixels_per_point: f32) {
+        let fit = viewport.size() / image_size;
+
+        self.zoom = match self.preset {
+            Some(ZoomPreset::Fit) => fit.min_elem(),
+            Some(ZoomPreset::Fill) => fit.max_elem(),
+            Some(ZoomPreset::Actual) => 1.0 / pixels_per_point,
+            Some(ZoomPreset::Double) => 2.0 / pixels_per_point,
+            None => self.zoom,
+        };
+    }
+
+    /// Where the image is drawn, before rotating it
+    pub fn image_rect(&self, viewport: Rect, image_size: Vec2) -> Rect {
+        Rect::from_center_size(viewport.center() + self.pan, image_size * self.zoom)
+    }
+
+    /// Zooms by a factor, keeping the point under the anchor in place
+    pub fn zoom_at(&mut self, viewport: Rect, anchor: Pos2, factor: f32) {
+        let zoom = (self.zoom * factor).clamp(MIN_ZOOM, MAX_ZOOM);
+        let from_center = anchor - viewport.center();
+
+        self.pan = from_center - (from_center - self.pan) * zoom / self.zoom;
+        self.zoom = zoom;
+        self.preset = None;
+    }
+
+    /// Zooms by a factor around the center of the view
+    pub fn zoom_by(&mut self, factor: f32) {
+        let zoom = (self.zoom * factor).clamp(MIN_ZOOM, MAX_ZOOM);
+
+        self.pan *= zoom / self.zoom;
+        self.zoom = zoom;
+        self.preset = None;
+    }
+
+    pub fn pan_by(&mut self, delta: Vec2) {
+        self.pan += delta;
+        self.preset = None;
+    }
+
+    /// Zooms with the mouse wheel or a pinch, and pans by dragging with the
+    /// middle button, or the primary one when nothing else uses it
+    pub fn handle_input(
+        &mut self,
+        ui: &Ui,
+        viewport: Rect,
+        response: &Response,
+        pan_primary: bool,
+    ) {
+        if let Some(pos) = response.hover_pos() {
+            let (scroll, pinch) = ui.input(|i| (i.raw_scroll_delta.y, i.zoom_delta()));
+            // Ctrl + wheel already shows up as a pinch, don't zoom twice
+            let factor = if pinch != 1.0 {
+                pinch
+            } else {
+                (scroll * WHEEL_ZOOM_SPEED).exp()
+            };
+            if factor != 1.0 {
+                self.zoom_at(viewport, pos, factor);
+            }
+        }
+
+        if response.dragged_by(PointerButton::Middle)
+            || (pan_primary && response.dragged_by(PointerButton::Primary))
+        {
+            self.pan_by(response.drag_delta());
+        }
+    }
+}
+
+/// Maps positions inside the image, from 0 to 1 with the origin on the top
+/// left corner, to positions on screen and back
+#[derive(Debug, Copy, Clone)]
+pub struct ImageTransform {
+    /// Where the image is drawn, before rotating it
+    rect: Rect,
+    rotation: Rot2,
+}
+
+impl ImageTransform {
+    pub fn new(rect: Rect, angle: f32) -> Self {
+        Self {
+            rect,
+            rotation: Rot2::from_angle(angle),
+        }
+    }
+
+    pub fn to_screen(&self, uv: Pos2) -> Pos2 {
+        self.rect.center() + self.rotation * ((uv - Pos2::new(0.5, 0.5)) * self.rect.size())
+    }
+
+    pub fn to_uv(&self, pos: Pos2) -> Pos2 {
+        Pos2::new(0.5, 0.5)
+            + self.rotation.inverse() * (pos - self.rect.center()) / self.rect.size()
+    }
+
+    /// The width of the image on screen
+    pub fn width(&self) -> f32 {
+        self.rect.width()
+    }
+}
+
+/// A small overview of the whole image in the corner of the view, with the
+/// visible part outlined. Clicking or dragging on it moves the view there.
+pub fn navigator(
+    ui: &mut Ui,
+    viewport: Rect,
+    view: &mut ImageView,
+    transform: ImageTransform,
+    texture: SizedTexture,
+    angle: f32,
+) {
+    let whole_image_visible = [(0.0, 0.0), (1.0, 0.0), (1.0, 1.0), (0.0, 1.0)]
+        .into_iter()
+        .all(|(u, v)| {
+            viewport
+                .expand(0.5)
+                .contains(transform.to_screen(Pos2::new(u, v)))
+        });
+    if whole_image_visible {
+        return;
+    }
+
+    let rotated = rotated_size(texture.size, angle);
+    let size = rotated * (NAVIGATOR_SIZE / rotated.max_elem());
+    let margin = Vec2::splat(8.0);
+    let rect = Rect::from_min_size(viewport.max - margin - size, size);
+
+    let painter = ui.painter_at(viewport);
+    painter.rect_filled(rect.expand(2.0), 2.0, Color32::from_black_alpha(160));
+
+    // The image itself is drawn unrotated, so undo the swap of the sides
+    let unrotated = Rect::from_center_size(rect.center(), texture.size * (size.x / rotated.x));
+    let minimap = ImageTransform::new(unrotated, angle);
+    let options = ImageOptions {
+        rotation: Some((Rot2::from_angle(angle), Vec2::splat(0.5))),
+        ..Default::default()
+    };
+    egui::paint_texture_at(&painter, unrotated, &options, &texture);
+
+    let visible: Vec<Pos2> = [
+        viewport.left_top(),
+        viewport.right_top(),
+        viewport.right_bottom(),
+        viewport.left_bottom(),
+    ]
+    .into_iter()
+    .map(|corner| minimap.to_screen(transform.to_uv(corner)))
+    .collect();
+    ui.painter_at(rect).add(Shape::closed_line(
+        visible,
+        Stroke::new(1.5, Color32::WHITE),
+    ));
+
+    let response = ui.interact(rect, ui.id().with("navigator"), Sense::click_and_drag());
+    if let Some(pos) = response.interact_pointer_pos() {
+        // Move the view so that the picked point ends up in the middle
+        let target = transform.to_screen(minimap.to_uv(pos));
+        view.pan_by(viewport.center() - target);
+    }
+}
+
+/// The size of an image once rotated, only quarter turns swap the sides
+pub fn rotated_size(size: Vec2, angle: f32) -> Vec2 {
+    let quarter_turns = (angle / std::f32::consts::FRAC_PI_2).round() as i32;
+    if quarter_turns.rem_euclid(2) == 1 {
+        Vec2::new(size.y, size.x)
+    } else {
+        size
+    }
+}
+
+/// Draws the outline of a mask along with the handles to move it around
+pub fn mask_overlay(ui: &mut Ui, transform: ImageTransform, masks: &mut MasksUniform, i: usize) {
+    let stroke = Stroke::new(1.5, Color32::WHITE);
+    let [a, b, c, d] = masks.geometry[i];
+
+    match masks.kind(i) {
+        MaskKind::Linear => {
+            let start = Pos2::new(a, b);
+            let end = Pos2::new(c, d);
+
+            // Lines across the image where the gradient starts and ends
+            let across = (end - start).rot90().normalized() * 2.0;
+            for p in [start, end] {
+                ui.painter().line_segment(
+                    [
+                        transform.to_screen(p - across),
+                        transform.to_screen(p + across),
+                    ],
+                    stroke,
+                );
+            }
+            ui.painter().line_segment(
+                [transform.to_screen(start), transform.to_screen(end)],
+                Stroke::new(1.0, Color32::GRAY),
+            );
+
+            let start = handle(ui, transform, (i, 0), start);
+            let end = handle(ui, transform, (i, 1), end);
+            masks.geometry[i] = [start.x, start.y, end.x, end.y];
+        }
+        MaskKind::Radial => {
+            let center = Pos2::new(a, b);
+            let radius = Vec2::new(c, d);
+
+            let outline = (0..ELLIPSE_SEGMENTS)
+                .map(|s| {
+                    let t = s as f32 / ELLIPSE_SEGMENTS as f32 * std::f32::consts::TAU;
+                    transform.to_screen(center + radius * Vec2::angled(t))
+                })
+                .collect();
+            ui.painter().add(Shape::closed_line(outline, stroke));
+
+            let moved = handle(ui, transform, (i, 0), center);
+            let x = handle(ui, transform, (i, 1), center + Vec2::new(radius.x, 0.0));
+            let y = handle(ui, transform, (i, 2), center + Vec2::new(0.0, radius.y));
+            masks.geometry[i] = [
+                moved.x,
+                moved.y,
+                (x.x - center.x).abs().max(0.01),
+                (y.y - center.y).abs().max(0.01),
+            ];
+        }
+        MaskKind::None => {}
+    }
+}
+
+/// Draws the line between the original and the developed image, which can be
+/// dragged to move it. The split is the horizontal position inside the image.
+pub fn compare_split(ui: &mut Ui, transform: ImageTransform, split: &mut f32) {
+    let top = transform.to_screen(Pos2::new(*split, 0.0));
+    let bottom = transform.to_screen(Pos2::new(*split, 1.0));
+    ui.painter()
+        .line_segment([top, bottom], Stroke::new(2.0, Color32::WHITE));
+
+    let moved = handle(ui, transform, "split", Pos2::new(*split, 0.5));
+    *split = moved.x.clamp(0.0, 1.0);
+}
+
+/// Draws a crosshair with its number on every sample point, which can be
+/// dragged to move it. Right clicking a point removes it.
+pub fn sample_overlay(ui: &mut Ui, transform: ImageTransform, samples: &mut Vec<SamplePoint>) {
+    let mut removed = None;
+
+    for (i, sample) in samples.iter_mut().enumerate() {
+        let [u, v] = sample.position;
+        let pos = transform.to_screen(Pos2::new(u, v));
+        for (stroke, size) in [
+            (Stroke::new(3.0, Color32::BLACK), 9.0),
+            (Stroke::new(1.0, Color32::WHITE), 8.0),
+        ] {
+            ui.painter()
+                .line_segment([pos - Vec2::X * size, pos + Vec2::X * size], stroke);
+            ui.painter()
+                .line_segment([pos - Vec2::Y * size, pos + Vec2::Y * size], stroke);
+        }
+        ui.painter().text(
+            pos + Vec2::splat(8.0),
+            egui::Align2::LEFT_TOP,
+            format!("{}", i + 1),
+            egui::FontId::proportional(13.0),
+            Color32::WHITE,
+        );
+
+        let rect = Rect::from_center_size(pos, Vec2::splat(14.0));
+        let response = ui.interact(rect, ui.id().with(("sample", i)), Sense::click_and_drag());
+        if response.secondary_clicked() {
+            removed = Some(i);
+        }
+        if let Some(pointer) = response
+            .interact_pointer_pos()
+            .filter(|_| response.dragged())
+        {
+            let uv = transform.to_uv(pointer);
+            sample.position = [uv.x.clamp(0.0, 1.0), uv.y.clamp(0.0, 1.0)];
+        }
+    }
+
+    if let Some(i) = removed {
+        samples.remove(i);
+    }
+}
+
+/// Draws the patches of a color target where they'll be measured, with
+/// handles on the corner patches to line the grid up with the scan
+pub fn grid_overlay(
+    ui: &mut Ui,
+    transform: ImageTransform,
+    grid: &mut PatchGrid,
+    layout: TargetLayout,
+) {
+    let (rows, columns) = layout.size();
+    let stroke = Stroke::new(1.0, Color32::WHITE);
+
+    let outline = grid
+        .corners
+        .iter()
+        .map(|[u, v]| transform.to_screen(Pos2::new(*u, *v)))
+        .collect();
+    ui.painter().add(Shape::closed_line(outline, stroke));
+
+    for row in 0..rows {
+        for column in 0..columns {
+            let [u, v] = grid.patch_center(layout, row, column);
+            let pos = transform.to_screen(Pos2::new(u, v));
+            ui.painter()
+                .rect_stroke(Rect::from_center_size(pos, Vec2::splat(6.0)), 0.0, stroke);
+        }
+    }
+
+    for (i, corner) in grid.corners.iter_mut().enumerate() {
+        let moved = handle(ui, transform, ("grid", i), Pos2::new(corner[0], corner[1]));
+        *corner = [moved.x.clamp(0.0, 1.0), moved.y.clamp(0.0, 1.0)];
+    }
+}
+
+/// A small circle that can be dragged around, returns where it ended up
+fn handle(ui: &mut Ui, transform: ImageTransform, id: impl Hash, uv: Pos2) -> Pos2 {
+    let pos = transform.to_screen(uv);
+    let rect = Rect::from_center_size(pos, Vec2::splat(14.0));
+    let response = ui.interact(rect, ui.id().with(("handle", id)), Sense::drag());
+
+    let fill = if response.hovered() || response.dragged() {
+        Color32::WHITE
+    } else {
+        Color32::from_black_alpha(128)
+    };
+    ui.painter()
+        .circle(pos, 5.0, fill, Stroke::new(1.5, Color32::WHITE));
+
+    match response.interact_pointer_pos() {
+        Some(pointer) if response.dragged() => transform.to_uv(pointer),
+        _ => uv,
+    }
+}
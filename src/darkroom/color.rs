@@ -0,0 +1,506 @@
+//! Color management. Images are converted into a wide gamut working space
+//! when they're loaded, edited there in linear light, and converted to the
+//! output space at the end of the shader.
+
+use std::path::Path;
+
+use cgmath::{Matrix, Matrix3, SquareMatrix, Vector3};
+use image::{
+    DynamicImage, ImageBuffer, ImageDecoder, ImageReader, ImageResult, Rgb, RgbImage, Rgba,
+    RgbaImage,
+};
+use qcms::{DataType, Intent, Profile, Transform};
+use rayon::prelude::*;
+
+use crate::darkroom::icc;
+
+/// White points, as xy chromaticities
+pub const D65: [f32; 2] = [0.3127, 0.3290];
+pub const D50: [f32; 2] = [0.3457, 0.3585];
+
+pub const SRGB: RgbSpace = RgbSpace {
+    name: "sRGB",
+    primaries: [[0.64, 0.33], [0.30, 0.60], [0.15, 0.06]],
+    white: D65,
+    transfer: Transfer::Srgb,
+};
+
+pub const DISPLAY_P3: RgbSpace = RgbSpace {
+    name: "Display P3",
+    primaries: [[0.680, 0.320], [0.265, 0.690], [0.150, 0.060]],
+    white: D65,
+    transfer: Transfer::Srgb,
+};
+
+pub const ADOBE_RGB: RgbSpace = RgbSpace {
+    name: "Adobe RGB (1998)",
+    primaries: [[0.64, 0.33], [0.21, 0.71], [0.15, 0.06]],
+    white: D65,
+    transfer: Transfer::Gamma(563.0 / 256.0),
+};
+
+pub const PROPHOTO: RgbSpace = RgbSpace {
+    name: "ProPhoto RGB",
+    primaries: [[0.7347, 0.2653], [0.1596, 0.8404], [0.0366, 0.0001]],
+    white: D50,
+    transfer: Transfer::Gamma(1.8),
+};
+
+/// Rec. 2020 primaries, wide enough for anything film can hold. Images are
+/// developed in linear light, see [`LinearImage`], the 2.2 gamma is only
+/// used where the space has to fit in 8 bits, like ICC conversions.
+pub const WORKING_SPACE: RgbSpace = RgbSpace {
+    name: "Emulse working space (Rec. 2020)",
+    primaries: [[0.708, 0.292], [0.170, 0.797], [0.131, 0.046]],
+    white: D65,
+    transfer: Transfer::Gamma(2.2),
+};
+
+/// Pixels in the working space, in linear light. 16 bits per channel keep
+/// the shadows from banding once they're lifted.
+pub type LinearImage = ImageBuffer<Rgba<u16>, Vec<u16>>;
+
+/// Colors on each axis of the table images are converted through when qcms
+/// converts them, every fifth 8 bit value
+const CONVERSION_NODES: usize = 52;
+
+// from: http://www.brucelindbloom.com/index.html?Eqn_ChromAdapt.html
+const BRADFORD: [[f32; 3]; 3] = [
+    [0.8951, 0.2664, -0.1614],
+    [-0.7502, 1.7135, 0.0367],
+    [0.0389, -0.0685, 1.0296],
+];
+
+/// How the values of a color space relate to linear light
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Transfer {
+    /// The piecewise sRGB curve
+    Srgb,
+    Gamma(f32),
+}
+
+impl Transfer {
+    /// From encoded values to linear light
+    pub fn decode(&self, c: f32) -> f32 {
+        match self {
+            Transfer::Srgb if c <= 0.04045 => c / 12.92,
+            Transfer::Srgb => ((c + 0.055) / 1.055).powf(2.4),
+            Transfer::Gamma(gamma) => c.max(0.0).powf(*gamma),
+        }
+    }
+
+    /// From linear light to encoded values
+    pub fn encode(&self, c: f32) -> f32 {
+        match self {
+            Transfer::Srgb if c <= 0.0031308 => c * 12.92,
+            Transfer::Srgb => 1.055 * c.powf(1.0 / 2.4) - 0.055,
+            Transfer::Gamma(gamma) => c.max(0.0).powf(1.0 / gamma),
+        }
+    }
+
+    /// The same curve, as an ICC profile describes it
+    pub fn curve(&self) -> icc::Curve {
+        match self {
+            Transfer::Srgb => icc::Curve::Parametric([
+                2.4,
+                1.0 / 1.055,
+                0.055 / 1.055,
+                1.0 / 12.92,
+                0.04045,
+                0.0,
+                0.0,
+            ]),
+            Transfer::Gamma(gamma) => icc::Curve::Gamma(*gamma),
+        }
+    }
+}
+
+/// An RGB space made of three primaries, a white point and a tone curve
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct RgbSpace {
+    pub name: &'static str,
+    /// xy chromaticities of red, green and blue
+    pub primaries: [[f32; 2]; 3],
+    pub white: [f32; 2],
+    pub transfer: Transfer,
+}
+
+impl RgbSpace {
+    /// Turns linear RGB into XYZ, with the white of the space at Y = 1
+    pub fn to_xyz(&self) -> Matrix3<f32> {
+        let [r, g, b] = self.primaries.map(xy_to_xyz);
+        let primaries = Matrix3::from_cols(r, g, b);
+
+        let scale = primaries
+            .invert()
+            .expect("primaries aren't linearly independent")
+            * xy_to_xyz(self.white);
+
+        Matrix3::from_cols(r * scale.x, g * scale.y, b * scale.z)
+    }
+
+    /// Like [`RgbSpace::to_xyz`], adapted to the D50 white of ICC profiles
+    pub fn to_xyz_d50(&self) -> Matrix3<f32> {
+        adaptation(self.white, D50) * self.to_xyz()
+    }
+
+    /// An ICC profile describing the space
+    pub fn icc(&self) -> Vec<u8> {
+        icc::matrix_profile(self.name, self.to_xyz_d50(), self.transfer)
+    }
+
+    pub fn profile(&self) -> Box<Profile> {
+        Profile::new_from_slice(&self.icc(), false).expect("built-in profile is invalid")
+    }
+
+    /// The space as it's read back from [`RgbSpace::icc`], without rounding
+    pub fn matrix_shaper(&self) -> icc::MatrixShaper {
+        icc::MatrixShaper {
+            to_xyz_d50: self.to_xyz_d50(),
+            curves: [
+                self.transfer.curve(),
+                self.transfer.curve(),
+                self.transfer.curve(),
+            ],
+        }
+    }
+}
+
+/// Converts linear RGB from one space to another
+pub fn conversion(from: &RgbSpace, to: &RgbSpace) -> Matrix3<f32> {
+    let to_rgb = to
+        .to_xyz_d50()
+        .invert()
+        .expect("primaries aren't linearly independent");
+
+    to_rgb * from.to_xyz_d50()
+}
+
+/// Bradford chromatic adaptation between two white points, in XYZ
+pub fn adaptation(from: [f32; 2], to: [f32; 2]) -> Matrix3<f32> {
+    let bradford = Matrix3::from(BRADFORD).transpose();
+    let inverse = bradford.invert().expect("Bradford matrix is invertible");
+
+    let source = bradford * xy_to_xyz(from);
+    let destination = bradford * xy_to_xyz(to);
+    let gains = Matrix3::from_diagonal(Vector3::new(
+        destination.x / source.x,
+        destination.y / source.y,
+        destination.z / source.z,
+    ));
+
+    inverse * gains * bradford
+}
+
+fn xy_to_xyz([x, y]: [f32; 2]) -> Vector3<f32> {
+    Vector3::new(x / y, 1.0, (1.0 - x - y) / y)
+}
+
+/// Reads an image and converts it into the working space. A profile assigned
+/// to the roll wins over the one embedded in the file, and files without
+/// either are taken as sRGB.
+pub fn load_working_image(path: &Path, assigned: Option<&[u8]>) -> ImageResult<LinearImage> {
+    let mut decoder = ImageReader::open(path)?
+        .with_guessed_format()?
+        .into_decoder()?;
+    let embedded = decoder.icc_profile()?;
+    let mut pixels = DynamicImage::from_decoder(decoder)?.to_rgba16();
+
+    if !to_working_space(assigned.or(embedded.as_deref()), &mut pixels) {
+        log::warn!(
+            "couldn't read the profile of {}, using sRGB",
+            path.display()
+        );
+    }
+
+    Ok(pixels)
+}
+
+/// Whether an image holds next to no color, like a black and white negative
+/// scanned in color. A cast shared by the whole image, like the tint of the
+/// film base, doesn't count as color.
+pub fn is_monochrome(pixels: &LinearImage) -> bool {
+    // How far a pixel may stray from the cast on average, out of 255 once
+    // gamma encoded
+    const MAX_CHROMA: f32 = 3.0;
+    // Looking at this many pixels is plenty to tell
+    const SAMPLES: usize = 1 << 16;
+
+    let step = (pixels.len() / 4 / SAMPLES).max(1);
+    let opponents: Vec<[f32; 2]> = pixels
+        .pixels()
+        .step_by(step)
+        .map(|pixel| {
+            let [r, g, b, _] = pixel
+                .0
+                .map(|c| WORKING_SPACE.transfer.encode(c as f32 / u16::MAX as f32) * 255.0);
+            [r - g, b - g]
+        })
+        .collect();
+    if opponents.is_empty() {
+        return false;
+    }
+
+    let count = opponents.len() as f32;
+    let cast = opponents
+        .iter()
+        .fold([0.0, 0.0], |sum, [a, b]| [sum[0] + a, sum[1] + b])
+        .map(|sum| sum / count);
+    let chroma = opponents
+        .iter()
+        .map(|[a, b]| (a - cast[0]).abs() + (b - cast[1]).abs())
+        .sum::<f32>()
+        / count;
+
+    chroma < MAX_CHROMA
+}
+
+/// Converts pixels described by a profile into the working space, in place,
+/// taking them as sRGB without one. Returns false if the profile couldn't be
+/// read, they're taken as sRGB then too.
+///
+/// Profiles made of a matrix and tone curves, which is what most scanners
+/// and cameras come with, are converted in floating point and keep all 16
+/// bits. Others go through qcms, which only converts 8 bit values, see
+/// [`from_profile`].
+pub fn to_working_space(icc: Option<&[u8]>, pixels: &mut LinearImage) -> bool {
+    let Some(icc) = icc else {
+        from_matrix_shaper(&SRGB.matrix_shaper(), pixels);
+        return true;
+    };
+
+    if let Some(matrix_shaper) = icc::read_matrix_shaper(icc) {
+        from_matrix_shaper(&matrix_shaper, pixels);
+        return true;
+    }
+    match Profile::new_from_slice(icc, false) {
+        Some(profile) => {
+            from_profile(&profile, pixels);
+            true
+        }
+        None => {
+            from_matrix_shaper(&SRGB.matrix_shaper(), pixels);
+            false
+        }
+    }
+}
+
+/// Decodes every channel through its curve and converts the result with the
+/// matrices of both spaces
+fn from_matrix_shaper(input: &icc::MatrixShaper, pixels: &mut LinearImage) {
+    let curves: [Vec<f32>; 3] = [0, 1, 2].map(|channel| {
+        (0..=u16::MAX)
+            .map(|c| input.curves[channel].decode(c as f32 / u16::MAX as f32))
+            .collect()
+    });
+    let to_working = WORKING_SPACE
+        .to_xyz_d50()
+        .invert()
+        .expect("primaries aren't linearly independent")
+        * input.to_xyz_d50;
+
+    pixels.par_chunks_exact_mut(4).for_each(|pixel| {
+        let linear = Vector3::new(
+            curves[0][pixel[0] as usize],
+            curves[1][pixel[1] as usize],
+            curves[2][pixel[2] as usize],
+        );
+        let rgb: [f32; 3] = (to_working * linear).into();
+        for (c, value) in pixel.iter_mut().zip(rgb) {
+            *c = (value.clamp(0.0, 1.0) * u16::MAX as f32).round() as u16;
+        }
+    });
+}
+
+/// Converts through qcms. It only takes 8 bit values, so it converts a grid
+/// of colors and the pixels are interpolated between them. That keeps
+/// gradients smooth, but the colors are only as accurate as 8 bits with a
+/// 2.2 gamma at the nodes.
+fn from_profile(input: &Profile, pixels: &mut LinearImage) {
+    let table = conversion_table(input);
+    let scale = (CONVERSION_NODES - 1) as f32 / u16::MAX as f32;
+
+    pixels.par_chunks_exact_mut(4).for_each(|pixel| {
+        let position = [pixel[0], pixel[1], pixel[2]].map(|c| c as f32 * scale);
+        let rgb = interpolate(&table, position);
+        for (c, value) in pixel.iter_mut().zip(rgb) {
+            *c = (value.clamp(0.0, 1.0) * u16::MAX as f32).round() as u16;
+        }
+    });
+}
+
+/// The pixels with the curve of the working space, 8 bits per channel, as
+/// measuring the patches of a target expects them
+pub fn encode_working(pixels: &LinearImage) -> RgbImage {
+    let curve: Vec<u8> = (0..=u16::MAX)
+        .map(|c| {
+            let encoded = WORKING_SPACE.transfer.encode(c as f32 / u16::MAX as f32);
+            (encoded * 255.0).round() as u8
+        })
+        .collect();
+
+    let (width, height) = pixels.dimensions();
+    RgbImage::from_fn(width, height, |x, y| {
+        let [r, g, b, _] = pixels.get_pixel(x, y).0;
+        Rgb([curve[r as usize], curve[g as usize], curve[b as usize]])
+    })
+}
+
+/// Working space colors in linear light for a grid of colors described by
+/// the profile, see [`CONVERSION_NODES`]. Red changes fastest, then green.
+fn conversion_table(input: &Profile) -> Vec<[f32; 3]> {
+    let step = |i: usize| (i * 255 / (CONVERSION_NODES - 1)) as u8;
+    let mut grid = Vec::with_capacity(CONVERSION_NODES.pow(3) * 3);
+    for b in 0..CONVERSION_NODES {
+        for g in 0..CONVERSION_NODES {
+            for r in 0..CONVERSION_NODES {
+                grid.extend([step(r), step(g), step(b)]);
+            }
+        }
+    }
+
+    let mut output = WORKING_SPACE.profile();
+    output.precache_output_transform();
+    match Transform::new(input, &output, DataType::RGB8, Intent::Perceptual) {
+        Some(transform) => transform.apply(&mut grid),
+        // The values are taken as they are, which is right for sRGB at least
+        None => log::warn!("couldn't convert the image to the working space"),
+    }
+
+    grid.chunks_exact(3)
+        .map(|node| {
+            [node[0], node[1], node[2]].map(|c| WORKING_SPACE.transfer.decode(c as f32 / 255.0))
+        })
+        .collect()
+}
+
+/// Trilinear interpolation in a table from [`conversion_table`], at a
+/// position counted in nodes
+fn interpolate(table: &[[f32; 3]], position: [f32; 3]) -> [f32; 3] {
+    let index = position.map(|p| (p as usize).min(CONVERSION_NODES - 2));
+    let fraction = [0, 1, 2].map(|axis| position[axis] - index[axis] as f32);
+
+    let mut rgb = [0.0; 3];
+    for corner in 0..8 {
+        let offset = [corner & 1, (corner >> 1) & 1, (corner >> 2) & 1];
+        let weight: f32 = (0..3)
+            .map(|axis| match offset[axis] {
+                0 => 1.0 - fraction[axis],
+                _ => fraction[axis],
+            })
+            .product();
+        let [r, g, b] = [0, 1, 2].map(|axis| index[axis] + offset[axis]);
+        let node = table[(b * CONVERSION_NODES + g) * CONVERSION_NODES + r];
+
+        for (c, value) in rgb.iter_mut().zip(node) {
+            *c += weight * value;
+        }
+    }
+
+    rgb
+}
+
+/// Converts pixels in the working space to the space of a profile, in place.
+/// Returns false if the profile can't be converted to.
+pub fn from_working_space(output: &mut Profile, pixels: &mut RgbaImage) -> bool {
+    output.precache_output_transform();
+
+    convert(&WORKING_SPACE.profile(), output, pixels)
+}
+
+fn convert(input: &Profile, output: &Profile, pixels: &mut RgbaImage) -> bool {
+    match Transform::new(input, output, DataType::RGBA8, Intent::Perceptual) {
+        Some(transform) => {
+            transform.apply(pixels);
+            true
+        }
+        None => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Every 16 bit value a few steps apart on each channel
+    fn ramp() -> LinearImage {
+        LinearImage::from_fn(64, 64, |x, y| {
+            let c = |i: u32| (i * 1039).min(u16::MAX as u32) as u16;
+            Rgba([c(x), c(y), c((x * 64 + y) / 64), u16::MAX])
+        })
+    }
+
+    fn to_f32(c: u16) -> f32 {
+        c as f32 / u16::MAX as f32
+    }
+
+    #[test]
+    fn profiles_read_back() {
+        for space in [SRGB, DISPLAY_P3, ADOBE_RGB, PROPHOTO] {
+            let read = icc::read_matrix_shaper(&space.icc()).unwrap();
+
+            let matrix: [[f32; 3]; 3] = read.to_xyz_d50.into();
+            let expected: [[f32; 3]; 3] = space.to_xyz_d50().into();
+            for (column, expected) in matrix.iter().zip(expected) {
+                for (c, expected) in column.iter().zip(expected) {
+                    assert!((c - expected).abs() < 1e-4, "{}", space.name);
+                }
+            }
+            for curve in &read.curves {
+                for x in [0.0, 0.01, 0.2, 0.5, 0.9, 1.0] {
+                    let error = (curve.decode(x) - space.transfer.decode(x)).abs();
+                    assert!(error < 1e-3, "{} at {x}", space.name);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn srgb_converts_in_16_bits() {
+        let to_working = conversion(&SRGB, &WORKING_SPACE);
+        let to_srgb = conversion(&WORKING_SPACE, &SRGB);
+
+        // The built-in curve, and the one of the profile written for sRGB
+        for icc in [None, Some(SRGB.icc())] {
+            let original = ramp();
+            let mut pixels = original.clone();
+            assert!(to_working_space(icc.as_deref(), &mut pixels));
+
+            for (before, after) in original.pixels().zip(pixels.pixels()) {
+                let linear = Vector3::from(
+                    [before[0], before[1], before[2]].map(|c| SRGB.transfer.decode(to_f32(c))),
+                );
+                let expected: [f32; 3] = (to_working * linear).into();
+                let converted = [after[0], after[1], after[2]].map(to_f32);
+                for (c, expected) in converted.iter().zip(expected) {
+                    assert!((c - expected).abs() <= 1.5 / u16::MAX as f32);
+                }
+
+                // And back to where it started, as close as rounding the
+                // linear channels, and the colorants of the profile to 16
+                // fractional bits, allows where the curve is steep
+                let back: [f32; 3] = (to_srgb * Vector3::from(converted)).into();
+                for (c, original) in back.iter().zip([before[0], before[1], before[2]]) {
+                    let encode = |c: f32| SRGB.transfer.encode(c) * u16::MAX as f32;
+                    let rounding = 4.0 / u16::MAX as f32;
+                    let tolerance = encode(c.max(0.0) + rounding) - encode(c.max(0.0)) + 1.0;
+                    let c = encode(*c);
+                    assert!(
+                        (c - original as f32).abs() <= tolerance,
+                        "{c} isn't {original}"
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn unreadable_profiles_fall_back_to_srgb() {
+        let mut pixels = ramp();
+        let mut srgb = ramp();
+
+        assert!(!to_working_space(Some(b"not a profile"), &mut pixels));
+        to_working_space(None, &mut srgb);
+        assert_eq!(pixels, srgb);
+    }
+}
@@ -0,0 +1,438 @@
+use std::{
+    fmt, fs,
+    io::{self, Cursor, Write},
+    path::{Path, PathBuf},
+};
+
+use flate2::{write::ZlibEncoder, Compression};
+use image::{
+    codecs::{jpeg::JpegEncoder, png::PngEncoder, tiff::TiffEncoder},
+    DynamicImage, ExtendedColorType, ImageEncoder, ImageError, RgbImage, RgbaImage,
+};
+use qcms::Profile;
+
+use crate::darkroom::{
+    color::{self, RgbSpace},
+    uniform::OutputUniform,
+};
+
+/// JPEG segments hold at most 64KiB, minus the length, the signature and the
+/// sequence numbers
+const JPEG_ICC_CHUNK: usize = 65535 - 2 - JPEG_ICC_SIGNATURE.len() - 2;
+const JPEG_ICC_SIGNATURE: &[u8] = b"ICC_PROFILE\0";
+
+/// Where the IHDR chunk of a PNG ends, the profile has to come right after it
+const PNG_HEADER_END: usize = 8 + 4 + 4 + 13 + 4;
+
+/// The TIFF tag that holds an ICC profile
+const TIFF_ICC_TAG: u16 = 34675;
+/// TIFF field type for opaque bytes
+const TIFF_UNDEFINED: u16 = 7;
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum ExportFormat {
+    Jpeg,
+    Png,
+    Tiff,
+}
+
+impl ExportFormat {
+    pub const ALL: [ExportFormat; 3] = [ExportFormat::Jpeg, ExportFormat::Png, ExportFormat::Tiff];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            ExportFormat::Jpeg => "JPEG",
+            ExportFormat::Png => "PNG",
+            ExportFormat::Tiff => "TIFF",
+        }
+    }
+
+    fn extension(&self) -> &'static str {
+        match self {
+            ExportFormat::Jpeg => "jpg",
+            ExportFormat::Png => "png",
+            ExportFormat::Tiff => "tif",
+        }
+    }
+}
+
+/// The color space of exported files
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum OutputProfile {
+    Srgb,
+    DisplayP3,
+    AdobeRgb,
+    ProPhoto,
+    /// An .icc file, see [`ExportSettings::custom_profile_path`]
+    Custom,
+}
+
+impl OutputProfile {
+    pub const ALL: [OutputProfile; 5] = [
+        OutputProfile::Srgb,
+        OutputProfile::DisplayP3,
+        OutputProfile::AdobeRgb,
+        OutputProfile::ProPhoto,
+        OutputProfile::Custom,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            OutputProfile::Custom => "Custom .icc",
+            _ => self.space().unwrap().name,
+        }
+    }
+
+    /// The built-in space, custom profiles don't have one
+    pub fn space(&self) -> Option<RgbSpace> {
+        match self {
+            OutputProfile::Srgb => Some(color::SRGB),
+            OutputProfile::DisplayP3 => Some(color::DISPLAY_P3),
+            OutputProfile::AdobeRgb => Some(color::ADOBE_RGB),
+            OutputProfile::ProPhoto => Some(color::PROPHOTO),
+            OutputProfile::Custom => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ExportSettings {
+    pub format: ExportFormat,
+    pub profile: OutputProfile,
+    pub custom_profile_path: String,
+    /// JPEG quality, from 1 to 100
+    pub quality: u8,
+}
+
+impl Default for ExportSettings {
+    fn default() -> Self {
+        Self {
+            format: ExportFormat::Jpeg,
+            profile: OutputProfile::Srgb,
+            custom_profile_path: String::new(),
+            quality: 92,
+        }
+    }
+}
+
+impl ExportSettings {
+    /// What the shader has to convert to. Built-in spaces are converted on
+    /// the GPU, custom profiles are converted from the working space by
+    /// [`export`] instead.
+    pub fn output_uniform(&self) -> OutputUniform {
+        OutputUniform::new(&self.profile.space().unwrap_or(color::WORKING_SPACE))
+    }
+}
+
+#[derive(Debug)]
+pub enum ExportError {
+    Io(io::Error),
+    Image(ImageError),
+    /// The custom profile can't be read or converted to
+    Profile(String),
+}
+
+impl fmt::Display for ExportError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ExportError::Io(err) => err.fmt(f),
+            ExportError::Image(err) => err.fmt(f),
+            ExportError::Profile(path) => write!(f, "{path} isn't a usable RGB ICC profile"),
+        }
+    }
+}
+
+impl From<io::Error> for ExportError {
+    fn from(err: io::Error) -> Self {
+        ExportError::Io(err)
+    }
+}
+
+impl From<ImageError> for ExportError {
+    fn from(err: ImageError) -> Self {
+        ExportError::Image(err)
+    }
+}
+
+/// Saves the developed image next to the original, tagged with its profile.
+/// The pixels have to come from a render with
+/// [`ExportSettings::output_uniform`].
+pub fn export(
+    mut pixels: RgbaImage,
+    image_path: &str,
+    settings: &ExportSettings,
+) -> Result<PathBuf, ExportError> {
+    let icc = match settings.profile.space() {
+        Some(space) => space.icc(),
+        None => {
+            let path = &settings.custom_profile_path;
+            let icc = fs::read(path)?;
+            let mut profile = Profile::new_from_slice(&icc, false)
+                .ok_or_else(|| ExportError::Profile(path.clone()))?;
+            if !color::from_working_space(&mut profile, &mut pixels) {
+                return Err(ExportError::Profile(path.clone()));
+            }
+            icc
+        }
+    };
+
+    let rgb = DynamicImage::ImageRgba8(pixels).to_rgb8();
+    let bytes = encode(&rgb, settings, &icc)?;
+
+    let path = export_path(image_path, settings.format);
+    fs::write(&path, bytes)?;
+
+    Ok(path)
+}
+
+/// The file in the format of the settings, with the profile embedded
+fn encode(rgb: &RgbImage, settings: &ExportSettings, icc: &[u8]) -> Result<Vec<u8>, ExportError> {
+    let (width, height) = rgb.dimensions();
+    let bytes = match settings.format {
+        ExportFormat::Jpeg => {
+            let mut jpeg = vec![];
+            JpegEncoder::new_with_quality(&mut jpeg, settings.quality).write_image(
+                rgb,
+                width,
+                height,
+                ExtendedColorType::Rgb8,
+            )?;
+            embed_in_jpeg(jpeg, icc)?
+        }
+        ExportFormat::Png => {
+            let mut png = vec![];
+            PngEncoder::new(&mut png).write_image(rgb, width, height, ExtendedColorType::Rgb8)?;
+            embed_in_png(png, icc)?
+        }
+        ExportFormat::Tiff => {
+            let mut tiff = Cursor::new(vec![]);
+            TiffEncoder::new(&mut tiff).write_image(rgb, width, height, ExtendedColorType::Rgb8)?;
+            embed_in_tiff(tiff.into_inner(), icc)?
+        }
+    };
+
+    Ok(bytes)
+}
+
+fn export_path(image_path: &str, format: ExportFormat) -> PathBuf {
+    let path = Path::new(image_path);
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+
+    path.with_file_name(format!("{stem}_emulse.{}", format.extension()))
+}
+
+/// Splits the profile into APP2 segments, right after the JFIF header.
+/// Segments are numbered with a byte, so profiles can't take more than 255.
+fn embed_in_jpeg(mut jpeg: Vec<u8>, icc: &[u8]) -> io::Result<Vec<u8>> {
+    let mut at = 2;
+    if jpeg[2..4] == [0xFF, 0xE0] {
+        at += 2 + u16::from_be_bytes([jpeg[4], jpeg[5]]) as usize;
+    }
+
+    let count = u8::try_from((icc.len() + JPEG_ICC_CHUNK - 1) / JPEG_ICC_CHUNK).map_err(|_| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "ICC profile too big to embed in a JPEG",
+        )
+    })?;
+    let mut segments = vec![];
+    for (i, chunk) in icc.chunks(JPEG_ICC_CHUNK).enumerate() {
+        let length = 2 + JPEG_ICC_SIGNATURE.len() + 2 + chunk.len();
+
+        segments.extend([0xFF, 0xE2]);
+        segments.extend((length as u16).to_be_bytes());
+        segments.extend(JPEG_ICC_SIGNATURE);
+        // Sequence numbers start at 1
+        segments.extend([i as u8 + 1, count]);
+        segments.extend(chunk);
+    }
+
+    jpeg.splice(at..at, segments);
+    Ok(jpeg)
+}
+
+/// Adds a compressed iCCP chunk after the header
+fn embed_in_png(mut png: Vec<u8>, icc: &[u8]) -> io::Result<Vec<u8>> {
+    let mut data = b"ICC profile\0".to_vec();
+    // Compression method, deflate is the only one
+    data.push(0);
+    let mut zlib = ZlibEncoder::new(data, Compression::default());
+    zlib.write_all(icc)?;
+    let data = zlib.finish()?;
+
+    let mut chunk = vec![];
+    chunk.extend((data.len() as u32).to_be_bytes());
+    chunk.extend(b"iCCP");
+    chunk.extend(&data);
+    chunk.extend(crc32fast::hash(&chunk[4..]).to_be_bytes());
+
+    png.splice(PNG_HEADER_END..PNG_HEADER_END, chunk);
+    Ok(png)
+}
+
+/// Appends the profile and a copy of the first directory with the profile
+/// tag added, then points the header at the new directory. The old entries
+/// keep pointing at their data, which doesn't move.
+pub fn embed_in_tiff(mut tiff: Vec<u8>, icc: &[u8]) -> io::Result<Vec<u8>> {
+    let invalid = |what: &str| io::Error::new(io::ErrorKind::InvalidData, what.to_string());
+
+    let big_endian = match tiff.get(..2) {
+        Some(b"II") => false,
+        Some(b"MM") => true,
+        _ => return Err(invalid("not a TIFF file")),
+    };
+    let read_u16 = |tiff: &[u8], at: usize| {
+        let bytes = [tiff[at], tiff[at + 1]];
+        if big_endian {
+            u16::from_be_bytes(bytes)
+        } else {
+            u16::from_le_bytes(bytes)
+        }
+    };
+    let read_u32 = |tiff: &[u8], at: usize| {
+        let bytes = [tiff[at], tiff[at + 1], tiff[at + 2], tiff[at + 3]];
+        if big_endian {
+            u32::from_be_bytes(bytes)
+        } else {
+            u32::from_le_bytes(bytes)
+        }
+    };
+    let u16_bytes = |v: u16| {
+        if big_endian {
+            v.to_be_bytes()
+        } else {
+            v.to_le_bytes()
+        }
+    };
+    let u32_bytes = |v: u32| {
+        if big_endian {
+            v.to_be_bytes()
+        } else {
+            v.to_le_bytes()
+        }
+    };
+
+    if tiff.len() < 8 {
+        return Err(invalid("truncated TIFF header"));
+    }
+    let directory = read_u32(&tiff, 4) as usize;
+    if tiff.len() < directory + 2 {
+        return Err(invalid("TIFF directory out of the file"));
+    }
+    let count = read_u16(&tiff, directory) as usize;
+    let entries_end = directory + 2 + count * 12;
+    if tiff.len() < entries_end + 4 {
+        return Err(invalid("truncated TIFF directory"));
+    }
+    let mut entries: Vec<&[u8]> = tiff[directory + 2..entries_end].chunks(12).collect();
+    let next_directory = read_u32(&tiff, entries_end);
+
+    // Everything has to start on a word boundary
+    let icc_offset = (tiff.len() + 1) & !1;
+    let directory_offset = (icc_offset + icc.len() + 1) & !1;
+
+    let mut icc_entry = vec![];
+    icc_entry.extend(u16_bytes(TIFF_ICC_TAG));
+    icc_entry.extend(u16_bytes(TIFF_UNDEFINED));
+    icc_entry.extend(u32_bytes(icc.len() as u32));
+    icc_entry.extend(u32_bytes(icc_offset as u32));
+    entries.push(&icc_entry);
+    // Tags have to be in ascending order
+    entries.sort_by_key(|entry| read_u16(entry, 0));
+
+    let mut new_directory = vec![];
+    new_directory.extend(u16_bytes(entries.len() as u16));
+    for entry in entries {
+        new_directory.extend(entry);
+    }
+    new_directory.extend(u32_bytes(next_directory));
+
+    tiff.resize(icc_offset, 0);
+    tiff.extend(icc);
+    tiff.resize(directory_offset, 0);
+    tiff.extend(new_directory);
+    tiff[4..8].copy_from_slice(&u32_bytes(directory_offset as u32));
+
+    Ok(tiff)
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{ImageDecoder, ImageReader, Rgb};
+
+    use super::*;
+
+    /// Big enough for the profiles, the TIFF decoder refuses tags holding
+    /// much more than the image's pixels
+    fn gradient() -> RgbImage {
+        RgbImage::from_fn(512, 256, |x, y| Rgb([(x / 2) as u8, y as u8, 128]))
+    }
+
+    fn settings(format: ExportFormat) -> ExportSettings {
+        ExportSettings {
+            format,
+            ..Default::default()
+        }
+    }
+
+    /// What a decoder finds in the file, after checking it still decodes
+    fn embedded_profile(bytes: Vec<u8>) -> Option<Vec<u8>> {
+        let mut decoder = ImageReader::new(Cursor::new(bytes))
+            .with_guessed_format()
+            .unwrap()
+            .into_decoder()
+            .unwrap();
+        let icc = decoder.icc_profile().unwrap();
+
+        let decoded = DynamicImage::from_decoder(decoder).unwrap();
+        assert_eq!(decoded.width(), 512);
+        assert_eq!(decoded.height(), 256);
+
+        icc
+    }
+
+    #[test]
+    fn profiles_round_trip() {
+        let icc = color::DISPLAY_P3.icc();
+
+        for format in ExportFormat::ALL {
+            let bytes = encode(&gradient(), &settings(format), &icc).unwrap();
+            assert_eq!(
+                embedded_profile(bytes),
+                Some(icc.clone()),
+                "{}",
+                format.name()
+            );
+        }
+    }
+
+    #[test]
+    fn jpeg_splits_big_profiles() {
+        let icc: Vec<u8> = (0..3 * JPEG_ICC_CHUNK + 100).map(|i| i as u8).collect();
+
+        let bytes = encode(&gradient(), &settings(ExportFormat::Jpeg), &icc).unwrap();
+        assert_eq!(embedded_profile(bytes), Some(icc));
+    }
+
+    #[test]
+    fn jpeg_refuses_profiles_over_255_segments() {
+        let mut jpeg = vec![];
+        JpegEncoder::new(&mut jpeg)
+            .write_image(&gradient(), 512, 256, ExtendedColorType::Rgb8)
+            .unwrap();
+
+        let largest = vec![0; 255 * JPEG_ICC_CHUNK];
+        assert!(embed_in_jpeg(jpeg.clone(), &largest).is_ok());
+        let too_big = vec![0; 255 * JPEG_ICC_CHUNK + 1];
+        assert!(embed_in_jpeg(jpeg, &too_big).is_err());
+    }
+
+    #[test]
+    fn tiff_rejects_directories_out_of_the_file() {
+        let mut tiff = b"II*\0".to_vec();
+        tiff.extend(1000_u32.to_le_bytes());
+
+        assert!(embed_in_tiff(tiff, b"icc").is_err());
+        assert!(embed_in_tiff(b"II*\0".to_vec(), b"icc").is_err());
+    }
+}
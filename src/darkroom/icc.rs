@@ -0,0 +1,292 @@
+//! Writes ICC profiles for RGB spaces made of a matrix and a tone curve, which
+//! is all the built-in spaces need. The profiles follow version 2 of the
+//! format, which every reader understands. Profiles of that kind are also
+//! read back, so images can be converted with more than the 8 bits qcms
+//! works with.
+
+use cgmath::{Matrix3, Vector3};
+
+use crate::darkroom::color::Transfer;
+
+/// D50, the white point of the profile connection space
+const D50_XYZ: [f32; 3] = [0.9642, 1.0, 0.8249];
+
+const HEADER_SIZE: usize = 128;
+
+/// Entries of the tone curve when it isn't a plain gamma
+const CURVE_POINTS: usize = 1024;
+
+/// A display class RGB profile. The matrix turns linear RGB into XYZ
+/// adapted to D50, its columns are the colorants.
+pub fn matrix_profile(description: &str, to_xyz_d50: Matrix3<f32>, transfer: Transfer) -> Vec<u8> {
+    let curve = curve_tag(transfer);
+    let tags: Vec<([u8; 4], Vec<u8>)> = vec![
+        (*b"desc", description_tag(description)),
+        (*b"cprt", text_tag("No copyright, use freely")),
+        (*b"wtpt", xyz_tag(D50_XYZ)),
+        (*b"rXYZ", xyz_tag(to_xyz_d50.x.into())),
+        (*b"gXYZ", xyz_tag(to_xyz_d50.y.into())),
+        (*b"bXYZ", xyz_tag(to_xyz_d50.z.into())),
+        (*b"rTRC", curve.clone()),
+        (*b"gTRC", curve.clone()),
+        (*b"bTRC", curve),
+    ];
+
+    let table_size = 4 + tags.len() * 12;
+    let mut data = vec![];
+    let mut table = vec![];
+    table.extend((tags.len() as u32).to_be_bytes());
+
+    for (signature, tag) in &tags {
+        let offset = HEADER_SIZE + table_size + data.len();
+        table.extend(signature);
+        table.extend((offset as u32).to_be_bytes());
+        table.extend((tag.len() as u32).to_be_bytes());
+
+        data.extend(tag);
+        // Every tag starts on a 4 byte boundary
+        data.resize((data.len() + 3) & !3, 0);
+    }
+
+    let size = HEADER_SIZE + table.len() + data.len();
+    let mut profile = header(size);
+    profile.extend(table);
+    profile.extend(data);
+
+    profile
+}
+
+/// The colorants and tone curves of an RGB profile made of a matrix and a
+/// tone curve per channel
+#[derive(Debug, Clone, PartialEq)]
+pub struct MatrixShaper {
+    /// Turns linear RGB into XYZ adapted to D50, its columns are the colorants
+    pub to_xyz_d50: Matrix3<f32>,
+    pub curves: [Curve; 3],
+}
+
+/// A tone curve, from encoded values to linear light
+#[derive(Debug, Clone, PartialEq)]
+pub enum Curve {
+    Gamma(f32),
+    /// Evenly spaced samples, interpolated between
+    Table(Vec<f32>),
+    /// The parametric curves of version 4, all taken as the one with every
+    /// parameter: `(a * x + b)^g + e` from `d` on, `c * x + f` below.
+    /// Parameters are in the order of the format, `[g, a, b, c, d, e, f]`.
+    Parametric([f32; 7]),
+}
+
+impl Curve {
+    pub fn decode(&self, x: f32) -> f32 {
+        match self {
+            Curve::Gamma(gamma) => x.max(0.0).powf(*gamma),
+            Curve::Table(samples) => {
+                let position = x.clamp(0.0, 1.0) * (samples.len() - 1) as f32;
+                let index = (position as usize).min(samples.len().saturating_sub(2));
+                let fraction = position - index as f32;
+                let next = samples.get(index + 1).unwrap_or(&samples[index]);
+
+                samples[index] + (next - samples[index]) * fraction
+            }
+            Curve::Parametric([g, a, b, c, d, e, f]) => {
+                if x >= *d {
+                    (a * x + b).max(0.0).powf(*g) + e
+                } else {
+                    c * x + f
+                }
+            }
+        }
+    }
+}
+
+/// Reads a profile made of a matrix and tone curves. None for anything else,
+/// including profiles that also have a lookup table, which color management
+/// modules convert through instead of the matrix.
+pub fn read_matrix_shaper(icc: &[u8]) -> Option<MatrixShaper> {
+    let header = icc.get(..HEADER_SIZE)?;
+    if &header[16..20] != b"RGB " || &header[20..24] != b"XYZ " {
+        return None;
+    }
+    if find_tag(icc, b"A2B0").is_some() {
+        return None;
+    }
+
+    let colorants = [b"rXYZ", b"gXYZ", b"bXYZ"].map(|signature| {
+        let tag = find_tag(icc, signature)?;
+        if tag.get(..4)? != b"XYZ " {
+            return None;
+        }
+        Some(Vector3::new(
+            read_s15_fixed16(tag, 8)?,
+            read_s15_fixed16(tag, 12)?,
+            read_s15_fixed16(tag, 16)?,
+        ))
+    });
+    let [Some(r), Some(g), Some(b)] = colorants else {
+        return None;
+    };
+
+    let curves = [b"rTRC", b"gTRC", b"bTRC"].map(|signature| read_curve(find_tag(icc, signature)?));
+    let [Some(red), Some(green), Some(blue)] = curves else {
+        return None;
+    };
+
+    Some(MatrixShaper {
+        to_xyz_d50: Matrix3::from_cols(r, g, b),
+        curves: [red, green, blue],
+    })
+}
+
+/// The data of a tag, if the profile has it
+fn find_tag<'a>(icc: &'a [u8], signature: &[u8; 4]) -> Option<&'a [u8]> {
+    let count = read_u32(icc, HEADER_SIZE)? as usize;
+
+    (0..count).find_map(|i| {
+        let entry = HEADER_SIZE + 4 + i * 12;
+        if icc.get(entry..entry + 4)? != signature {
+            return None;
+        }
+        let offset = read_u32(icc, entry + 4)? as usize;
+        let size = read_u32(icc, entry + 8)? as usize;
+
+        icc.get(offset..offset.checked_add(size)?)
+    })
+}
+
+fn read_curve(tag: &[u8]) -> Option<Curve> {
+    match tag.get(..4)? {
+        b"curv" => {
+            let count = read_u32(tag, 8)? as usize;
+            let entries = (0..count)
+                .map(|i| {
+                    Some(u16::from_be_bytes(
+                        tag.get(12 + i * 2..14 + i * 2)?.try_into().ok()?,
+                    ))
+                })
+                .collect::<Option<Vec<_>>>()?;
+
+            Some(match entries[..] {
+                [] => Curve::Gamma(1.0),
+                // A single entry is the gamma as an unsigned 8.8 fixed point number
+                [gamma] => Curve::Gamma(gamma as f32 / 256.0),
+                _ => Curve::Table(entries.iter().map(|&c| c as f32 / 65535.0).collect()),
+            })
+        }
+        b"para" => {
+            let kind = u16::from_be_bytes(tag.get(8..10)?.try_into().ok()?);
+            let count = [1, 3, 4, 5, 7].get(kind as usize)?;
+            let parameters = (0..*count)
+                .map(|i| read_s15_fixed16(tag, 12 + i * 4))
+                .collect::<Option<Vec<_>>>()?;
+
+            Some(Curve::Parametric(match parameters[..] {
+                [g] => [g, 1.0, 0.0, 0.0, 0.0, 0.0, 0.0],
+                [g, a, b] => [g, a, b, 0.0, -b / a, 0.0, 0.0],
+                [g, a, b, c] => [g, a, b, 0.0, -b / a, c, c],
+                [g, a, b, c, d] => [g, a, b, c, d, 0.0, 0.0],
+                [g, a, b, c, d, e, f] => [g, a, b, c, d, e, f],
+                _ => return None,
+            }))
+        }
+        _ => None,
+    }
+}
+
+fn read_u32(bytes: &[u8], at: usize) -> Option<u32> {
+    Some(u32::from_be_bytes(bytes.get(at..at + 4)?.try_into().ok()?))
+}
+
+fn read_s15_fixed16(bytes: &[u8], at: usize) -> Option<f32> {
+    Some(read_u32(bytes, at)? as i32 as f32 / 65536.0)
+}
+
+fn header(size: usize) -> Vec<u8> {
+    let mut header = Vec::with_capacity(HEADER_SIZE);
+    header.extend((size as u32).to_be_bytes());
+    header.extend([0; 4]); // preferred CMM
+    header.extend([2, 0x10, 0, 0]); // version 2.1
+    header.extend(b"mntr");
+    header.extend(b"RGB ");
+    header.extend(b"XYZ ");
+    for part in [2024u16, 1, 1, 0, 0, 0] {
+        header.extend(part.to_be_bytes());
+    }
+    header.extend(b"acsp");
+    header.extend([0; 4]); // platform
+    header.extend([0; 4]); // flags
+    header.extend([0; 4]); // device manufacturer
+    header.extend([0; 4]); // device model
+    header.extend([0; 8]); // device attributes
+    header.extend([0; 4]); // perceptual intent
+    for c in D50_XYZ {
+        header.extend(s15_fixed16(c));
+    }
+    header.extend([0; 4]); // creator
+    header.resize(HEADER_SIZE, 0);
+
+    header
+}
+
+fn description_tag(text: &str) -> Vec<u8> {
+    let mut tag = type_signature(b"desc");
+    tag.extend((text.len() as u32 + 1).to_be_bytes());
+    tag.extend(text.as_bytes());
+    tag.push(0);
+    // No unicode nor scriptcode versions of the text
+    tag.extend([0; 8]);
+    tag.extend([0; 3]);
+    tag.extend([0; 67]);
+
+    tag
+}
+
+fn text_tag(text: &str) -> Vec<u8> {
+    let mut tag = type_signature(b"text");
+    tag.extend(text.as_bytes());
+    tag.push(0);
+
+    tag
+}
+
+fn xyz_tag(xyz: [f32; 3]) -> Vec<u8> {
+    let mut tag = type_signature(b"XYZ ");
+    for c in xyz {
+        tag.extend(s15_fixed16(c));
+    }
+
+    tag
+}
+
+fn curve_tag(transfer: Transfer) -> Vec<u8> {
+    let mut tag = type_signature(b"curv");
+
+    match transfer {
+        Transfer::Gamma(gamma) => {
+            tag.extend(1u32.to_be_bytes());
+            // A single entry is the gamma as an unsigned 8.8 fixed point number
+            tag.extend(((gamma * 256.0).round() as u16).to_be_bytes());
+        }
+        Transfer::Srgb => {
+            tag.extend((CURVE_POINTS as u32).to_be_bytes());
+            for i in 0..CURVE_POINTS {
+                let encoded = i as f32 / (CURVE_POINTS - 1) as f32;
+                let linear = transfer.decode(encoded);
+                tag.extend(((linear * 65535.0).round() as u16).to_be_bytes());
+            }
+        }
+    }
+
+    tag
+}
+
+fn type_signature(signature: &[u8; 4]) -> Vec<u8> {
+    let mut tag = signature.to_vec();
+    tag.extend([0; 4]);
+
+    tag
+}
+
+fn s15_fixed16(value: f32) -> [u8; 4] {
+    ((value * 65536.0).round() as i32).to_be_bytes()
+}
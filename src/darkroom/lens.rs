@@ -0,0 +1,51 @@
+//! Lens profiles. The corrections themselves are done in the shader, see
+//! [`LensUniform`], this saves and loads them for reuse across images.
+
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::darkroom::uniform::LensUniform;
+
+/// Where saved lens profiles go, next to the catalog
+const LENS_PROFILE_DIR: &str = "lenses";
+
+/// Corrections measured for a lens, kept in a JSON file so they can be
+/// shared and used on any image taken or scanned with it
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LensProfile {
+    pub name: String,
+    pub correction: LensUniform,
+}
+
+impl LensProfile {
+    pub fn load(path: &Path) -> io::Result<Self> {
+        let json = fs::read_to_string(path)?;
+
+        Ok(serde_json::from_str(&json)?)
+    }
+
+    /// Writes the profile in the lens profiles folder
+    pub fn save(&self) -> io::Result<PathBuf> {
+        fs::create_dir_all(LENS_PROFILE_DIR)?;
+
+        let stem: String = self
+            .name
+            .chars()
+            .map(|c| {
+                if c.is_alphanumeric() || c == '-' {
+                    c
+                } else {
+                    '_'
+                }
+            })
+            .collect();
+        let path = Path::new(LENS_PROFILE_DIR).join(format!("{stem}.json"));
+        fs::write(&path, serde_json::to_string_pretty(self)?)?;
+
+        Ok(path)
+    }
+}
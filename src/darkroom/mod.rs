@@ -1,20 +1,73 @@
 #![allow(clippy::new_without_default)]
 
+pub mod brush;
+pub mod calibration;
+pub mod canvas;
+pub mod color;
+pub mod export;
+pub mod icc;
+pub mod lens;
+pub mod proof;
 pub mod renderer;
+pub mod sampler;
+pub mod stage;
+pub mod test_strip;
 pub mod texture;
+pub mod tile;
 pub mod uniform;
 pub mod vertex;
+pub mod widgets;
 
-use crate::darkroom::{renderer::Renderer, uniform::FragmentUniform};
+use std::path::Path;
+use std::rc::Rc;
 
-use cgmath::{Angle, Rad};
-use egui::Vec2;
+use crate::darkroom::{
+    brush::{BrushMode, BrushSettings, BrushStroke, DodgeBurnMask},
+    calibration::{Calibration, CorrectionFit, PatchGrid, TargetLayout},
+    canvas::{CompareMode, ImageTransform, ImageView, ZoomPreset},
+    export::{ExportFormat, ExportSettings, OutputProfile},
+    lens::LensProfile,
+    proof::{RenderingIntent, LUT_SIZE},
+    renderer::Renderer,
+    sampler::{SamplePoint, SampleReading, MAX_SAMPLES},
+    stage::Stages,
+    test_strip::{StripParameter, StripSettings, TestStrip},
+    texture::InputTexture,
+    uniform::{
+        paper_grade_name, ClippingUniform, ContrastFilter, FragmentUniform, MaskKind, ProofUniform,
+        Toning, HSL_BANDS, MAX_MASKS, PAPER_GRADE_MAX, PAPER_GRADE_MIN,
+    },
+    widgets::ColorWheel,
+};
+use crate::lighttable::{
+    db::{self, Database, ImageKey, Snapshot},
+    module::{self, EditModule},
+    preset::Preset,
+};
+
+use cgmath::{Angle, Matrix4, Rad, SquareMatrix};
+use egui::{emath::Rot2, load::SizedTexture, Vec2};
 use miniquad as mq;
 
+/// Long edge of the copy rendered while sliders are dragged
+const PROXY_SIZE: u32 = 2048;
+
 pub struct Darkroom {
     /// A handle to the image processing renderer
     renderer: Renderer,
 
+    /// Renders a scaled down copy of the image while adjustments are being
+    /// dragged, so big scans follow the sliders. Small images don't have one.
+    /// It only develops and marks clipping, soft proofs always come from the
+    /// full image.
+    proxy: Option<Renderer>,
+
+    /// Whether a slider is being dragged
+    adjusting: bool,
+
+    /// What was last rendered, so nothing is rendered again until it changes
+    stages: Stages,
+
     /// A way to parametrize the shaders from the UI
     frag_uniform: FragmentUniform,
 
@@ -24,38 +77,480 @@ pub struct Darkroom {
     /// The texture that's shown on screen after the render pass
     output_texture_id: egui::TextureId,
 
+    /// The working image rendered without edits, to compare against
+    input_texture_id: egui::TextureId,
+
+    /// The image converted into the working space, which is what gets
+    /// developed. Without it the unprocessed image is developed as is.
+    working_texture: Option<InputTexture>,
+
+    /// Images too big for a texture are shown from a scaled down copy, the
+    /// full image is kept here to export it in tiles
+    full_image: Option<color::LinearImage>,
+
+    /// The biggest texture the driver takes, see [`tile::max_texture_size`]
+    max_texture_size: u32,
+
+    compare: CompareMode,
+
+    /// Where the line between the original and the developed image is when
+    /// comparing them side by side on the same image
+    split_position: f32,
+
     /// How much to rotate the image, in degrees
     rotation_angle: Rad<f32>,
 
-    /// How much to zoom in / out
-    zoom_factor: f32,
+    /// How much the image is zoomed in and where it's panned to
+    view: ImageView,
+
+    /// The hue band currently being edited in the HSL module
+    hsl_band: usize,
+
+    /// The local adjustment whose handles are shown on the image
+    selected_mask: Option<usize>,
+
+    /// Which file is being developed, and which version of its edits
+    key: ImageKey,
+    version_name: String,
+
+    /// How to build the next test strip
+    strip_settings: StripSettings,
+
+    /// The test strip shown instead of the image, if any
+    test_strip: Option<TestStrip>,
+
+    /// Test strips need the rendering context, so they're built on the next update
+    test_strip_requested: bool,
+    test_strip_closed: bool,
+
+    /// Exporting reads the render back, so it also waits for the next update
+    export_requested: bool,
+    export_settings: ExportSettings,
+
+    /// Feedback about the last action, shown under the image
+    status: String,
+
+    /// Where the edits are stored
+    db: Rc<Database>,
+
+    /// Dodge and burn strokes, in the order they were painted
+    strokes: Vec<BrushStroke>,
+
+    /// The strokes replayed into a texture for the shader
+    dodge_burn: DodgeBurnMask,
+
+    /// Settings for the next stroke
+    brush: BrushSettings,
+
+    /// Whether dragging on the image paints with the brush
+    brush_active: bool,
+
+    /// Marks blown highlights and crushed shadows on screen
+    show_clipping: bool,
+    clipping: ClippingUniform,
+
+    /// Shows the image through a printer or paper profile
+    soft_proof: bool,
+    proof_profile_path: String,
+    proof_intent: RenderingIntent,
+    proof: ProofUniform,
+
+    /// The profile as a lookup table for the shader, built again on the
+    /// next update when the profile or the intent change
+    proof_lut: Option<mq::TextureId>,
+    proof_lut_changed: bool,
+
+    /// Points whose colors are measured, and what was measured on the last update
+    samples: Vec<SamplePoint>,
+    sample_readings: Vec<SampleReading>,
+
+    /// Whether clicking on the image places a sample point
+    sampler_active: bool,
+
+    /// The lens profile file to load, or what to save the current
+    /// corrections as
+    lens_profile_path: String,
+    lens_profile_name: String,
+
+    /// Presets in the catalog, reloaded whenever they change
+    presets: Vec<Preset>,
+
+    /// What the next preset is saved as
+    preset_name: String,
+    preset_modules: Vec<EditModule>,
+
+    /// The JSON file to import a preset from
+    preset_import_path: String,
+
+    /// Named states of the edits, and the one compared against instead of
+    /// the original, if any
+    snapshots: Vec<Snapshot>,
+    snapshot_name: String,
+    compare_snapshot: Option<usize>,
+
+    /// The snapshot to compare against changed, so its dodge and burn mask
+    /// has to be built again on the next update
+    snapshot_changed: bool,
+    snapshot_mask: Option<DodgeBurnMask>,
+    snapshot_texture_id: Option<egui::TextureId>,
+
+    /// Where the patches of a color target are on the image, when it's a
+    /// scan of one, and whether dragging on the image moves the grid
+    target_layout: TargetLayout,
+    patch_grid: PatchGrid,
+    placing_grid: bool,
+
+    /// The CGATS file with the measured values of the target
+    reference_path: String,
+
+    /// The last fitted scanner profile, and what it's saved as
+    calibration: Option<Calibration>,
+    calibration_name: String,
+
+    /// The last correction measured from a ColorChecker, and the setup it's
+    /// saved for
+    correction_fit: Option<CorrectionFit>,
+    camera_name: String,
+    light_name: String,
+
+    /// Corrections in the catalog, reloaded whenever they change
+    camera_corrections: Vec<db::CameraCorrection>,
 }
 
 impl Darkroom {
-    pub fn new(mq_ctx: &mut mq::Context, texture_handle: egui::TextureHandle) -> Self {
-        let dimensions = texture_handle.size();
+    pub fn new(
+        mq_ctx: &mut mq::Context,
+        db: Rc<Database>,
+        key: ImageKey,
+        texture_handle: egui::TextureHandle,
+    ) -> Self {
         let id = texture_handle.id();
+        let max_texture_size = tile::max_texture_size();
+        let (working_texture, full_image, proxy_texture, monochrome) =
+            match load_working_texture(mq_ctx, &db, &key.path, max_texture_size) {
+                Some(working) => (
+                    Some(working.texture),
+                    working.full_image,
+                    working.proxy,
+                    working.monochrome,
+                ),
+                None => (None, None, None, false),
+            };
+        let input = working_texture.unwrap_or_else(|| {
+            let [width, height] = texture_handle.size();
+            InputTexture::gamma(egui_to_mq_texture_id(id), (width as u32, height as u32))
+        });
+        let dimensions = [input.size.0 as usize, input.size.1 as usize];
+
+        let record = match db.get_image(&key) {
+            Ok(Some(record)) => record,
+            Ok(None) => {
+                // Scans of black and white film start out in black and white
+                let mut record = db::Image::new(&key);
+                record.uniform.monochrome.enabled = monochrome as u32;
+                record
+            }
+            Err(err) => {
+                log::error!("couldn't load the edits of {}: {err}", key.path);
+                db::Image::new(&key)
+            }
+        };
+
+        let presets = load_presets(&db);
+        let camera_corrections = load_camera_corrections(&db);
+
+        let mut dodge_burn =
+            DodgeBurnMask::new(mq_ctx, (dimensions[0] as u32, dimensions[1] as u32));
+        dodge_burn.rebuild(&record.strokes);
+
+        let mut renderer = Renderer::new(mq_ctx, input, dodge_burn.texture);
+        let proxy = proxy_texture.map(|proxy| Renderer::new(mq_ctx, proxy, dodge_burn.texture));
+
+        // The original never changes, so it's rendered once
+        let neutral_mask = DodgeBurnMask::new(mq_ctx, (1, 1));
+        let original_id = renderer.render_original(mq_ctx, neutral_mask.texture);
+        neutral_mask.delete(mq_ctx);
 
         Self {
-            renderer: Renderer::new(mq_ctx, egui_to_mq_texture_id(id), dimensions),
-            frag_uniform: FragmentUniform::default(),
+            renderer,
+            proxy,
+            adjusting: false,
+            stages: Stages::default(),
+            frag_uniform: record.uniform,
             input_texture_dimensions: (dimensions[0] as f32, dimensions[1] as f32),
             output_texture_id: id,
+            input_texture_id: original_id,
+            working_texture,
+            full_image,
+            max_texture_size,
+            compare: CompareMode::Off,
+            split_position: 0.5,
             rotation_angle: Rad(0.0),
-            zoom_factor: 1.0,
+            view: ImageView::default(),
+            hsl_band: 0,
+            selected_mask: None,
+            key,
+            version_name: record.version_name,
+            strip_settings: StripSettings::default(),
+            test_strip: None,
+            test_strip_requested: false,
+            test_strip_closed: false,
+            export_requested: false,
+            export_settings: ExportSettings::default(),
+            status: String::new(),
+            db,
+            strokes: record.strokes,
+            dodge_burn,
+            brush: BrushSettings::default(),
+            brush_active: false,
+            show_clipping: false,
+            clipping: ClippingUniform::default(),
+            soft_proof: false,
+            proof_profile_path: String::new(),
+            proof_intent: RenderingIntent::Perceptual,
+            proof: ProofUniform::default(),
+            proof_lut: None,
+            proof_lut_changed: false,
+            samples: record.samples,
+            sample_readings: vec![],
+            sampler_active: false,
+            lens_profile_path: String::new(),
+            lens_profile_name: String::new(),
+            presets,
+            preset_name: String::new(),
+            preset_modules: EditModule::global(),
+            preset_import_path: String::new(),
+            snapshots: record.snapshots,
+            snapshot_name: String::new(),
+            compare_snapshot: None,
+            snapshot_changed: false,
+            snapshot_mask: None,
+            snapshot_texture_id: None,
+            target_layout: TargetLayout::It8,
+            patch_grid: PatchGrid::default(),
+            placing_grid: false,
+            reference_path: String::new(),
+            calibration: None,
+            calibration_name: String::new(),
+            correction_fit: None,
+            camera_name: String::new(),
+            light_name: String::new(),
+            camera_corrections,
+        }
+    }
+
+    /// The current edits, as stored in the catalog
+    fn record(&self) -> db::Image {
+        db::Image {
+            path: self.key.path.clone(),
+            version: self.key.version,
+            version_name: self.version_name.clone(),
+            uniform: self.frag_uniform,
+            strokes: self.strokes.clone(),
+            samples: self.samples.clone(),
+            snapshots: self.snapshots.clone(),
+        }
+    }
+
+    /// Stores the current edits in the catalog
+    pub fn save(&self) {
+        if let Err(err) = self.db.save_image(&self.record()) {
+            log::error!("couldn't save the edits of {}: {err}", self.key.path);
+        }
+    }
+
+    /// Frees everything the darkroom put on the GPU, the image included
+    pub fn delete(self, mq_ctx: &mut mq::Context) {
+        self.renderer.delete(mq_ctx);
+        if let Some(proxy) = self.proxy {
+            proxy.delete(mq_ctx);
+        }
+        self.dodge_burn.delete(mq_ctx);
+        if let Some(mask) = self.snapshot_mask {
+            mask.delete(mq_ctx);
+        }
+        if let Some(lut) = self.proof_lut {
+            mq_ctx.delete_texture(lut);
+        }
+        if let Some(strip) = self.test_strip {
+            strip.delete(mq_ctx);
         }
     }
 
     pub fn update(&mut self, mq_ctx: &mut mq::Context) {
-        // Apply filters to the current image
-        self.output_texture_id = self.renderer.render(mq_ctx, self.frag_uniform);
+        if std::mem::take(&mut self.test_strip_closed) {
+            if let Some(strip) = self.test_strip.take() {
+                strip.delete(mq_ctx);
+            }
+        }
+
+        if std::mem::take(&mut self.test_strip_requested) {
+            if let Some(strip) = self.test_strip.take() {
+                strip.delete(mq_ctx);
+            }
+
+            self.test_strip = Some(TestStrip::render(
+                mq_ctx,
+                &self.renderer,
+                self.frag_uniform,
+                self.strip_settings,
+            ));
+        }
+
+        if std::mem::take(&mut self.proof_lut_changed) {
+            self.rebuild_proof_lut(mq_ctx);
+        }
+
+        let mask_changed = self.dodge_burn.upload(mq_ctx);
+        self.render_snapshot(mq_ctx);
+
+        if std::mem::take(&mut self.export_requested) {
+            self.export(mq_ctx);
+            // The export was rendered over the image on screen
+            self.stages.color.invalidate();
+        }
+
+        // Apply filters to the current image, from the proxy while a slider
+        // is dragged and at full resolution once it's let go
+        let (renderer, from_proxy) = match &mut self.proxy {
+            Some(proxy) if self.adjusting && !self.soft_proof => (proxy, true),
+            _ => (&mut self.renderer, false),
+        };
+        let first = self
+            .stages
+            .develop(&self.frag_uniform, from_proxy, mask_changed);
+        if let Some(first) = first {
+            renderer.render_from(mq_ctx, self.frag_uniform, first);
+        }
+        let developed = first.is_some();
+        self.output_texture_id = renderer.output(mq_ctx);
+
+        // Proofing and clipping both read the developed image, clipping is
+        // shown on top
+        match self.proof_lut.filter(|_| self.soft_proof) {
+            Some(lut) => {
+                if self.stages.proof.dirty((lut, self.proof), developed) {
+                    renderer.render_proof(mq_ctx, self.frag_uniform, lut, self.proof);
+                }
+                self.output_texture_id = renderer.proof_output(mq_ctx);
+            }
+            None => self.stages.proof.invalidate(),
+        }
+
+        if self.show_clipping {
+            if self.stages.clipping.dirty(self.clipping, developed) {
+                renderer.render_clipping(mq_ctx, self.clipping);
+            }
+            self.output_texture_id = renderer.clipping_output(mq_ctx);
+        } else {
+            self.stages.clipping.invalidate();
+        }
+
+        // Reading the samples back stalls until the GPU is done, so they're
+        // only measured once a dragged slider is let go, which renders the
+        // full image again
+        if !from_proxy && self.stages.samples.dirty(self.samples.clone(), developed) {
+            self.sample_readings = if self.samples.is_empty() {
+                vec![]
+            } else {
+                renderer.sample(mq_ctx, &self.samples)
+            };
+        }
+    }
+
+    fn rebuild_proof_lut(&mut self, mq_ctx: &mut mq::Context) {
+        if let Some(lut) = self.proof_lut.take() {
+            mq_ctx.delete_texture(lut);
+        }
+        // A new table can get the ID of the old one
+        self.stages.proof.invalidate();
+
+        let lut = std::fs::read(&self.proof_profile_path)
+            .map_err(|err| format!("Couldn't read the profile: {err}"))
+            .and_then(|icc| {
+                proof::build_lut(&icc, self.proof_intent)
+                    .ok_or_else(|| "Only RGB profiles can be proofed against".to_string())
+            });
+
+        match lut {
+            Ok(lut) => {
+                let size = LUT_SIZE as u16;
+                self.proof_lut = Some(mq_ctx.new_texture_from_rgba8(size * size, size, &lut));
+            }
+            Err(err) => {
+                self.status = err;
+                self.soft_proof = false;
+            }
+        }
+    }
+
+    /// Renders the image in the output space and saves it. The next render
+    /// puts the one on screen back in place.
+    fn export(&mut self, mq_ctx: &mut mq::Context) {
+        let mut uniform = self.frag_uniform;
+        uniform.output = self.export_settings.output_uniform();
+
+        let pixels = match &self.full_image {
+            Some(full_image) => {
+                self.renderer
+                    .render_tiled(mq_ctx, uniform, full_image, self.max_texture_size)
+            }
+            None => {
+                self.renderer.render(mq_ctx, uniform);
+                Some(self.renderer.read_output(mq_ctx))
+            }
+        };
+        let Some(pixels) = pixels else {
+            self.status = "Couldn't upload the image to render it".to_string();
+            return;
+        };
+        self.status = match export::export(pixels, &self.key.path, &self.export_settings) {
+            Ok(path) => format!("Exported {}", path.display()),
+            Err(err) => {
+                log::error!("couldn't export {}: {err}", self.key.path);
+                format!("Couldn't export the image: {err}")
+            }
+        };
+    }
+
+    /// Renders the snapshot being compared against, if any
+    fn render_snapshot(&mut self, mq_ctx: &mut mq::Context) {
+        let Some((index, snapshot)) = self
+            .compare_snapshot
+            .and_then(|i| Some((i, self.snapshots.get(i)?)))
+        else {
+            self.snapshot_texture_id = None;
+            self.stages.snapshot.invalidate();
+            return;
+        };
+
+        let mask = self.snapshot_mask.get_or_insert_with(|| {
+            let (width, height) = self.renderer.dimensions();
+            DodgeBurnMask::new(mq_ctx, (width, height))
+        });
+        if std::mem::take(&mut self.snapshot_changed) {
+            mask.rebuild(&snapshot.strokes);
+        }
+        let mask_changed = mask.upload(mq_ctx);
+
+        if self
+            .stages
+            .snapshot
+            .dirty((index, snapshot.uniform), mask_changed)
+        {
+            self.snapshot_texture_id = Some(self.renderer.render_snapshot(
+                mq_ctx,
+                snapshot.uniform,
+                mask.texture,
+            ));
+        }
     }
 
     pub fn ui(&mut self, ctx: &egui::Context) {
-        egui::SidePanel::right("right_panel")
+        let panel = egui::SidePanel::right("right_panel")
             .exact_width(180.0)
             .show(ctx, |ui| {
-                ui.vertical(|ui| {
+                egui::ScrollArea::vertical().show(ui, |ui| {
                     ui.label("contrast");
                     ui.add(
                         egui::Slider::new(&mut self.frag_uniform.contrast, -30.0..=30.0)
@@ -63,9 +558,23 @@ impl Darkroom {
                             .trailing_fill(true),
                     );
 
-                    ui.label("brightness");
+                    ui.label("exposure");
+                    ui.add(
+                        egui::Slider::new(&mut self.frag_uniform.exposure, -3.0..=3.0)
+                            .step_by(1.0 / 6.0)
+                            .suffix(" EV")
+                            .trailing_fill(true),
+                    );
+
+                    ui.label("highlights");
+                    ui.add(
+                        egui::Slider::new(&mut self.frag_uniform.highlights, -1.0..=1.0)
+                            .trailing_fill(true),
+                    );
+
+                    ui.label("shadows");
                     ui.add(
-                        egui::Slider::new(&mut self.frag_uniform.brightness, -0.25..=0.25)
+                        egui::Slider::new(&mut self.frag_uniform.shadows, -1.0..=1.0)
                             .trailing_fill(true),
                     );
 
@@ -78,9 +587,38 @@ impl Darkroom {
                     let mut invert = self.frag_uniform.invert != 0;
                     ui.add(egui::Checkbox::new(&mut invert, "Invert"));
                     self.frag_uniform.invert = invert as u32;
+                    if invert {
+                        self.film_base_ui(ui);
+                    }
+
+                    egui::CollapsingHeader::new("White balance")
+                        .show(ui, |ui| self.white_balance_ui(ui));
+                    egui::CollapsingHeader::new("HSL").show(ui, |ui| self.hsl_ui(ui));
+                    egui::CollapsingHeader::new("Color grading").show(ui, |ui| self.grading_ui(ui));
+                    egui::CollapsingHeader::new("Black & white")
+                        .show(ui, |ui| self.monochrome_ui(ui));
+                    egui::CollapsingHeader::new("Paper").show(ui, |ui| self.paper_ui(ui));
+                    egui::CollapsingHeader::new("Local adjustments")
+                        .show(ui, |ui| self.masks_ui(ui));
+                    egui::CollapsingHeader::new("Lens correction").show(ui, |ui| self.lens_ui(ui));
+                    egui::CollapsingHeader::new("Dodge & burn").show(ui, |ui| self.brush_ui(ui));
+                    egui::CollapsingHeader::new("Test strip").show(ui, |ui| self.test_strip_ui(ui));
+                    egui::CollapsingHeader::new("Snapshots").show(ui, |ui| self.snapshots_ui(ui));
+                    egui::CollapsingHeader::new("Presets").show(ui, |ui| self.presets_ui(ui));
+                    egui::CollapsingHeader::new("Color samples").show(ui, |ui| self.samples_ui(ui));
+                    egui::CollapsingHeader::new("Camera correction")
+                        .show(ui, |ui| self.camera_correction_ui(ui));
+                    egui::CollapsingHeader::new("Scanner calibration")
+                        .show(ui, |ui| self.calibration_ui(ui));
                 });
             });
 
+        // Anything dragged from the panel is an adjustment
+        self.adjusting = ctx.dragged_id().is_some()
+            && ctx
+                .input(|i| i.pointer.press_origin())
+                .is_some_and(|origin| panel.response.rect.contains(origin));
+
         egui::CentralPanel::default().show(ctx, |ui| {
             egui::TopBottomPanel::top("image_controls").show_inside(ui, |ui| {
                 ui.horizontal(|ui| {
@@ -91,41 +629,1375 @@ impl Darkroom {
                         self.rotation_angle += Rad::turn_div_4();
                     }
 
+                    ui.separator();
                     if ui.button("-").clicked() {
-                        self.zoom_factor -= 0.125;
+                        self.view.zoom_by(1.0 / 1.25);
                     }
                     if ui.button("+").clicked() {
-                        self.zoom_factor += 0.125;
+                        self.view.zoom_by(1.25);
                     }
+                    for preset in ZoomPreset::ALL {
+                        if ui.button(preset.name()).clicked() {
+                            self.view.set_preset(preset);
+                        }
+                    }
+                    let magnification = self.view.magnification(ui.ctx().pixels_per_point());
+                    ui.label(format!("{:.0}%", magnification * 100.0));
+
+                    ui.separator();
+                    ui.toggle_value(&mut self.show_clipping, "Clipping");
+                    ui.menu_button("⚙", |ui| self.clipping_ui(ui));
+                    ui.menu_button("Soft proof", |ui| self.proof_ui(ui));
+
+                    ui.separator();
+                    egui::ComboBox::from_id_source("compare")
+                        .selected_text(format!("Compare: {}", self.compare.name()))
+                        .show_ui(ui, |ui| {
+                            for mode in CompareMode::ALL {
+                                ui.selectable_value(&mut self.compare, mode, mode.name());
+                            }
+                        });
+
+                    ui.separator();
+                    ui.menu_button("Export", |ui| self.export_ui(ui));
                 });
             });
 
             egui::TopBottomPanel::bottom("image_info").show_inside(ui, |ui| {
                 ui.horizontal_centered(|ui| {
-                    ui.label(format!(
-                        "{} x {} px",
-                        self.input_texture_dimensions.0, self.input_texture_dimensions.1
-                    ));
+                    match &self.full_image {
+                        Some(full_image) => {
+                            let (width, height) = full_image.dimensions();
+                            ui.label(format!("{width} x {height} px"));
+                            ui.label("Tiled").on_hover_text(
+                                "Too big for the GPU, a smaller copy is shown and exports are rendered in tiles",
+                            );
+                        }
+                        None => {
+                            ui.label(format!(
+                                "{} x {} px",
+                                self.input_texture_dimensions.0, self.input_texture_dimensions.1
+                            ));
+                        }
+                    }
+                    if let Some(snapshot) =
+                        self.compare_snapshot.and_then(|i| self.snapshots.get(i))
+                    {
+                        ui.label(format!("Comparing against {}", snapshot.name));
+                    }
+                    if self.working_texture.is_none() {
+                        ui.colored_label(
+                            ui.visuals().error_fg_color,
+                            "Couldn't read the image, only its thumbnail is shown. \
+                             It can't be exported or rendered into a test strip.",
+                        );
+                    }
+                    ui.label(&self.status);
                 });
             });
 
-            egui::ScrollArea::both().show(ui, |ui| {
-                ui.centered_and_justified(|ui| {
-                    let img = egui::Image::new((
-                        self.output_texture_id.to_owned(),
-                        self.input_texture_dimensions.into(),
-                    ))
-                    .rotate(self.rotation_angle.0, Vec2::splat(0.5))
-                    .maintain_aspect_ratio(true)
-                    .fit_to_fraction((self.zoom_factor, self.zoom_factor).into());
+            let holding_original =
+                self.compare == CompareMode::Hold && ui.input(|i| i.key_down(egui::Key::Backslash));
+            let texture_id = match &self.test_strip {
+                Some(strip) => strip.texture_id,
+                None if holding_original => self.before_texture_id(),
+                None => self.output_texture_id,
+            };
+
+            let mut viewport = ui.available_rect_before_wrap();
+            let side_by_side = self.compare == CompareMode::SideBySide && self.test_strip.is_none();
+            if side_by_side {
+                let (original, edited) = viewport.split_left_right_at_fraction(0.5);
+                viewport = edited;
+
+                // Both halves share the same zoom and pan
+                let size = self.image_size();
+                self.view
+                    .update(viewport, self.rotated_size(), ui.ctx().pixels_per_point());
+                let rect = self.view.image_rect(original, size);
+                self.paint_image(ui, original, rect, self.before_texture_id(), full_uv());
+            }
+
+            ui.allocate_ui_at_rect(viewport, |ui| {
+                ui.set_clip_rect(viewport.intersect(ui.clip_rect()));
+                self.edit_view(ui, viewport, texture_id);
+            });
+        });
+    }
+
+    /// What the edits are compared against: a snapshot or the original
+    fn before_texture_id(&self) -> egui::TextureId {
+        self.snapshot_texture_id.unwrap_or(self.input_texture_id)
+    }
+
+    fn image_size(&self) -> Vec2 {
+        self.input_texture_dimensions.into()
+    }
+
+    /// The size of the image as shown, which swaps sides on quarter turns
+    fn rotated_size(&self) -> Vec2 {
+        canvas::rotated_size(self.image_size(), self.rotation_angle.0)
+    }
+
+    /// Paints part of the image in a rect, rotated around the center of the
+    /// whole image and clipped to the view
+    fn paint_image(
+        &self,
+        ui: &egui::Ui,
+        viewport: egui::Rect,
+        rect: egui::Rect,
+        texture_id: egui::TextureId,
+        uv: egui::Rect,
+    ) {
+        let origin = (egui::pos2(0.5, 0.5) - uv.min) / uv.size();
+        let options = egui::ImageOptions {
+            uv,
+            rotation: Some((Rot2::from_angle(self.rotation_angle.0), origin)),
+            ..Default::default()
+        };
+        let texture = SizedTexture::new(texture_id, self.image_size());
+        egui::paint_texture_at(&ui.painter_at(viewport), rect, &options, &texture);
+    }
+
+    /// The image being developed, along with whatever is drawn on top of it
+    fn edit_view(&mut self, ui: &mut egui::Ui, viewport: egui::Rect, texture_id: egui::TextureId) {
+        let response = ui.interact(
+            viewport,
+            ui.id().with("edit_view"),
+            egui::Sense::click_and_drag(),
+        );
+
+        let editing = self.test_strip.is_none();
+        let painting = editing && self.brush_active;
+        let pixels_per_point = ui.ctx().pixels_per_point();
+        self.view
+            .update(viewport, self.rotated_size(), pixels_per_point);
+        self.view.handle_input(ui, viewport, &response, !painting);
+
+        let rect = self.view.image_rect(viewport, self.image_size());
+        let transform = ImageTransform::new(rect, self.rotation_angle.0);
+        self.paint_image(ui, viewport, rect, texture_id, full_uv());
+
+        if editing && self.compare == CompareMode::Split {
+            self.split_view(ui, viewport, rect, transform);
+        }
+
+        if painting {
+            self.brush_overlay(ui, &response, transform);
+        } else if editing && self.sampler_active {
+            self.sampler_overlay(ui, &response, transform);
+        } else if editing && self.placing_grid {
+            canvas::grid_overlay(ui, transform, &mut self.patch_grid, self.target_layout);
+        } else if let Some(i) = self.selected_mask.filter(|_| editing) {
+            canvas::mask_overlay(ui, transform, &mut self.frag_uniform.masks, i);
+        }
+        self.test_strip_overlay(ui, &response, transform);
+
+        let texture = SizedTexture::new(texture_id, self.image_size());
+        canvas::navigator(
+            ui,
+            viewport,
+            &mut self.view,
+            transform,
+            texture,
+            self.rotation_angle.0,
+        );
+    }
+
+    /// Paints the original over the part of the image left of the split
+    fn split_view(
+        &mut self,
+        ui: &mut egui::Ui,
+        viewport: egui::Rect,
+        rect: egui::Rect,
+        transform: ImageTransform,
+    ) {
+        let split = self.split_position.max(0.001);
+        let before =
+            egui::Rect::from_min_size(rect.min, egui::vec2(rect.width() * split, rect.height()));
+        let uv = egui::Rect::from_min_max(egui::pos2(0.0, 0.0), egui::pos2(split, 1.0));
+        self.paint_image(ui, viewport, before, self.before_texture_id(), uv);
+
+        canvas::compare_split(ui, transform, &mut self.split_position);
+    }
+
+    fn proof_ui(&mut self, ui: &mut egui::Ui) {
+        let toggled = ui.checkbox(&mut self.soft_proof, "Enabled").changed();
+
+        ui.add(
+            egui::TextEdit::singleline(&mut self.proof_profile_path)
+                .hint_text("path/to/printer.icc"),
+        );
+        let mut reload = ui.button("Load profile").clicked();
+
+        egui::ComboBox::from_label("Intent")
+            .selected_text(self.proof_intent.name())
+            .show_ui(ui, |ui| {
+                for intent in RenderingIntent::ALL {
+                    reload |= ui
+                        .selectable_value(&mut self.proof_intent, intent, intent.name())
+                        .changed();
+                }
+            });
+
+        let mut show_gamut = self.proof.show_gamut != 0;
+        ui.checkbox(&mut show_gamut, "Mark out of gamut colors");
+        self.proof.show_gamut = show_gamut as u32;
+
+        if reload {
+            self.soft_proof = true;
+        }
+        if reload || (toggled && self.soft_proof && self.proof_lut.is_none()) {
+            self.proof_lut_changed = true;
+        }
+    }
+
+    fn clipping_ui(&mut self, ui: &mut egui::Ui) {
+        let clipping = &mut self.clipping;
+
+        ui.label("highlights");
+        ui.add(egui::Slider::new(
+            &mut clipping.highlight_threshold,
+            0.8..=1.0,
+        ));
+
+        ui.label("shadows");
+        ui.add(egui::Slider::new(&mut clipping.shadow_threshold, 0.0..=0.2));
+
+        let mut per_channel = clipping.per_channel != 0;
+        ui.horizontal(|ui| {
+            ui.selectable_value(&mut per_channel, false, "Luminance");
+            ui.selectable_value(&mut per_channel, true, "Per channel");
+        });
+        clipping.per_channel = per_channel as u32;
+    }
+
+    fn test_strip_ui(&mut self, ui: &mut egui::Ui) {
+        let settings = &mut self.strip_settings;
+
+        ui.label("parameter");
+        let previous = settings.parameter;
+        egui::ComboBox::from_id_source("strip_parameter")
+            .selected_text(settings.parameter.name())
+            .show_ui(ui, |ui| {
+                for parameter in StripParameter::ALL {
+                    ui.selectable_value(&mut settings.parameter, parameter, parameter.name());
+                }
+            });
+        if settings.parameter != previous {
+            settings.step = settings.parameter.default_step();
+        }
+
+        ui.label("bands");
+        ui.add(egui::Slider::new(&mut settings.bands, 3..=9).trailing_fill(true));
+
+        ui.label("step");
+        let max_step = settings.parameter.default_step() * 4.0;
+        ui.add(egui::Slider::new(&mut settings.step, 0.0..=max_step).trailing_fill(true));
+
+        ui.horizontal_wrapped(|ui| {
+            // The thumbnail stands in for images that couldn't be read, it's
+            // too small to judge anything from
+            let readable = self.working_texture.is_some();
+            if ui
+                .add_enabled(readable, egui::Button::new("Render"))
+                .clicked()
+            {
+                self.test_strip_requested = true;
+            }
+
+            if let Some(strip) = &self.test_strip {
+                if ui.button("Export").clicked() {
+                    self.status = match strip.export(&self.key.path) {
+                        Ok(path) => format!("Saved {}", path.display()),
+                        Err(err) => format!("Couldn't save the test strip: {err}"),
+                    };
+                }
+
+                if ui.button("Close").clicked() {
+                    self.test_strip_closed = true;
+                }
+            }
+        });
+    }
+
+    /// Labels every band of the test strip and applies the value of the one
+    /// that gets clicked
+    fn test_strip_overlay(
+        &mut self,
+        ui: &egui::Ui,
+        response: &egui::Response,
+        transform: ImageTransform,
+    ) {
+        let Some(strip) = &self.test_strip else {
+            return;
+        };
+
+        let bands = strip.values.len();
+        for (i, value) in strip.values.iter().enumerate() {
+            // Near the bottom of the band, following the rotation of the image
+            let uv = egui::pos2((i as f32 + 0.5) / bands as f32, 0.95);
+            ui.painter().text(
+                transform.to_screen(uv),
+                egui::Align2::CENTER_BOTTOM,
+                strip.parameter.format(*value),
+                egui::FontId::proportional(14.0),
+                egui::Color32::WHITE,
+            );
+        }
+
+        let clicked_uv = response
+            .interact_pointer_pos()
+            .filter(|_| response.clicked())
+            .map(|pos| transform.to_uv(pos))
+            .filter(|uv| full_uv().contains(*uv));
+        if let Some(uv) = clicked_uv {
+            let value = strip.values[strip.band_at(uv.x)];
+
+            strip.parameter.set(&mut self.frag_uniform, value);
+            self.status = format!(
+                "{} set to {}",
+                strip.parameter.name(),
+                strip.parameter.format(value)
+            );
+            self.test_strip_closed = true;
+        }
+    }
+
+    fn masks_ui(&mut self, ui: &mut egui::Ui) {
+        let masks = &mut self.frag_uniform.masks;
+
+        ui.horizontal_wrapped(|ui| {
+            for kind in [MaskKind::Linear, MaskKind::Radial] {
+                if ui.button(format!("+ {}", kind.name())).clicked() {
+                    if let Some(i) = masks.add(kind) {
+                        self.selected_mask = Some(i);
+                    }
+                }
+            }
+        });
+
+        for i in (0..MAX_MASKS).filter(|i| masks.kind(*i) != MaskKind::None) {
+            let selected = self.selected_mask == Some(i);
+            let label = format!("{} {}", masks.kind(i).name(), i + 1);
+            if ui.selectable_label(selected, label).clicked() {
+                self.selected_mask = if selected { None } else { Some(i) };
+            }
+        }
+
+        let Some(i) = self.selected_mask else {
+            return;
+        };
+        ui.separator();
+
+        let mut invert = masks.invert[i] != 0;
+        ui.add(egui::Checkbox::new(&mut invert, "Invert"));
+        masks.invert[i] = invert as u32;
+
+        if masks.kind(i) == MaskKind::Radial {
+            ui.label("feather");
+            ui.add(egui::Slider::new(&mut masks.feather[i], 0.0..=1.0).trailing_fill(true));
+        }
+
+        let [exposure, contrast, temperature, saturation] = &mut masks.adjustments[i];
+
+        ui.label("exposure");
+        ui.add(
+            egui::Slider::new(exposure, -2.0..=2.0)
+                .suffix(" EV")
+                .trailing_fill(true),
+        );
+
+        ui.label("contrast");
+        ui.add(
+            egui::Slider::new(contrast, -30.0..=30.0)
+                .step_by(1.0)
+                .trailing_fill(true),
+        );
+
+        ui.label("temperature");
+        ui.add(egui::Slider::new(temperature, -1.0..=1.0).trailing_fill(true));
+
+        ui.label("saturation");
+        ui.add(egui::Slider::new(saturation, -1.0..=1.0).trailing_fill(true));
+
+        if ui.button("Delete").clicked() {
+            masks.remove(i);
+            self.selected_mask = None;
+        }
+    }
+
+    fn snapshots_ui(&mut self, ui: &mut egui::Ui) {
+        let mut restored = None;
+        let mut deleted = None;
+
+        for (i, snapshot) in self.snapshots.iter().enumerate() {
+            ui.horizontal(|ui| {
+                if ui
+                    .button(&snapshot.name)
+                    .on_hover_text("Go back to these edits")
+                    .clicked()
+                {
+                    restored = Some(i);
+                }
+
+                let comparing = self.compare_snapshot == Some(i);
+                if ui
+                    .selectable_label(comparing, "⇄")
+                    .on_hover_text("Compare against these edits instead of the original")
+                    .clicked()
+                {
+                    self.compare_snapshot = if comparing { None } else { Some(i) };
+                    self.snapshot_changed = true;
+                    if self.compare == CompareMode::Off {
+                        self.compare = CompareMode::Split;
+                    }
+                }
+
+                if ui.small_button("🗑").clicked() {
+                    deleted = Some(i);
+                }
+            });
+        }
+
+        if let Some(snapshot) = restored.map(|i| self.snapshots[i].clone()) {
+            self.frag_uniform = snapshot.uniform;
+            self.strokes = snapshot.strokes;
+            self.dodge_burn.rebuild(&self.strokes);
+            self.status = format!("Restored {}", snapshot.name);
+        }
+
+        if let Some(i) = deleted {
+            self.snapshots.remove(i);
+            self.compare_snapshot = match self.compare_snapshot {
+                Some(c) if c == i => None,
+                Some(c) if c > i => Some(c - 1),
+                other => other,
+            };
+        }
+
+        ui.separator();
+        ui.text_edit_singleline(&mut self.snapshot_name);
+        if ui.button("Take snapshot").clicked() {
+            let name = match self.snapshot_name.trim() {
+                "" => format!("Snapshot {}", self.snapshots.len() + 1),
+                name => name.to_string(),
+            };
+            self.snapshots.push(Snapshot {
+                name,
+                uniform: self.frag_uniform,
+                strokes: self.strokes.clone(),
+            });
+            self.snapshot_name.clear();
+        }
+    }
 
-                    ui.add(img);
+    fn presets_ui(&mut self, ui: &mut egui::Ui) {
+        let mut applied = None;
+        let mut deleted = None;
+
+        for preset in &self.presets {
+            ui.horizontal(|ui| {
+                if ui.button(&preset.name).clicked() {
+                    applied = Some(preset.clone());
+                }
+                if ui.small_button("⬆").on_hover_text("Export").clicked() {
+                    self.status = match preset.export() {
+                        Ok(path) => format!("Exported to {}", path.display()),
+                        Err(err) => format!("Couldn't export {}: {err}", preset.name),
+                    };
+                }
+                if ui.small_button("🗑").clicked() {
+                    deleted = Some(preset.name.clone());
+                }
+            });
+        }
+
+        if let Some(preset) = applied {
+            let mut record = self.record();
+            preset.apply(&mut record);
+
+            self.frag_uniform = record.uniform;
+            if record.strokes != self.strokes {
+                self.strokes = record.strokes;
+                self.dodge_burn.rebuild(&self.strokes);
+            }
+            self.status = format!("Applied {}", preset.name);
+        }
+
+        if let Some(name) = deleted {
+            if let Err(err) = self.db.delete_preset(&name) {
+                log::error!("couldn't delete the preset {name}: {err}");
+            }
+            self.presets = load_presets(&self.db);
+        }
+
+        ui.separator();
+        ui.label("save as");
+        ui.text_edit_singleline(&mut self.preset_name);
+        module::modules_ui(ui, &mut self.preset_modules);
+
+        let name = self.preset_name.trim();
+        if ui
+            .add_enabled(!name.is_empty(), egui::Button::new("Save preset"))
+            .clicked()
+        {
+            let preset = Preset::new(
+                name.to_string(),
+                self.preset_modules.clone(),
+                &self.record(),
+            );
+            self.save_preset(preset);
+        }
+
+        ui.separator();
+        ui.label("import from");
+        ui.text_edit_singleline(&mut self.preset_import_path);
+        if ui.button("Import").clicked() {
+            match Preset::import(Path::new(self.preset_import_path.trim())) {
+                Ok(preset) => self.save_preset(preset),
+                Err(err) => self.status = format!("Couldn't import the preset: {err}"),
+            }
+        }
+    }
+
+    fn save_preset(&mut self, preset: Preset) {
+        self.status = match self.db.save_preset(&preset) {
+            Ok(()) => format!("Saved {}", preset.name),
+            Err(err) => {
+                log::error!("couldn't save the preset {}: {err}", preset.name);
+                format!("Couldn't save {}", preset.name)
+            }
+        };
+        self.presets = load_presets(&self.db);
+    }
+
+    fn export_ui(&mut self, ui: &mut egui::Ui) {
+        let settings = &mut self.export_settings;
+
+        egui::ComboBox::from_label("Format")
+            .selected_text(settings.format.name())
+            .show_ui(ui, |ui| {
+                for format in ExportFormat::ALL {
+                    ui.selectable_value(&mut settings.format, format, format.name());
+                }
+            });
+        if settings.format == ExportFormat::Jpeg {
+            ui.add(egui::Slider::new(&mut settings.quality, 1..=100).text("Quality"));
+        }
+
+        egui::ComboBox::from_label("Profile")
+            .selected_text(settings.profile.name())
+            .show_ui(ui, |ui| {
+                for profile in OutputProfile::ALL {
+                    ui.selectable_value(&mut settings.profile, profile, profile.name());
+                }
+            });
+        if settings.profile == OutputProfile::Custom {
+            ui.add(
+                egui::TextEdit::singleline(&mut settings.custom_profile_path)
+                    .hint_text("path/to/printer.icc"),
+            );
+        }
+
+        ui.separator();
+        // Without the working image only the thumbnail would be exported
+        let readable = self.working_texture.is_some();
+        if ui
+            .add_enabled(readable, egui::Button::new("Export"))
+            .clicked()
+        {
+            self.export_requested = true;
+            ui.close_menu();
+        }
+    }
+
+    fn film_base_ui(&mut self, ui: &mut egui::Ui) {
+        ui.horizontal(|ui| {
+            ui.label("film base");
+            egui::color_picker::show_color(
+                ui,
+                swatch_color(self.frag_uniform.film_base),
+                egui::vec2(24.0, 16.0),
+            );
+
+            if let Some(reading) = self.sample_picker(ui, "Pick") {
+                self.frag_uniform.film_base = sampler::encode(reading.input);
+            }
+            if ui.button("Reset").clicked() {
+                self.frag_uniform.film_base = [1.0; 3];
+            }
+        });
+    }
+
+    fn white_balance_ui(&mut self, ui: &mut egui::Ui) {
+        let [red, _, blue] = &mut self.frag_uniform.white_balance;
+
+        ui.label("red");
+        ui.add(
+            egui::Slider::new(red, 0.25..=4.0)
+                .logarithmic(true)
+                .trailing_fill(true),
+        );
+
+        ui.label("blue");
+        ui.add(
+            egui::Slider::new(blue, 0.25..=4.0)
+                .logarithmic(true)
+                .trailing_fill(true),
+        );
+
+        ui.horizontal(|ui| {
+            if let Some(reading) = self.sample_picker(ui, "Neutral from sample") {
+                // Balance the color the shader sees right before the gains
+                let uniform = &self.frag_uniform;
+                let color = if uniform.invert != 0 {
+                    sampler::invert_negative(reading.input, uniform.film_base)
+                } else {
+                    reading.input
+                };
+                self.frag_uniform.white_balance = sampler::neutral_gains(color);
+            }
+            if ui.button("Reset").clicked() {
+                self.frag_uniform.white_balance = [1.0; 3];
+            }
+        });
+    }
+
+    /// A menu listing the sample points, returns the reading of the one picked
+    fn sample_picker(&self, ui: &mut egui::Ui, label: &str) -> Option<SampleReading> {
+        let mut picked = None;
+
+        ui.menu_button(label, |ui| {
+            if self.sample_readings.is_empty() {
+                ui.label("Place a sample point first");
+            }
+
+            for (i, reading) in self.sample_readings.iter().enumerate() {
+                if ui.button(format!("Sample {}", i + 1)).clicked() {
+                    picked = Some(*reading);
+                    ui.close_menu();
+                }
+            }
+        });
+
+        picked
+    }
+
+    fn calibration_ui(&mut self, ui: &mut egui::Ui) {
+        ui.label("Profiles the scanner from a scan of a color target, open the scan to use it");
+
+        egui::ComboBox::from_label("Target")
+            .selected_text(self.target_layout.name())
+            .show_ui(ui, |ui| {
+                for layout in TargetLayout::ALL {
+                    ui.selectable_value(&mut self.target_layout, layout, layout.name());
+                }
+            });
+        ui.add(
+            egui::TextEdit::singleline(&mut self.reference_path).hint_text("path/to/reference.txt"),
+        );
+
+        ui.horizontal(|ui| {
+            if ui
+                .toggle_value(&mut self.placing_grid, "Place grid")
+                .on_hover_text("Drag the corners onto the centers of the corner patches")
+                .changed()
+                && self.placing_grid
+            {
+                self.brush_active = false;
+                self.sampler_active = false;
+            }
+            if ui.button("Fit").clicked() {
+                self.fit_calibration();
+            }
+        });
+
+        let Some(fitted) = self.calibration else {
+            return;
+        };
+        ui.label(format!(
+            "ΔE mean {:.2}, max {:.2}",
+            fitted.mean_error, fitted.max_error
+        ));
+
+        ui.add(egui::TextEdit::singleline(&mut self.calibration_name).hint_text("Profile name"));
+        let named = !self.calibration_name.trim().is_empty();
+        ui.horizontal(|ui| {
+            if ui.add_enabled(named, egui::Button::new("Save")).clicked() {
+                self.save_calibration(&fitted, false);
+            }
+            if ui
+                .add_enabled(named, egui::Button::new("Save and use for this roll"))
+                .clicked()
+            {
+                self.save_calibration(&fitted, true);
+            }
+        });
+    }
+
+    fn camera_correction_ui(&mut self, ui: &mut egui::Ui) {
+        let applied = self
+            .camera_corrections
+            .iter()
+            .find(|c| c.matrix == self.frag_uniform.input_matrix)
+            .map(|c| c.name());
+        let identity: [[f32; 4]; 4] = Matrix4::identity().into();
+        let selected = match applied {
+            Some(name) => name,
+            None if self.frag_uniform.input_matrix == identity => "None".to_string(),
+            None => "Unsaved".to_string(),
+        };
+
+        egui::ComboBox::from_label("Applied")
+            .selected_text(selected)
+            .show_ui(ui, |ui| {
+                if ui.selectable_label(false, "None").clicked() {
+                    self.frag_uniform.input_matrix = identity;
+                }
+                for correction in &self.camera_corrections {
+                    if ui.selectable_label(false, correction.name()).clicked() {
+                        self.frag_uniform.input_matrix = correction.matrix;
+                    }
+                }
+            });
+
+        ui.separator();
+        ui.label(
+            "To measure one, open a frame of a ColorChecker shot with the same camera and light",
+        );
+        ui.horizontal(|ui| {
+            if ui
+                .toggle_value(&mut self.placing_grid, "Place grid")
+                .on_hover_text("Drag the corners onto the centers of the corner patches")
+                .changed()
+                && self.placing_grid
+            {
+                self.target_layout = TargetLayout::ColorChecker;
+                self.brush_active = false;
+                self.sampler_active = false;
+            }
+            if ui.button("Measure").clicked() {
+                self.fit_camera_correction();
+            }
+        });
+
+        let Some(fit) = self.correction_fit else {
+            return;
+        };
+        ui.label(format!(
+            "ΔE mean {:.2}, max {:.2}",
+            fit.mean_error, fit.max_error
+        ));
+        ui.add(egui::TextEdit::singleline(&mut self.camera_name).hint_text("Camera"));
+        ui.add(egui::TextEdit::singleline(&mut self.light_name).hint_text("Light source"));
+
+        let named = !self.camera_name.trim().is_empty() && !self.light_name.trim().is_empty();
+        if ui
+            .add_enabled(named, egui::Button::new("Save and apply"))
+            .clicked()
+        {
+            self.save_camera_correction(&fit);
+        }
+    }
+
+    /// Measures the ColorChecker in the working space, before any edits
+    fn fit_camera_correction(&mut self) {
+        let Some(pixels) = load_working_image(&self.db, &self.key.path) else {
+            self.status = "Couldn't read the image".to_string();
+            return;
+        };
+
+        let rgb = color::encode_working(&pixels);
+        let measured = calibration::measure(&rgb, &self.patch_grid, TargetLayout::ColorChecker);
+        self.correction_fit = calibration::fit_camera_correction(&measured);
+        self.status = match &self.correction_fit {
+            Some(fit) => format!("Measured a correction, mean ΔE {:.2}", fit.mean_error),
+            None => "Couldn't measure a correction, check the grid".to_string(),
+        };
+    }
+
+    fn save_camera_correction(&mut self, fit: &CorrectionFit) {
+        let correction = db::CameraCorrection {
+            camera: self.camera_name.trim().to_string(),
+            light: self.light_name.trim().to_string(),
+            matrix: Matrix4::from(fit.matrix).into(),
+        };
+
+        self.status = match self.db.save_camera_correction(&correction) {
+            Ok(()) => {
+                self.frag_uniform.input_matrix = correction.matrix;
+                format!("Saved {}", correction.name())
+            }
+            Err(err) => {
+                log::error!("couldn't save the camera correction: {err}");
+                "Couldn't save the correction".to_string()
+            }
+        };
+        self.camera_corrections = load_camera_corrections(&self.db);
+    }
+
+    /// Measures the patches on the file as it was scanned, before any color
+    /// management, and fits a profile to the reference
+    fn fit_calibration(&mut self) {
+        let reference = match calibration::read_reference(Path::new(&self.reference_path)) {
+            Ok(reference) => reference,
+            Err(err) => {
+                self.status = format!("Couldn't read the reference file: {err}");
+                return;
+            }
+        };
+        let scan = match image::open(&self.key.path) {
+            Ok(scan) => scan.to_rgb8(),
+            Err(err) => {
+                self.status = format!("Couldn't read the scan: {err}");
+                return;
+            }
+        };
+
+        let measured = calibration::measure(&scan, &self.patch_grid, self.target_layout);
+        self.calibration = calibration::fit(&measured, &reference);
+        self.status = match &self.calibration {
+            Some(fitted) => format!("Fitted a profile, mean ΔE {:.2}", fitted.mean_error),
+            None => "Couldn't fit a profile, check the target and the reference file".to_string(),
+        };
+    }
+
+    fn save_calibration(&mut self, fitted: &Calibration, use_for_roll: bool) {
+        let name = self.calibration_name.trim().to_string();
+        let profile = db::InputProfile {
+            icc: fitted.icc(&name),
+            name,
+        };
+
+        let saved = self.db.save_input_profile(&profile).and_then(|()| {
+            if !use_for_roll {
+                return Ok(());
+            }
+            let mut roll = self.db.get_roll(&db::roll_path(&self.key.path))?;
+            roll.input_profile = Some(profile.name.clone());
+            self.db.save_roll(&roll)
+        });
+
+        self.status = match saved {
+            Ok(()) if use_for_roll => format!(
+                "Saved {}, the roll uses it from the next time an image is opened",
+                profile.name
+            ),
+            Ok(()) => format!("Saved {}", profile.name),
+            Err(err) => {
+                log::error!("couldn't save the input profile {}: {err}", profile.name);
+                "Couldn't save the profile".to_string()
+            }
+        };
+    }
+
+    fn samples_ui(&mut self, ui: &mut egui::Ui) {
+        if ui
+            .toggle_value(&mut self.sampler_active, "⊕ Sample")
+            .changed()
+            && self.sampler_active
+        {
+            self.brush_active = false;
+            self.placing_grid = false;
+        }
+        ui.label(format!(
+            "Click on the image to add up to {MAX_SAMPLES} points, right click one to remove it"
+        ));
+
+        let mut removed = None;
+        for (i, reading) in self.sample_readings.iter().enumerate() {
+            ui.separator();
+            ui.horizontal(|ui| {
+                ui.strong(format!("{}", i + 1));
+                egui::color_picker::show_color(
+                    ui,
+                    swatch_color(sampler::encode(reading.input)),
+                    egui::vec2(16.0, 16.0),
+                );
+                ui.label("→");
+                egui::color_picker::show_color(
+                    ui,
+                    swatch_color(reading.output),
+                    egui::vec2(16.0, 16.0),
+                );
+                if ui.small_button("🗑").clicked() {
+                    removed = Some(i);
+                }
+            });
+
+            let [r, g, b] = sampler::encode(reading.input).map(|c| (c * 255.0).round());
+            ui.monospace(format!("in  {r:3} {g:3} {b:3}"));
+            let [r, g, b] = reading.output.map(|c| (c * 255.0).round());
+            ui.monospace(format!("RGB {r:3} {g:3} {b:3}"));
+            let [h, s, l] = sampler::rgb_to_hsl(reading.output);
+            ui.monospace(format!("HSL {h:3.0} {:3.0} {:3.0}", s * 100.0, l * 100.0));
+            let [l, a, b] = sampler::rgb_to_lab(reading.output);
+            ui.monospace(format!("Lab {l:3.0} {a:3.0} {b:3.0}"));
+        }
+
+        // The readings lag a frame behind, so the point may be gone already
+        if let Some(i) = removed.filter(|i| *i < self.samples.len()) {
+            self.samples.remove(i);
+        }
+    }
+
+    fn sampler_overlay(
+        &mut self,
+        ui: &mut egui::Ui,
+        response: &egui::Response,
+        transform: ImageTransform,
+    ) {
+        let clicked_uv = response
+            .interact_pointer_pos()
+            .filter(|_| response.clicked())
+            .map(|pos| transform.to_uv(pos))
+            .filter(|uv| full_uv().contains(*uv));
+        if let Some(uv) = clicked_uv.filter(|_| self.samples.len() < MAX_SAMPLES) {
+            self.samples.push(SamplePoint {
+                position: [uv.x, uv.y],
+            });
+        }
+
+        canvas::sample_overlay(ui, transform, &mut self.samples);
+    }
+
+    fn brush_ui(&mut self, ui: &mut egui::Ui) {
+        if ui.toggle_value(&mut self.brush_active, "🖌 Paint").changed() && self.brush_active {
+            self.sampler_active = false;
+            self.placing_grid = false;
+        }
+
+        ui.horizontal(|ui| {
+            ui.selectable_value(&mut self.brush.mode, BrushMode::Dodge, "Dodge");
+            ui.selectable_value(&mut self.brush.mode, BrushMode::Burn, "Burn");
+        });
+
+        ui.label("size");
+        ui.add(egui::Slider::new(&mut self.brush.size, 0.005..=0.25).trailing_fill(true));
+
+        ui.label("feather");
+        ui.add(egui::Slider::new(&mut self.brush.feather, 0.0..=1.0).trailing_fill(true));
+
+        ui.label("flow");
+        ui.add(egui::Slider::new(&mut self.brush.flow, 0.05..=1.0).trailing_fill(true));
+
+        ui.horizontal(|ui| {
+            if ui.button("Undo").clicked() && self.strokes.pop().is_some() {
+                self.dodge_burn.rebuild(&self.strokes);
+            }
+            if ui.button("Clear").clicked() {
+                self.strokes.clear();
+                self.dodge_burn.rebuild(&self.strokes);
+            }
+        });
+    }
+
+    /// Paints on the mask while dragging over the image
+    fn brush_overlay(
+        &mut self,
+        ui: &egui::Ui,
+        response: &egui::Response,
+        transform: ImageTransform,
+    ) {
+        if let Some(pos) = response.hover_pos() {
+            ui.painter().circle_stroke(
+                pos,
+                self.brush.size * transform.width(),
+                egui::Stroke::new(1.0, egui::Color32::WHITE),
+            );
+        }
+
+        let Some(pos) = response.interact_pointer_pos() else {
+            return;
+        };
+        let uv = transform.to_uv(pos);
+        let point = [uv.x, uv.y];
+
+        if response.drag_started_by(egui::PointerButton::Primary) {
+            let stroke = BrushStroke {
+                settings: self.brush,
+                points: vec![point],
+            };
+            self.dodge_burn.begin_stroke(&stroke);
+            self.strokes.push(stroke);
+        } else if response.dragged_by(egui::PointerButton::Primary) {
+            let Some(stroke) = self.strokes.last_mut() else {
+                return;
+            };
+
+            let last = *stroke.points.last().unwrap();
+            if last != point {
+                stroke.points.push(point);
+                self.dodge_burn.paint_segment(&stroke.settings, last, point);
+            }
+        }
+    }
+
+    fn hsl_ui(&mut self, ui: &mut egui::Ui) {
+        egui::ComboBox::from_id_source("hsl_band")
+            .selected_text(HSL_BANDS[self.hsl_band])
+            .show_ui(ui, |ui| {
+                for (i, name) in HSL_BANDS.iter().enumerate() {
+                    ui.selectable_value(&mut self.hsl_band, i, *name);
+                }
+            });
+
+        let hsl = &mut self.frag_uniform.hsl;
+        let band = self.hsl_band;
+
+        ui.label("hue");
+        ui.add(egui::Slider::new(&mut hsl.hue[band], -1.0..=1.0).trailing_fill(true));
+
+        ui.label("saturation");
+        ui.add(egui::Slider::new(&mut hsl.saturation[band], -1.0..=1.0).trailing_fill(true));
+
+        ui.label("luminance");
+        ui.add(egui::Slider::new(&mut hsl.luminance[band], -1.0..=1.0).trailing_fill(true));
+
+        if ui.button("Reset").clicked() {
+            *hsl = Default::default();
+        }
+    }
+
+    fn grading_ui(&mut self, ui: &mut egui::Ui) {
+        let grading = &mut self.frag_uniform.grading;
+
+        grading_wheel_ui(ui, "shadows", &mut grading.shadows);
+        grading_wheel_ui(ui, "midtones", &mut grading.midtones);
+        grading_wheel_ui(ui, "highlights", &mut grading.highlights);
+
+        ui.label("balance");
+        ui.add(egui::Slider::new(&mut grading.balance, -1.0..=1.0).trailing_fill(true));
+
+        grading_wheel_ui(ui, "global", &mut grading.global);
+
+        if ui.button("Reset").clicked() {
+            *grading = Default::default();
+        }
+    }
+
+    fn monochrome_ui(&mut self, ui: &mut egui::Ui) {
+        let monochrome = &mut self.frag_uniform.monochrome;
+
+        let mut enabled = monochrome.enabled != 0;
+        ui.add(egui::Checkbox::new(&mut enabled, "Monochrome"));
+        monochrome.enabled = enabled as u32;
+
+        ui.add_enabled_ui(enabled, |ui| {
+            ui.label("filter");
+            ui.horizontal_wrapped(|ui| {
+                for filter in ContrastFilter::ALL {
+                    let selected = monochrome.mixer == filter.mixer();
+                    if ui.selectable_label(selected, filter.name()).clicked() {
+                        monochrome.mixer = filter.mixer();
+                    }
+                }
+            });
+
+            for (name, weight) in ["red", "green", "blue"].iter().zip(&mut monochrome.mixer) {
+                ui.label(*name);
+                ui.add(egui::Slider::new(weight, 0.0..=1.0).trailing_fill(true));
+            }
+
+            ui.label("toning");
+            let mut toning = Toning::ALL
+                .get(monochrome.toning as usize)
+                .copied()
+                .unwrap_or(Toning::None);
+            egui::ComboBox::from_id_source("toning")
+                .selected_text(toning.name())
+                .show_ui(ui, |ui| {
+                    for t in Toning::ALL {
+                        ui.selectable_value(&mut toning, t, t.name());
+                    }
                 });
+            monochrome.toning = toning as u32;
+
+            if toning != Toning::None {
+                ui.label("strength");
+                ui.add(
+                    egui::Slider::new(&mut monochrome.toning_strength, 0.0..=1.0)
+                        .trailing_fill(true),
+                );
+            }
+
+            if toning == Toning::SplitTone {
+                ui.label("shadows hue");
+                ui.add(egui::Slider::new(
+                    &mut monochrome.split_hues[0],
+                    0.0..=360.0,
+                ));
+                ui.label("highlights hue");
+                ui.add(egui::Slider::new(
+                    &mut monochrome.split_hues[1],
+                    0.0..=360.0,
+                ));
+            }
+        });
+    }
+
+    fn lens_ui(&mut self, ui: &mut egui::Ui) {
+        let lens = &mut self.frag_uniform.lens;
+
+        ui.label("distortion k1");
+        ui.add(egui::Slider::new(&mut lens.k1, -0.3..=0.3).trailing_fill(true));
+        ui.label("distortion k2");
+        ui.add(egui::Slider::new(&mut lens.k2, -0.1..=0.1).trailing_fill(true));
+
+        ui.label("vignetting");
+        ui.add(egui::Slider::new(&mut lens.vignetting, -1.0..=0.5).trailing_fill(true))
+            .on_hover_text(
+                "The lens falloff, 1 + vignetting × r² with r = 1 at the corners. \
+                 Negative when the corners came out darker, they're brightened back.",
+            );
+
+        ui.label("red chromatic aberration");
+        ui.add(
+            egui::Slider::new(&mut lens.ca_red, -0.005..=0.005)
+                .max_decimals(4)
+                .trailing_fill(true),
+        );
+        ui.label("blue chromatic aberration");
+        ui.add(
+            egui::Slider::new(&mut lens.ca_blue, -0.005..=0.005)
+                .max_decimals(4)
+                .trailing_fill(true),
+        );
+
+        if ui.button("Reset").clicked() {
+            *lens = Default::default();
+        }
+
+        ui.separator();
+        ui.label("load profile from");
+        ui.text_edit_singleline(&mut self.lens_profile_path);
+        if ui
+            .add_enabled(
+                !self.lens_profile_path.trim().is_empty(),
+                egui::Button::new("Load"),
+            )
+            .clicked()
+        {
+            self.status = match LensProfile::load(Path::new(self.lens_profile_path.trim())) {
+                Ok(profile) => {
+                    self.frag_uniform.lens = profile.correction;
+                    format!("Corrected for {}", profile.name)
+                }
+                Err(err) => format!("Couldn't load the lens profile: {err}"),
+            };
+        }
+
+        ui.label("save profile as");
+        ui.text_edit_singleline(&mut self.lens_profile_name);
+        let name = self.lens_profile_name.trim();
+        if ui
+            .add_enabled(!name.is_empty(), egui::Button::new("Save"))
+            .clicked()
+        {
+            let profile = LensProfile {
+                name: name.to_string(),
+                correction: self.frag_uniform.lens,
+            };
+            self.status = match profile.save() {
+                Ok(path) => format!("Saved {}", path.display()),
+                Err(err) => format!("Couldn't save the lens profile: {err}"),
+            };
+        }
+    }
+
+    fn paper_ui(&mut self, ui: &mut egui::Ui) {
+        let monochrome = self.frag_uniform.monochrome.enabled != 0;
+        let paper = &mut self.frag_uniform.paper;
+
+        if !monochrome {
+            ui.label("Only available in monochrome");
+        }
+
+        ui.add_enabled_ui(monochrome, |ui| {
+            let mut enabled = paper.enabled != 0;
+            ui.add(egui::Checkbox::new(&mut enabled, "Print on paper"));
+            paper.enabled = enabled as u32;
+
+            let mut split_grade = paper.split_grade != 0;
+            ui.add_enabled(
+                enabled,
+                egui::Checkbox::new(&mut split_grade, "Split grade"),
+            );
+            paper.split_grade = split_grade as u32;
+
+            ui.add_enabled_ui(enabled, |ui| {
+                if split_grade {
+                    ui.label("soft exposure (00)");
+                    ui.add(
+                        egui::Slider::new(&mut paper.soft_exposure, -4.0..=4.0)
+                            .step_by(1.0 / 6.0)
+                            .suffix(" EV")
+                            .trailing_fill(true),
+                    );
+
+                    ui.label("hard exposure (5)");
+                    ui.add(
+                        egui::Slider::new(&mut paper.hard_exposure, -4.0..=4.0)
+                            .step_by(1.0 / 6.0)
+                            .suffix(" EV")
+                            .trailing_fill(true),
+                    );
+                } else {
+                    ui.label("exposure");
+                    ui.add(
+                        egui::Slider::new(&mut paper.exposure, -3.0..=3.0)
+                            .step_by(1.0 / 6.0)
+                            .suffix(" EV")
+                            .trailing_fill(true),
+                    );
+
+                    ui.label("grade");
+                    ui.add(
+                        egui::Slider::new(&mut paper.grade, PAPER_GRADE_MIN..=PAPER_GRADE_MAX)
+                            .step_by(0.5)
+                            .custom_formatter(|grade, _| paper_grade_name(grade as f32))
+                            .trailing_fill(true),
+                    );
+                }
             });
         });
     }
 }
 
+fn grading_wheel_ui(ui: &mut egui::Ui, name: &str, wheel: &mut [f32; 3]) {
+    let [hue, strength, luminance] = wheel;
+
+    ui.label(name);
+    ui.add(ColorWheel::new(hue, strength));
+    ui.add(egui::Slider::new(luminance, -1.0..=1.0).trailing_fill(true));
+}
+
+fn mq_to_egui_texture_id(mq_ctx: &mut mq::Context, from: mq::TextureId) -> egui::TextureId {
+    let raw_id = match unsafe { mq_ctx.texture_raw_id(from) } {
+        mq::RawId::OpenGl(id) => id as u64,
+    };
+
+    egui::TextureId::User(raw_id)
+}
+
+/// The image in the working space, as uploaded for developing
+struct WorkingImage {
+    texture: InputTexture,
+    /// The image at full size, when it's too big for a texture and the
+    /// texture holds a scaled down copy
+    full_image: Option<color::LinearImage>,
+    /// A copy at [`PROXY_SIZE`], for images bigger than that
+    proxy: Option<InputTexture>,
+    /// Whether the image has next to no color, so new edits start in black
+    /// and white
+    monochrome: bool,
+}
+
+/// Uploads the image in the working space. Images too big for a texture are
+/// uploaded scaled down, and returned at full size along with it.
+fn load_working_texture(
+    mq_ctx: &mut mq::Context,
+    db: &Database,
+    path: &str,
+    max_texture_size: u32,
+) -> Option<WorkingImage> {
+    let pixels = load_working_image(db, path)?;
+    let monochrome = color::is_monochrome(&pixels);
+
+    let mut upload = |pixels: &color::LinearImage| {
+        let texture = InputTexture::linear(mq_ctx, pixels);
+        if texture.is_none() {
+            let (width, height) = pixels.dimensions();
+            log::error!("couldn't upload {path}, {width} x {height} px is too big for a texture");
+        }
+        texture
+    };
+
+    let proxy = scaled_to(&pixels, PROXY_SIZE).and_then(|proxy| upload(&proxy));
+
+    if !tile::needs_tiling(pixels.dimensions(), max_texture_size) {
+        let texture = upload(&pixels)?;
+        return Some(WorkingImage {
+            texture,
+            full_image: None,
+            proxy,
+            monochrome,
+        });
+    }
+
+    let preview = scaled_to(&pixels, max_texture_size).expect("image needs tiling");
+    let texture = upload(&preview)?;
+
+    Some(WorkingImage {
+        texture,
+        full_image: Some(pixels),
+        proxy,
+        monochrome,
+    })
+}
+
+/// A copy with the long edge at `size`, if the image is bigger than that
+fn scaled_to(pixels: &color::LinearImage, size: u32) -> Option<color::LinearImage> {
+    let (width, height) = pixels.dimensions();
+    if width.max(height) <= size {
+        return None;
+    }
+
+    let scale = size as f32 / width.max(height) as f32;
+    Some(image::imageops::resize(
+        pixels,
+        ((width as f32 * scale) as u32).max(1),
+        ((height as f32 * scale) as u32).max(1),
+        image::imageops::FilterType::Triangle,
+    ))
+}
+
+/// Reads the image again and converts it into the working space, using the
+/// profile assigned to its roll if there's one
+fn load_working_image(db: &Database, path: &str) -> Option<color::LinearImage> {
+    let assigned = match db.get_input_profile_for(path) {
+        Ok(profile) => profile,
+        Err(err) => {
+            log::error!("couldn't load the input profile of {path}: {err}");
+            None
+        }
+    };
+
+    match color::load_working_image(Path::new(path), assigned.as_ref().map(|p| p.icc.as_slice())) {
+        Ok(pixels) => Some(pixels),
+        Err(err) => {
+            log::error!("couldn't convert {path} to the working space: {err}");
+            None
+        }
+    }
+}
+
+fn load_camera_corrections(db: &Database) -> Vec<db::CameraCorrection> {
+    db.get_camera_corrections().unwrap_or_else(|err| {
+        log::error!("couldn't load the camera corrections: {err}");
+        vec![]
+    })
+}
+
+fn load_presets(db: &Database) -> Vec<Preset> {
+    db.get_presets().unwrap_or_else(|err| {
+        log::error!("couldn't load the presets: {err}");
+        vec![]
+    })
+}
+
+/// A color from 0 to 1 as shown in the UI
+fn swatch_color(rgb: [f32; 3]) -> egui::Color32 {
+    let [r, g, b] = rgb.map(|c| (c.clamp(0.0, 1.0) * 255.0).round() as u8);
+    egui::Color32::from_rgb(r, g, b)
+}
+
+/// The whole texture
+fn full_uv() -> egui::Rect {
+    egui::Rect::from_min_max(egui::pos2(0.0, 0.0), egui::pos2(1.0, 1.0))
+}
+
 fn egui_to_mq_texture_id(from: egui::TextureId) -> mq::TextureId {
     match from {
         egui::TextureId::Managed(id) => {
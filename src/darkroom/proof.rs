@@ -0,0 +1,115 @@
+//! Soft proofing, showing the image as it would come out of a printer or on
+//! a paper, and which colors it can't reproduce
+
+use qcms::{DataType, Intent, Profile, Transform};
+
+use crate::darkroom::color;
+
+/// Entries of the lookup table on each axis, the shader has to agree
+pub const LUT_SIZE: usize = 33;
+
+/// How far a color can move on a round trip through the proofed profile
+/// before it counts as out of gamut, in 8 bit steps
+const GAMUT_TOLERANCE: i32 = 3;
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum RenderingIntent {
+    Perceptual,
+    RelativeColorimetric,
+    Saturation,
+    AbsoluteColorimetric,
+}
+
+impl RenderingIntent {
+    pub const ALL: [RenderingIntent; 4] = [
+        RenderingIntent::Perceptual,
+        RenderingIntent::RelativeColorimetric,
+        RenderingIntent::Saturation,
+        RenderingIntent::AbsoluteColorimetric,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            RenderingIntent::Perceptual => "Perceptual",
+            RenderingIntent::RelativeColorimetric => "Relative colorimetric",
+            RenderingIntent::Saturation => "Saturation",
+            RenderingIntent::AbsoluteColorimetric => "Absolute colorimetric",
+        }
+    }
+
+    fn qcms(&self) -> Intent {
+        match self {
+            RenderingIntent::Perceptual => Intent::Perceptual,
+            RenderingIntent::RelativeColorimetric => Intent::RelativeColorimetric,
+            RenderingIntent::Saturation => Intent::Saturation,
+            RenderingIntent::AbsoluteColorimetric => Intent::AbsoluteColorimetric,
+        }
+    }
+}
+
+/// Builds a table taking working space colors to how they look on the
+/// display once printed with the profile. The table is laid out as an RGBA
+/// image of `LUT_SIZE * LUT_SIZE` by `LUT_SIZE` pixels, red goes along the
+/// rows inside each blue slice and green goes down the columns. Alpha is 0
+/// for colors the profile can't reproduce.
+///
+/// Returns None when the profile isn't an RGB profile that can be printed to.
+pub fn build_lut(icc: &[u8], intent: RenderingIntent) -> Option<Vec<u8>> {
+    let mut proofed = Profile::new_from_slice(icc, false)?;
+    proofed.precache_output_transform();
+    let mut working = color::WORKING_SPACE.profile();
+    working.precache_output_transform();
+    let mut display = color::SRGB.profile();
+    display.precache_output_transform();
+
+    let to_proofed = Transform::new(&working, &proofed, DataType::RGBA8, intent.qcms())?;
+    let to_display = Transform::new(
+        &proofed,
+        &display,
+        DataType::RGBA8,
+        Intent::RelativeColorimetric,
+    )?;
+    // Colorimetric both ways, so whatever moves is out of gamut rather than
+    // remapped on purpose
+    let check_in = Transform::new(
+        &working,
+        &proofed,
+        DataType::RGBA8,
+        Intent::RelativeColorimetric,
+    )?;
+    let check_out = Transform::new(
+        &proofed,
+        &working,
+        DataType::RGBA8,
+        Intent::RelativeColorimetric,
+    )?;
+
+    let step = |i: usize| (i * 255 / (LUT_SIZE - 1)) as u8;
+    let mut grid = Vec::with_capacity(LUT_SIZE.pow(3) * 4);
+    for g in 0..LUT_SIZE {
+        for b in 0..LUT_SIZE {
+            for r in 0..LUT_SIZE {
+                grid.extend([step(r), step(g), step(b), 255]);
+            }
+        }
+    }
+
+    let mut lut = grid.clone();
+    to_proofed.apply(&mut lut);
+    to_display.apply(&mut lut);
+
+    let mut round_trip = grid.clone();
+    check_in.apply(&mut round_trip);
+    check_out.apply(&mut round_trip);
+
+    for ((entry, original), back) in lut
+        .chunks_exact_mut(4)
+        .zip(grid.chunks_exact(4))
+        .zip(round_trip.chunks_exact(4))
+    {
+        let moved = (0..3).any(|c| (original[c] as i32 - back[c] as i32).abs() > GAMUT_TOLERANCE);
+        entry[3] = if moved { 0 } else { 255 };
+    }
+
+    Some(lut)
+}
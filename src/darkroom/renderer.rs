@@ -1,24 +1,56 @@
+use image::RgbaImage;
 use miniquad as mq;
 
-use crate::darkroom::vertex::Vertex;
+use crate::darkroom::{mq_to_egui_texture_id, vertex::Vertex};
 
-use super::uniform::FragmentUniform;
+use super::{
+    color::{self, LinearImage},
+    sampler::{SamplePoint, SampleReading, MAX_SAMPLES},
+    stage::DevelopStage,
+    texture::InputTexture,
+    tile,
+    uniform::{
+        ClippingUniform, FragmentUniform, OutputUniform, ProofUniform, SampleUniform,
+        StagesUniform, TileUniform,
+    },
+    PROXY_SIZE,
+};
 
-#[derive(Copy, Clone)]
+/// Develops an image on the GPU. Only the main pass is made up front, the
+/// others take a texture the size of the image each, so they're made the
+/// first time they're used.
 pub struct Renderer {
     pipeline: mq::Pipeline,
     vertex_buffer: mq::BufferId,
     index_buffer: mq::BufferId,
     render_pass: mq::RenderPass,
-    input_texture_id: mq::TextureId,
+    /// Where snapshots are rendered to compare them against the current edits
+    snapshot_pass: Option<mq::RenderPass>,
+    /// The image without edits, to compare against
+    original_pass: Option<mq::RenderPass>,
+    /// What the input and tone stages left, in linear light, so a change to
+    /// a later stage doesn't run them again
+    intermediate_passes: Option<[mq::RenderPass; 2]>,
+    /// Display only pass that marks clipped pixels on top of the output
+    clipping: Option<Pass>,
+    /// Display only pass showing the image through a printer profile. The
+    /// image is rendered in the working space first, then looked up.
+    proof: Option<ProofPasses>,
+    /// Reads the colors under the sample points into a row of texels
+    sample: Option<Pass>,
+    /// The image being developed, deleted along with the renderer
+    input: InputTexture,
+    dodge_burn_texture_id: mq::TextureId,
+    dimensions: (u32, u32),
 }
 
 impl Renderer {
     pub fn new(
         mq_ctx: &mut mq::Context,
-        texture_id: mq::TextureId,
-        dimensions: [usize; 2],
+        input: InputTexture,
+        dodge_burn_texture_id: mq::TextureId,
     ) -> Self {
+        let dimensions = [input.size.0 as usize, input.size.1 as usize];
         let vertex_buffer = get_vertex_buffer(mq_ctx);
 
         #[rustfmt::skip]
@@ -31,82 +63,527 @@ impl Renderer {
             mq::BufferSource::slice(indices),
         );
 
-        let shader = mq_ctx
-            .new_shader(
-                mq::ShaderSource::Glsl {
-                    vertex: include_str!("shader_vert.glsl"),
-                    fragment: include_str!("shader_frag.glsl"),
-                },
-                mq::ShaderMeta {
-                    images: vec!["tex".to_string()],
-                    uniforms: mq::UniformBlockLayout {
-                        uniforms: vec![mq::UniformDesc::new("contrast", mq::UniformType::Float1)],
-                    },
+        let pipeline = new_pipeline(
+            mq_ctx,
+            include_str!("shader_frag.glsl"),
+            mq::ShaderMeta {
+                images: vec![
+                    "tex".to_string(),
+                    "tex_low".to_string(),
+                    "dodge_burn".to_string(),
+                ],
+                uniforms: mq::UniformBlockLayout {
+                    uniforms: FragmentUniform::uniform_descs(),
                 },
-            )
-            .unwrap();
-
-        let pipeline = mq_ctx.new_pipeline(
-            &[mq::BufferLayout {
-                ..Default::default()
-            }],
-            &[
-                mq::VertexAttribute::new("position", mq::VertexFormat::Float2),
-                mq::VertexAttribute::new("tex_coords", mq::VertexFormat::Float2),
-            ],
-            shader,
-            mq::PipelineParams {
-                depth_write: true,
-                depth_test: mq::Comparison::LessOrEqual,
-                ..Default::default()
             },
         );
-
-        let output_texture = mq_ctx.new_render_texture(mq::TextureParams {
-            width: dimensions[0] as u32,
-            height: dimensions[1] as u32,
-            format: mq::TextureFormat::RGBA8,
-            ..Default::default()
-        });
-
-        let render_pass = mq_ctx.new_render_pass(output_texture, None);
+        let render_pass = new_render_pass(mq_ctx, dimensions);
 
         Self {
             render_pass,
+            snapshot_pass: None,
+            original_pass: None,
+            intermediate_passes: None,
             pipeline,
+            clipping: None,
+            proof: None,
+            sample: None,
             vertex_buffer,
             index_buffer,
-            input_texture_id: texture_id,
+            input,
+            dodge_burn_texture_id,
+            dimensions: input.size,
         }
     }
 
+    /// Frees the passes, the buffers and the input texture. miniquad has no
+    /// way to delete pipelines, they're small next to the passes.
+    pub fn delete(self, mq_ctx: &mut mq::Context) {
+        let passes = [
+            Some(self.render_pass),
+            self.snapshot_pass,
+            self.original_pass,
+            self.intermediate_passes.map(|passes| passes[0]),
+            self.intermediate_passes.map(|passes| passes[1]),
+            self.clipping.map(|clipping| clipping.pass),
+            self.proof.map(|proof| proof.source),
+            self.proof.map(|proof| proof.pass),
+            self.sample.map(|sample| sample.pass),
+        ];
+        for pass in passes.into_iter().flatten() {
+            mq_ctx.delete_render_pass(pass);
+        }
+
+        mq_ctx.delete_buffer(self.vertex_buffer);
+        mq_ctx.delete_buffer(self.index_buffer);
+        self.input.delete(mq_ctx);
+    }
+
     pub fn render(&self, mq_ctx: &mut mq::Context, uniforms: FragmentUniform) -> egui::TextureId {
+        let uniforms = self.whole_image(uniforms);
+        self.render_into(
+            mq_ctx,
+            self.render_pass,
+            uniforms,
+            self.input,
+            self.dodge_burn_texture_id,
+        );
+
+        self.output(mq_ctx)
+    }
+
+    /// Renders from a develop stage on, keeping what the ones before it
+    /// left last time. They have to have run with the same edits. Renderers
+    /// bigger than the proxy develop in a single pass, intermediate textures
+    /// take 8 bytes a pixel and big scans are only rendered once the sliders
+    /// are let go.
+    pub fn render_from(
+        &mut self,
+        mq_ctx: &mut mq::Context,
+        uniforms: FragmentUniform,
+        first: DevelopStage,
+    ) -> egui::TextureId {
+        let size = self.pass_size();
+        if size[0].max(size[1]) > PROXY_SIZE as usize {
+            return self.render(mq_ctx, uniforms);
+        }
+
+        let intermediates = *self.intermediate_passes.get_or_insert_with(|| {
+            [0, 1].map(|_| new_pass(mq_ctx, size, mq::TextureFormat::RGBA16F))
+        });
+        let passes = [intermediates[0], intermediates[1], self.render_pass];
+
+        let mut uniforms = self.whole_image(uniforms);
+        for stage in DevelopStage::ALL
+            .into_iter()
+            .filter(|stage| *stage >= first)
+        {
+            uniforms.stages = StagesUniform::only(stage);
+            let input = match stage {
+                DevelopStage::Input => self.input,
+                _ => {
+                    let before = passes[stage as usize - 1];
+                    let texture = mq_ctx.render_pass_color_attachments(before)[0];
+                    InputTexture {
+                        high: texture,
+                        low: texture,
+                        size: self.dimensions,
+                        gamma: false,
+                    }
+                }
+            };
+            self.render_into(
+                mq_ctx,
+                passes[stage as usize],
+                uniforms,
+                input,
+                self.dodge_burn_texture_id,
+            );
+        }
+
+        self.output(mq_ctx)
+    }
+
+    /// The output of the last [`Renderer::render`]
+    pub fn output(&self, mq_ctx: &mut mq::Context) -> egui::TextureId {
+        let output_texture = self.output_texture(mq_ctx);
+        mq_to_egui_texture_id(mq_ctx, output_texture)
+    }
+
+    /// Renders other edits without touching the output of [`Renderer::render`]
+    pub fn render_snapshot(
+        &mut self,
+        mq_ctx: &mut mq::Context,
+        uniforms: FragmentUniform,
+        dodge_burn_texture_id: mq::TextureId,
+    ) -> egui::TextureId {
+        let size = self.pass_size();
+        let pass = *self
+            .snapshot_pass
+            .get_or_insert_with(|| new_render_pass(mq_ctx, size));
+
+        let uniforms = self.whole_image(uniforms);
+        self.render_into(mq_ctx, pass, uniforms, self.input, dodge_burn_texture_id);
+
+        self.snapshot_output(mq_ctx)
+    }
+
+    /// The output of the last [`Renderer::render_snapshot`]
+    pub fn snapshot_output(&self, mq_ctx: &mut mq::Context) -> egui::TextureId {
+        let pass = self.snapshot_pass.expect("no snapshot was rendered");
+        let snapshot_texture = mq_ctx.render_pass_color_attachments(pass)[0];
+        mq_to_egui_texture_id(mq_ctx, snapshot_texture)
+    }
+
+    /// Renders the input without any edits, from the same working space
+    /// texture and in the same display space as the developed image. The
+    /// mask has to leave the exposure alone.
+    pub fn render_original(
+        &mut self,
+        mq_ctx: &mut mq::Context,
+        neutral_mask_id: mq::TextureId,
+    ) -> egui::TextureId {
+        let size = self.pass_size();
+        let pass = *self
+            .original_pass
+            .get_or_insert_with(|| new_render_pass(mq_ctx, size));
+
+        let uniforms = self.whole_image(FragmentUniform::default());
+        self.render_into(mq_ctx, pass, uniforms, self.input, neutral_mask_id);
+
+        let original_texture = mq_ctx.render_pass_color_attachments(pass)[0];
+        mq_to_egui_texture_id(mq_ctx, original_texture)
+    }
+
+    /// Renders an image bigger than what the GPU takes at full resolution,
+    /// one tile at a time. The input texture of the renderer has to be a
+    /// scaled down copy of it, the dodge & burn mask is shared with it.
+    /// Returns None if a tile couldn't be uploaded.
+    pub fn render_tiled(
+        &self,
+        mq_ctx: &mut mq::Context,
+        uniforms: FragmentUniform,
+        image: &LinearImage,
+        max_texture_size: u32,
+    ) -> Option<RgbaImage> {
+        let size = image.dimensions();
+
+        tile::render_tiled(image, &uniforms.lens, max_texture_size, |source, tile| {
+            let input = InputTexture::linear(mq_ctx, &source)?;
+            let pass = new_render_pass(
+                mq_ctx,
+                [tile.region.width as usize, tile.region.height as usize],
+            );
+
+            let mut uniforms = uniforms;
+            uniforms.tile = tile.uniform(size);
+            self.render_into(mq_ctx, pass, uniforms, input, self.dodge_burn_texture_id);
+
+            let mut bytes = vec![0; tile.region.width as usize * tile.region.height as usize * 4];
+            let output = mq_ctx.render_pass_color_attachments(pass)[0];
+            mq_ctx.texture_read_pixels(output, &mut bytes);
+
+            mq_ctx.delete_render_pass(pass);
+            input.delete(mq_ctx);
+
+            let rendered = RgbaImage::from_raw(tile.region.width, tile.region.height, bytes)
+                .expect("tile texture size mismatch");
+            Some(rendered)
+        })
+    }
+
+    /// Covers the whole image with the input texture
+    fn whole_image(&self, mut uniforms: FragmentUniform) -> FragmentUniform {
+        uniforms.tile = TileUniform::whole(self.dimensions);
+        uniforms
+    }
+
+    /// The size of the passes the image is rendered into
+    fn pass_size(&self) -> [usize; 2] {
+        [self.dimensions.0 as usize, self.dimensions.1 as usize]
+    }
+
+    /// Renders into a pass, from the given input texture
+    fn render_into(
+        &self,
+        mq_ctx: &mut mq::Context,
+        pass: mq::RenderPass,
+        mut uniforms: FragmentUniform,
+        input: InputTexture,
+        dodge_burn_texture_id: mq::TextureId,
+    ) {
+        uniforms.input.gamma = input.gamma as u32;
+        let bindings = mq::Bindings {
+            vertex_buffers: vec![self.vertex_buffer],
+            index_buffer: self.index_buffer,
+            images: vec![input.high, input.low, dodge_burn_texture_id],
+        };
+
+        mq_ctx.begin_pass(Some(pass), mq::PassAction::clear_color(0.2, 0.0, 0.0, 1.0));
+        mq_ctx.apply_pipeline(&self.pipeline);
+        mq_ctx.apply_bindings(&bindings);
+        mq_ctx.apply_uniforms(mq::UniformsSource::table(&uniforms));
+        mq_ctx.draw(0, 6, 1);
+        mq_ctx.end_render_pass();
+    }
+
+    /// Marks the clipped pixels of the last render. This only goes to the
+    /// screen, the output of [`Renderer::render`] is left untouched.
+    pub fn render_clipping(
+        &mut self,
+        mq_ctx: &mut mq::Context,
+        uniforms: ClippingUniform,
+    ) -> egui::TextureId {
+        let size = self.pass_size();
+        let clipping = *self.clipping.get_or_insert_with(|| Pass {
+            pipeline: new_pipeline(
+                mq_ctx,
+                include_str!("shader_clipping_frag.glsl"),
+                mq::ShaderMeta {
+                    images: vec!["tex".to_string()],
+                    uniforms: mq::UniformBlockLayout {
+                        uniforms: ClippingUniform::uniform_descs(),
+                    },
+                },
+            ),
+            pass: new_render_pass(mq_ctx, size),
+        });
+
         let bindings = mq::Bindings {
             vertex_buffers: vec![self.vertex_buffer],
             index_buffer: self.index_buffer,
-            images: vec![self.input_texture_id],
+            images: vec![self.output_texture(mq_ctx)],
         };
 
         mq_ctx.begin_pass(
-            Some(self.render_pass),
-            mq::PassAction::clear_color(0.2, 0.0, 0.0, 1.0),
+            Some(clipping.pass),
+            mq::PassAction::clear_color(0.0, 0.0, 0.0, 1.0),
         );
-        mq_ctx.apply_pipeline(&self.pipeline);
+        mq_ctx.apply_pipeline(&clipping.pipeline);
         mq_ctx.apply_bindings(&bindings);
         mq_ctx.apply_uniforms(mq::UniformsSource::table(&uniforms));
         mq_ctx.draw(0, 6, 1);
         mq_ctx.end_render_pass();
 
-        // Retrieve output texture
-        let attachments = mq_ctx.render_pass_color_attachments(self.render_pass);
-        let raw_id = match unsafe { mq_ctx.texture_raw_id(attachments[0]) } {
-            mq::RawId::OpenGl(id) => id as u64,
+        self.clipping_output(mq_ctx)
+    }
+
+    /// The output of the last [`Renderer::render_clipping`]
+    pub fn clipping_output(&self, mq_ctx: &mut mq::Context) -> egui::TextureId {
+        let clipping = self.clipping.expect("clipping wasn't rendered");
+        let clipping_texture = mq_ctx.render_pass_color_attachments(clipping.pass)[0];
+        mq_to_egui_texture_id(mq_ctx, clipping_texture)
+    }
+
+    /// Shows the edits as they'd look once printed, through a lookup table
+    /// from [`super::proof::build_lut`]. The output of [`Renderer::render`]
+    /// is left untouched.
+    pub fn render_proof(
+        &mut self,
+        mq_ctx: &mut mq::Context,
+        uniforms: FragmentUniform,
+        lut_texture_id: mq::TextureId,
+        proof_uniforms: ProofUniform,
+    ) -> egui::TextureId {
+        let size = self.pass_size();
+        let proof = *self.proof.get_or_insert_with(|| ProofPasses {
+            pipeline: new_pipeline(
+                mq_ctx,
+                include_str!("shader_proof_frag.glsl"),
+                mq::ShaderMeta {
+                    images: vec!["tex".to_string(), "lut".to_string()],
+                    uniforms: mq::UniformBlockLayout {
+                        uniforms: ProofUniform::uniform_descs(),
+                    },
+                },
+            ),
+            source: new_render_pass(mq_ctx, size),
+            pass: new_render_pass(mq_ctx, size),
+        });
+
+        let mut uniforms = self.whole_image(uniforms);
+        uniforms.output = OutputUniform::new(&color::WORKING_SPACE);
+        self.render_into(
+            mq_ctx,
+            proof.source,
+            uniforms,
+            self.input,
+            self.dodge_burn_texture_id,
+        );
+
+        let bindings = mq::Bindings {
+            vertex_buffers: vec![self.vertex_buffer],
+            index_buffer: self.index_buffer,
+            images: vec![
+                mq_ctx.render_pass_color_attachments(proof.source)[0],
+                lut_texture_id,
+            ],
+        };
+
+        mq_ctx.begin_pass(
+            Some(proof.pass),
+            mq::PassAction::clear_color(0.0, 0.0, 0.0, 1.0),
+        );
+        mq_ctx.apply_pipeline(&proof.pipeline);
+        mq_ctx.apply_bindings(&bindings);
+        mq_ctx.apply_uniforms(mq::UniformsSource::table(&proof_uniforms));
+        mq_ctx.draw(0, 6, 1);
+        mq_ctx.end_render_pass();
+
+        self.proof_output(mq_ctx)
+    }
+
+    /// The output of the last [`Renderer::render_proof`]
+    pub fn proof_output(&self, mq_ctx: &mut mq::Context) -> egui::TextureId {
+        let proof = self.proof.expect("no proof was rendered");
+        let proof_texture = mq_ctx.render_pass_color_attachments(proof.pass)[0];
+        mq_to_egui_texture_id(mq_ctx, proof_texture)
+    }
+
+    /// Measures the colors around every point, both in the input and in the
+    /// output of the last render
+    pub fn sample(
+        &mut self,
+        mq_ctx: &mut mq::Context,
+        points: &[SamplePoint],
+    ) -> Vec<SampleReading> {
+        let mut uniforms = SampleUniform::default();
+        for (uniform, point) in uniforms.points.iter_mut().zip(points) {
+            *uniform = point.position;
+        }
+
+        // Either way the input comes back gamma encoded
+        let input_uniforms = SampleUniform {
+            split: !self.input.gamma as u32,
+            ..uniforms
+        };
+        let input = self.sample_texture(mq_ctx, self.input.high, self.input.low, input_uniforms);
+        let output_texture = self.output_texture(mq_ctx);
+        let output = self.sample_texture(mq_ctx, output_texture, output_texture, uniforms);
+
+        input
+            .into_iter()
+            .zip(output)
+            .take(points.len())
+            .map(|(input, output)| SampleReading {
+                input: input.map(|c| color::WORKING_SPACE.transfer.decode(c)),
+                output,
+            })
+            .collect()
+    }
+
+    pub fn dimensions(&self) -> (u32, u32) {
+        self.dimensions
+    }
+
+    /// Copies the result of the last render pass back from the GPU
+    pub fn read_output(&self, mq_ctx: &mut mq::Context) -> RgbaImage {
+        let (width, height) = self.dimensions;
+        let mut bytes = vec![0; width as usize * height as usize * 4];
+
+        let output_texture = self.output_texture(mq_ctx);
+        mq_ctx.texture_read_pixels(output_texture, &mut bytes);
+
+        RgbaImage::from_raw(width, height, bytes).expect("output texture size mismatch")
+    }
+
+    fn sample_texture(
+        &mut self,
+        mq_ctx: &mut mq::Context,
+        texture: mq::TextureId,
+        low_texture: mq::TextureId,
+        uniforms: SampleUniform,
+    ) -> Vec<[f32; 3]> {
+        let sample = *self.sample.get_or_insert_with(|| Pass {
+            pipeline: new_pipeline(
+                mq_ctx,
+                include_str!("shader_sample_frag.glsl"),
+                mq::ShaderMeta {
+                    images: vec!["tex".to_string(), "tex_low".to_string()],
+                    uniforms: mq::UniformBlockLayout {
+                        uniforms: SampleUniform::uniform_descs(),
+                    },
+                },
+            ),
+            pass: new_render_pass(mq_ctx, [MAX_SAMPLES, 1]),
+        });
+
+        let bindings = mq::Bindings {
+            vertex_buffers: vec![self.vertex_buffer],
+            index_buffer: self.index_buffer,
+            images: vec![texture, low_texture],
         };
 
-        egui::TextureId::User(raw_id)
+        mq_ctx.begin_pass(
+            Some(sample.pass),
+            mq::PassAction::clear_color(0.0, 0.0, 0.0, 1.0),
+        );
+        mq_ctx.apply_pipeline(&sample.pipeline);
+        mq_ctx.apply_bindings(&bindings);
+        mq_ctx.apply_uniforms(mq::UniformsSource::table(&uniforms));
+        mq_ctx.draw(0, 6, 1);
+        mq_ctx.end_render_pass();
+
+        let mut bytes = [0; MAX_SAMPLES * 4];
+        let samples = mq_ctx.render_pass_color_attachments(sample.pass)[0];
+        mq_ctx.texture_read_pixels(samples, &mut bytes);
+
+        bytes
+            .chunks_exact(4)
+            .map(|texel| [texel[0], texel[1], texel[2]].map(|c| c as f32 / 255.0))
+            .collect()
+    }
+
+    fn output_texture(&self, mq_ctx: &mut mq::Context) -> mq::TextureId {
+        mq_ctx.render_pass_color_attachments(self.render_pass)[0]
     }
 }
 
+/// A pipeline drawing into a pass of its own
+#[derive(Copy, Clone)]
+struct Pass {
+    pipeline: mq::Pipeline,
+    pass: mq::RenderPass,
+}
+
+/// The image in the working space, and the pass looking it up in the table
+/// of the profile
+#[derive(Copy, Clone)]
+struct ProofPasses {
+    pipeline: mq::Pipeline,
+    source: mq::RenderPass,
+    pass: mq::RenderPass,
+}
+
+/// A pipeline that draws the full screen rectangle with a fragment shader
+fn new_pipeline(mq_ctx: &mut mq::Context, fragment: &str, meta: mq::ShaderMeta) -> mq::Pipeline {
+    let shader = mq_ctx
+        .new_shader(
+            mq::ShaderSource::Glsl {
+                vertex: include_str!("shader_vert.glsl"),
+                fragment,
+            },
+            meta,
+        )
+        .unwrap();
+
+    mq_ctx.new_pipeline(
+        &[mq::BufferLayout {
+            ..Default::default()
+        }],
+        &[
+            mq::VertexAttribute::new("position", mq::VertexFormat::Float2),
+            mq::VertexAttribute::new("tex_coords", mq::VertexFormat::Float2),
+        ],
+        shader,
+        mq::PipelineParams {
+            depth_write: true,
+            depth_test: mq::Comparison::LessOrEqual,
+            ..Default::default()
+        },
+    )
+}
+
+/// A render pass drawing into a texture of the size of the image
+fn new_render_pass(mq_ctx: &mut mq::Context, dimensions: [usize; 2]) -> mq::RenderPass {
+    new_pass(mq_ctx, dimensions, mq::TextureFormat::RGBA8)
+}
+
+fn new_pass(
+    mq_ctx: &mut mq::Context,
+    dimensions: [usize; 2],
+    format: mq::TextureFormat,
+) -> mq::RenderPass {
+    let output_texture = mq_ctx.new_render_texture(mq::TextureParams {
+        width: dimensions[0] as u32,
+        height: dimensions[1] as u32,
+        format,
+        ..Default::default()
+    });
+
+    mq_ctx.new_render_pass(output_texture, None)
+}
+
 fn get_vertex_buffer(mq_ctx: &mut mq::Context) -> mq::BufferId {
     // Draw a rectangle
     #[rustfmt::skip]
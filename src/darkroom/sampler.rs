@@ -0,0 +1,112 @@
+use serde::{Deserialize, Serialize};
+
+use crate::darkroom::color::WORKING_SPACE;
+
+/// How many points can be sampled at once, the shader has room for this many
+pub const MAX_SAMPLES: usize = 8;
+
+/// A point whose color is measured, relative to the image from 0 to 1 with
+/// the origin on the top left corner
+#[derive(Debug, Copy, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SamplePoint {
+    pub position: [f32; 2],
+}
+
+/// The color under a sample point, before and after developing the image.
+/// Channels go from 0 to 1, the input in linear light in the working space
+/// and the output as shown.
+#[derive(Debug, Copy, Clone, Default, PartialEq)]
+pub struct SampleReading {
+    pub input: [f32; 3],
+    pub output: [f32; 3],
+}
+
+/// Hue in degrees, saturation and lightness from 0 to 1
+pub fn rgb_to_hsl([r, g, b]: [f32; 3]) -> [f32; 3] {
+    let max = r.max(g).max(b);
+    let min = r.min(g).min(b);
+    let lightness = (max + min) / 2.0;
+    let delta = max - min;
+
+    if delta <= f32::EPSILON {
+        return [0.0, 0.0, lightness];
+    }
+
+    let saturation = delta / (1.0 - (2.0 * lightness - 1.0).abs());
+    let hue = if max == r {
+        ((g - b) / delta).rem_euclid(6.0)
+    } else if max == g {
+        (b - r) / delta + 2.0
+    } else {
+        (r - g) / delta + 4.0
+    };
+
+    [hue * 60.0, saturation, lightness]
+}
+
+/// CIE L*a*b* of an sRGB color, with a D65 white point
+pub fn rgb_to_lab(rgb: [f32; 3]) -> [f32; 3] {
+    let [r, g, b] = rgb.map(srgb_to_linear);
+
+    // from: http://www.brucelindbloom.com/index.html?Eqn_RGB_XYZ_Matrix.html
+    let x = 0.4124564 * r + 0.3575761 * g + 0.1804375 * b;
+    let y = 0.2126729 * r + 0.7151522 * g + 0.0721750 * b;
+    let z = 0.0193339 * r + 0.119192 * g + 0.9503041 * b;
+
+    xyz_to_lab([x, y, z], [0.95047, 1.0, 1.08883])
+}
+
+/// CIE L*a*b* of an XYZ color, relative to the XYZ of the white point
+pub fn xyz_to_lab([x, y, z]: [f32; 3], [xw, yw, zw]: [f32; 3]) -> [f32; 3] {
+    let fx = lab_f(x / xw);
+    let fy = lab_f(y / yw);
+    let fz = lab_f(z / zw);
+
+    [116.0 * fy - 16.0, 500.0 * (fx - fy), 200.0 * (fy - fz)]
+}
+
+fn srgb_to_linear(c: f32) -> f32 {
+    if c <= 0.04045 {
+        c / 12.92
+    } else {
+        ((c + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+fn lab_f(t: f32) -> f32 {
+    const EPSILON: f32 = 216.0 / 24389.0;
+    const KAPPA: f32 = 24389.0 / 27.0;
+
+    if t > EPSILON {
+        t.cbrt()
+    } else {
+        (KAPPA * t + 16.0) / 116.0
+    }
+}
+
+/// A linear input color with the curve of the working space, as it's shown
+/// and as the film base is picked
+pub fn encode(rgb: [f32; 3]) -> [f32; 3] {
+    rgb.map(|c| WORKING_SPACE.transfer.encode(c))
+}
+
+/// Turns a linear negative into a positive like the shader does, taking the
+/// color of the film base out first
+pub fn invert_negative(rgb: [f32; 3], film_base: [f32; 3]) -> [f32; 3] {
+    let transfer = WORKING_SPACE.transfer;
+    let mut out = rgb;
+    for (c, base) in out.iter_mut().zip(film_base) {
+        let transmission = (*c / transfer.decode(base.max(0.001))).clamp(0.0, 1.0);
+        *c = transfer.decode(1.0 - transfer.encode(transmission));
+    }
+
+    out
+}
+
+/// The gains that turn a linear color into a neutral grey of the same green,
+/// like the shader applies them
+pub fn neutral_gains(rgb: [f32; 3]) -> [f32; 3] {
+    let [r, g, b] = rgb.map(|c| c.max(1.0 / 65535.0));
+
+    [(g / r).clamp(0.25, 4.0), 1.0, (g / b).clamp(0.25, 4.0)]
+}
@@ -0,0 +1,40 @@
+#version 330 core
+
+in vec2 v_tex_coords;
+out vec4 color;
+
+// The output of the main pass
+uniform sampler2D tex;
+
+uniform float highlight_threshold;
+uniform float shadow_threshold;
+uniform int per_channel;
+
+// from: https://www.w3.org/WAI/GL/wiki/Relative_luminance
+const vec3 luminance_factors = vec3(0.2126, 0.7152, 0.0722);
+
+const vec4 highlight_color = vec4(1.0, 0.0, 0.0, 1.0);
+const vec4 shadow_color = vec4(0.0, 0.0, 1.0, 1.0);
+
+void main() {
+    vec4 p = texture(tex, v_tex_coords);
+
+    bool blown;
+    bool crushed;
+    if (per_channel != 0) {
+        blown = any(greaterThanEqual(p.rgb, vec3(highlight_threshold)));
+        crushed = any(lessThanEqual(p.rgb, vec3(shadow_threshold)));
+    } else {
+        float l = dot(p.rgb, luminance_factors);
+        blown = l >= highlight_threshold;
+        crushed = l <= shadow_threshold;
+    }
+
+    if (blown) {
+        color = highlight_color;
+    } else if (crushed) {
+        color = shadow_color;
+    } else {
+        color = p;
+    }
+}
@@ -3,33 +3,508 @@
 in vec2 v_tex_coords;
 out vec4 color;
 
+// The working image in linear light, split in high and low bytes, see
+// `InputTexture`. Or what the stage before left, when this one doesn't
+// start from the input.
 uniform sampler2D tex;
+uniform sampler2D tex_low;
+// Exposure changes painted with the dodge and burn brush, see `DodgeBurnMask`
+uniform sampler2D dodge_burn;
 
 uniform float contrast;
+uniform float saturation;
+// In stops
+uniform float exposure;
+uniform float highlights;
+uniform float shadows;
+// Camera correction, in linear light
+uniform mat4 input_matrix;
+uniform int invert;
+uniform float temperature;
+// Color of the unexposed film as picked, with a 2.2 gamma. It's divided out
+// before inverting.
+uniform vec3 film_base;
+// Gains for each channel, applied in linear light
+uniform vec3 white_balance;
+
+// One entry per hue band: red, orange, yellow, green, aqua, blue, purple, magenta
+uniform float hsl_hue[8];
+uniform float hsl_saturation[8];
+uniform float hsl_luminance[8];
+
+// Color grading tints, as (hue in degrees, strength, luminance)
+uniform vec3 grading_shadows;
+uniform vec3 grading_midtones;
+uniform vec3 grading_highlights;
+uniform vec3 grading_global;
+uniform float grading_balance;
+
+// Black and white conversion
+uniform int monochrome;
+uniform vec3 mixer;
+// 0: none, 1: selenium, 2: sepia, 3: split tone
+uniform int toning;
+uniform float toning_strength;
+uniform vec2 toning_split_hues;
+
+// Variable contrast paper, exposures are in stops
+uniform int paper;
+uniform float paper_exposure;
+// From -1 (grade 00) to 5
+uniform float paper_grade;
+uniform int paper_split_grade;
+uniform float paper_soft_exposure;
+uniform float paper_hard_exposure;
+
+// Local adjustments, see `MasksUniform`
+// 0: unused, 1: linear, 2: radial
+uniform int mask_kind[8];
+uniform int mask_invert[8];
+uniform vec4 mask_geometry[8];
+uniform float mask_feather[8];
+// Exposure in stops, contrast, temperature and saturation
+uniform vec4 mask_adjustments[8];
+
+// Lens corrections, see `LensUniform`
+uniform float lens_k1;
+uniform float lens_k2;
+uniform float lens_vignetting;
+uniform float lens_ca_red;
+uniform float lens_ca_blue;
+
+// Working space to output space, in linear light
+uniform mat4 output_matrix;
+// 0 for the sRGB curve, 1 for a plain gamma
+uniform int output_transfer;
+uniform float output_gamma;
+
+// Where the render is in the whole image, see `TileUniform`
+uniform vec2 image_size;
+uniform vec4 tile_region;
+uniform vec4 tile_source;
+
+// Whether the input is a single gamma encoded texture, see `InputUniform`
+uniform int input_gamma;
+
+// The develop stages to run, see DevelopStage. Stages after the input one
+// read what the one before left in tex, in linear light, and only the color
+// stage leaves the working space.
+uniform int first_stage;
+uniform int last_stage;
+
+// The range of the dodge and burn mask, in stops
+const float dodge_burn_stops = 2.0;
+
+// Middle grey in linear light. Contrast pivots around it, and it's where the
+// highlight and shadow recovery curves meet.
+const float middle_grey = 0.18;
 
 const float PI = 3.141592653589793238462643383279502884197169399375105820974944;
-const float max_value = 255.0;
 
-float adjustContrastPixel(float c, float percent) {
-    c = c * max_value;
-    float d = ((c / max_value - 0.5) * percent + 0.5) * max_value;
-    float e = clamp(d, 0.0, max_value);
-    return e / max_value;
+// Centers of the HSL bands, in degrees
+const float hsl_centers[8] = float[8](0.0, 30.0, 60.0, 120.0, 180.0, 240.0, 270.0, 300.0);
+
+// How far the hue slider can push a band, in degrees
+const float hsl_max_hue_shift = 30.0;
+// How far the luminance slider can push a band, in stops
+const float hsl_max_stops = 2.0;
+
+// Luminance of the Rec. 2020 primaries of the working space
+// from: https://www.itu.int/rec/R-REC-BT.2020
+const vec3 luminance_factors = vec3(0.2627, 0.6780, 0.0593);
+
+// Colors of the toned silver, as a multiplier of the grey value
+const vec3 selenium_color = vec3(0.92, 0.84, 0.96);
+const vec3 sepia_color = vec3(1.12, 0.94, 0.70);
+
+// Log exposure range of the paper for each grade, from 00 to 5. Soft grades
+// need a wider range of exposures to go from white to black.
+const float paper_ranges[7] = float[7](1.6, 1.4, 1.2, 1.0, 0.85, 0.7, 0.55);
+// Density range and contrast of the negative the positive is printed from,
+// and maximum density of the paper
+const float negative_density = 1.2;
+const float negative_gamma = 0.6;
+const float paper_dmax = 2.0;
+// log10(2), to turn stops into log exposure
+const float log_stop = 0.30103;
+
+// Luminance on a scale where 0.5 looks halfway between black and white, to
+// tell the tonal ranges of the image apart
+float lightness(vec3 p) {
+    return pow(max(dot(p, luminance_factors), 0.0), 1.0 / 2.2);
+}
+
+// Reads the input texture at a position in the whole image, in linear light
+vec4 sampleInput(vec2 uv) {
+    vec2 source = (uv - tile_source.xy) / tile_source.zw;
+    vec4 high = texture(tex, source);
+    if (input_gamma != 0) {
+        return vec4(pow(high.rgb, vec3(2.2)), high.a);
+    }
+
+    return (high * 256.0 + texture(tex_low, source)) / 257.0;
+}
+
+// Reads the image through the lens corrections. Each channel is read from
+// where the lens put it, and the light lost to vignetting is given back.
+vec4 sampleLens(vec2 uv) {
+    float half_short = min(image_size.x, image_size.y) / 2.0;
+    vec2 to_uv = half_short / image_size;
+
+    vec2 d = (uv - 0.5) / to_uv;
+    float r2 = dot(d, d);
+    vec2 source = d * (1.0 + lens_k1 * r2 + lens_k2 * r2 * r2);
+
+    vec4 p = sampleInput(0.5 + source * to_uv);
+    p.r = sampleInput(0.5 + source * (1.0 + lens_ca_red) * to_uv).r;
+    p.b = sampleInput(0.5 + source * (1.0 + lens_ca_blue) * to_uv).b;
+
+    vec2 corner = 0.5 / to_uv;
+    float falloff = 1.0 + lens_vignetting * r2 / dot(corner, corner);
+    p.rgb /= max(falloff, 0.05);
+
+    return p;
+}
+
+vec3 correctInput(vec3 p) {
+    return clamp(mat3(input_matrix) * p, 0.0, 1.0);
+}
+
+// Turns the negative into a positive, taking the color of the film base out
+// first so it ends up as pure black. The light the film lets through is
+// turned around on a perceptual scale, where the density of the film is
+// spread about evenly.
+vec3 invertNegative(vec3 p) {
+    vec3 base = pow(max(film_base, vec3(0.001)), vec3(2.2));
+    vec3 transmission = clamp(p / base, 0.0, 1.0);
+
+    return pow(1.0 - pow(transmission, vec3(1.0 / 2.2)), vec3(2.2));
+}
+
+// Leaves the working space for the output one, clipping whatever it can't hold
+vec3 toOutput(vec3 p) {
+    vec3 linear = clamp(mat3(output_matrix) * p, 0.0, 1.0);
+
+    if (output_transfer == 1) {
+        return pow(linear, vec3(1.0 / output_gamma));
+    }
+
+    vec3 low = linear * 12.92;
+    vec3 high = 1.055 * pow(linear, vec3(1.0 / 2.4)) - 0.055;
+    return mix(low, high, step(vec3(0.0031308), linear));
+}
+
+vec3 balanceWhite(vec3 p) {
+    return p * white_balance;
 }
 
+// Changes the exposure, like opening or closing the lens. The result isn't
+// clamped, so the recovery curves can bring it back.
+vec3 expose(vec3 p, float stops) {
+    return p * exp2(stops);
+}
+
+float highlightCurve(float x, float amount) {
+    if (x <= middle_grey) {
+        return x;
+    }
+
+    float over = x - middle_grey;
+    if (amount < 0.0) {
+        // A soft shoulder that never reaches white, no matter how bright
+        // the pixel got
+        float compressed = over / (1.0 + over / (1.0 - middle_grey));
+        return middle_grey + mix(over, compressed, -amount);
+    }
+
+    // Brightens the upper tones while keeping white in place
+    return x + amount * over * max(1.0 - x, 0.0);
+}
+
+float shadowCurve(float x, float amount) {
+    if (x >= middle_grey) {
+        return x;
+    }
+
+    // Keeps black at black, so the shadows don't wash out
+    return pow(x / middle_grey, exp2(-amount)) * middle_grey;
+}
+
+vec3 recoverTones(vec3 p) {
+    float l = dot(p, luminance_factors);
+    if (l <= 0.0) {
+        return vec3(0.0);
+    }
+
+    float target = shadowCurve(highlightCurve(l, highlights), shadows);
+    return clamp(p * target / l, 0.0, 1.0);
+}
+
+// Spreads every channel away from middle grey, or brings it closer, by the
+// same number of stops on both sides
 vec3 adjustContrast(vec3 p, float contrast) {
-    float percent = pow((100.0 + contrast) / 100.0, 2);
-    float new_r = adjustContrastPixel(p.r, percent);
-    float new_g = adjustContrastPixel(p.g, percent);
-    float new_b = adjustContrastPixel(p.b, percent);
+    float slope = pow((100.0 + contrast) / 100.0, 2.0);
+    return clamp(middle_grey * pow(max(p, 0.0) / middle_grey, vec3(slope)), 0.0, 1.0);
+}
+
+vec3 adjustSaturation(vec3 p, float saturation) {
+    float l = dot(p, luminance_factors);
+    return clamp(mix(vec3(l), p, saturation), 0.0, 1.0);
+}
+
+vec3 rgbToHsl(vec3 c) {
+    float max_c = max(max(c.r, c.g), c.b);
+    float min_c = min(min(c.r, c.g), c.b);
+    float l = (max_c + min_c) / 2.0;
+    float d = max_c - min_c;
+
+    if (d == 0.0) {
+        return vec3(0.0, 0.0, l);
+    }
 
-    return vec3(new_r, new_g, new_b);
+    float s = l > 0.5 ? d / (2.0 - max_c - min_c) : d / (max_c + min_c);
+    float h;
+    if (max_c == c.r) {
+        h = (c.g - c.b) / d + (c.g < c.b ? 6.0 : 0.0);
+    } else if (max_c == c.g) {
+        h = (c.b - c.r) / d + 2.0;
+    } else {
+        h = (c.r - c.g) / d + 4.0;
+    }
+
+    return vec3(h * 60.0, s, l);
+}
+
+float hueToRgb(float p, float q, float t) {
+    t = fract(t);
+    if (t < 1.0 / 6.0) return p + (q - p) * 6.0 * t;
+    if (t < 1.0 / 2.0) return q;
+    if (t < 2.0 / 3.0) return p + (q - p) * (2.0 / 3.0 - t) * 6.0;
+    return p;
+}
+
+vec3 hslToRgb(vec3 hsl) {
+    if (hsl.y == 0.0) {
+        return vec3(hsl.z);
+    }
+
+    float q = hsl.z < 0.5 ? hsl.z * (1.0 + hsl.y) : hsl.z + hsl.y - hsl.z * hsl.y;
+    float p = 2.0 * hsl.z - q;
+    float h = hsl.x / 360.0;
+
+    return vec3(
+        hueToRgb(p, q, h + 1.0 / 3.0),
+        hueToRgb(p, q, h),
+        hueToRgb(p, q, h - 1.0 / 3.0)
+    );
+}
+
+// Signed distance in degrees between two hues, in the range [-180, 180)
+float hueDistance(float a, float b) {
+    return mod(a - b + 180.0, 360.0) - 180.0;
+}
+
+// How much a hue belongs to a band. The weights of all bands add up to 1,
+// falling off smoothly towards the center of the neighbouring bands.
+float hslBandWeight(float h, int band) {
+    float d = hueDistance(h, hsl_centers[band]);
+    int neighbour = d >= 0.0 ? (band + 1) % 8 : (band + 7) % 8;
+    float width = abs(hueDistance(hsl_centers[neighbour], hsl_centers[band]));
+
+    return 1.0 - smoothstep(0.0, 1.0, abs(d) / width);
+}
+
+vec3 adjustHsl(vec3 p) {
+    vec3 hsl = rgbToHsl(p);
+
+    float hue_shift = 0.0;
+    float sat_shift = 0.0;
+    float lum_shift = 0.0;
+    for (int i = 0; i < 8; i++) {
+        float w = hslBandWeight(hsl.x, i);
+        hue_shift += w * hsl_hue[i];
+        sat_shift += w * hsl_saturation[i];
+        lum_shift += w * hsl_luminance[i];
+    }
+
+    hsl.x = mod(hsl.x + hue_shift * hsl_max_hue_shift, 360.0);
+    hsl.y = clamp(hsl.y * (1.0 + sat_shift), 0.0, 1.0);
+    // Greys have no hue, so only touch the luminance of colored pixels
+    hsl.z = clamp(hsl.z * exp2(lum_shift * hsl.y * hsl_max_stops), 0.0, 1.0);
+
+    return hslToRgb(hsl);
+}
+
+// Pushes a pixel towards the tint of a grading wheel like a colored filter
+// would, keeping its luminance unless the wheel asks otherwise. The wheel's
+// luminance is in stops.
+vec3 applyTint(vec3 p, vec3 wheel, float weight) {
+    vec3 tint = hslToRgb(vec3(wheel.x, 1.0, 0.5));
+    tint -= dot(tint, luminance_factors);
+
+    return p * exp2(weight * (tint * wheel.y * 2.0 + wheel.z));
+}
+
+vec3 adjustGrading(vec3 p) {
+    float l = lightness(p);
+
+    // A positive balance favors the highlights by lowering the pivot
+    float pivot = clamp(0.5 - 0.25 * grading_balance, 0.25, 0.75);
+    float shadows = 1.0 - smoothstep(0.0, pivot, l);
+    float highlights = smoothstep(pivot, 1.0, l);
+    float midtones = 1.0 - shadows - highlights;
+
+    p = applyTint(p, grading_shadows, shadows);
+    p = applyTint(p, grading_midtones, midtones);
+    p = applyTint(p, grading_highlights, highlights);
+    p = applyTint(p, grading_global, 1.0);
+
+    return clamp(p, 0.0, 1.0);
+}
+
+vec3 toMonochrome(vec3 p) {
+    // Normalize so that changing the filter doesn't change the exposure
+    float total = max(mixer.r + mixer.g + mixer.b, 0.001);
+    return vec3(clamp(dot(p, mixer) / total, 0.0, 1.0));
+}
+
+vec3 applyToning(vec3 p) {
+    float l = lightness(p);
+    vec3 toned = p;
+
+    if (toning == 1) {
+        // Selenium mostly affects the denser parts of the print
+        toned = mix(p, p * selenium_color, 1.0 - l);
+    } else if (toning == 2) {
+        toned = p * sepia_color;
+    } else if (toning == 3) {
+        toned = applyTint(p, vec3(toning_split_hues.x, 1.0, 0.0), 1.0 - smoothstep(0.0, 0.5, l));
+        toned = applyTint(toned, vec3(toning_split_hues.y, 1.0, 0.0), smoothstep(0.5, 1.0, l));
+    }
+
+    return clamp(mix(p, toned, toning_strength), 0.0, 1.0);
+}
+
+// The characteristic curve of the paper: how dense the print gets for a given
+// log exposure
+float paperDensity(float log_exposure, float grade) {
+    float g = clamp(grade + 1.0, 0.0, 6.0);
+    int i = int(floor(g));
+    float range = mix(paper_ranges[i], paper_ranges[min(i + 1, 6)], fract(g));
+
+    // 10% to 90% of the density over the exposure range of the grade
+    float slope = 4.394 / range;
+    // Middle grey of the scan lands on the middle of the curve at exposure 0
+    float log_exposure_mid = -0.5 * negative_density;
+
+    return paper_dmax / (1.0 + exp(-slope * (log_exposure - log_exposure_mid)));
+}
+
+vec3 printOnPaper(vec3 p) {
+    // Bright parts of the positive are dense on the negative, so they let
+    // less light reach the paper. Density follows the log of the light, and
+    // middle grey lands halfway through the density range.
+    float log_stops = log(max(p.r, 0.0001) / middle_grey) / log(10.0);
+    float log_transmission = clamp(
+        -negative_gamma * log_stops - 0.5 * negative_density,
+        -negative_density,
+        0.0
+    );
+
+    float density;
+    if (paper_split_grade != 0) {
+        float soft = paperDensity(log_transmission + paper_soft_exposure * log_stop, -1.0);
+        float hard = paperDensity(log_transmission + paper_hard_exposure * log_stop, 5.0);
+        // Both exposures add up on the same sheet, which can't go past dmax
+        density = paper_dmax * (1.0 - (1.0 - soft / paper_dmax) * (1.0 - hard / paper_dmax));
+    } else {
+        density = paperDensity(log_transmission + paper_exposure * log_stop, paper_grade);
+    }
+
+    return vec3(pow(10.0, -density));
+}
+
+// How much a mask covers a point of the image, from 0 to 1
+float maskWeight(int i, vec2 uv) {
+    vec4 g = mask_geometry[i];
+    float w = 0.0;
+
+    if (mask_kind[i] == 1) {
+        vec2 d = g.zw - g.xy;
+        float t = dot(uv - g.xy, d) / max(dot(d, d), 0.00001);
+        w = 1.0 - smoothstep(0.0, 1.0, t);
+    } else if (mask_kind[i] == 2) {
+        float r = length((uv - g.xy) / max(g.zw, vec2(0.00001)));
+        w = 1.0 - smoothstep(1.0 - mask_feather[i], 1.0, r);
+    }
+
+    return mask_invert[i] != 0 ? 1.0 - w : w;
+}
+
+vec3 applyMasks(vec3 p, vec2 uv) {
+    for (int i = 0; i < 8; i++) {
+        if (mask_kind[i] == 0) {
+            continue;
+        }
+
+        float w = maskWeight(i, uv);
+        vec4 a = mask_adjustments[i] * w;
+
+        p = adjustContrast(clamp(expose(p, a.x), 0.0, 1.0), a.y);
+        // Warmer or cooler by up to half a stop on red and blue
+        p *= exp2(vec3(a.z, 0.0, -a.z) * 0.5);
+        p = adjustSaturation(p, 1.0 + a.w);
+    }
+
+    return clamp(p, 0.0, 1.0);
+}
+
+vec3 dodgeAndBurn(vec3 p, vec2 uv) {
+    float stops = (texture(dodge_burn, uv).r - 0.5) * 2.0 * dodge_burn_stops;
+    return clamp(expose(p, stops), 0.0, 1.0);
 }
 
 void main() {
-    vec4 p = texture2D(tex, v_tex_coords);
+    // Position in the whole image, tiles only cover part of it
+    vec2 uv = tile_region.xy + v_tex_coords * tile_region.zw;
+
+    vec4 p;
+    if (first_stage == 0) {
+        p = sampleLens(uv);
+        p.rgb = correctInput(p.rgb);
+
+        if (invert != 0) {
+            p.rgb = invertNegative(p.rgb);
+        }
+        p.rgb = balanceWhite(p.rgb);
+    } else {
+        p = texture(tex, v_tex_coords);
+    }
+
+    if (first_stage <= 1 && last_stage >= 1) {
+        p.rgb = expose(p.rgb, exposure);
+        p.rgb = recoverTones(p.rgb);
+        p.rgb = adjustContrast(p.rgb, contrast);
+        p.rgb = applyMasks(p.rgb, uv);
+        p.rgb = dodgeAndBurn(p.rgb, uv);
+    }
+
+    if (last_stage >= 2) {
+        // Black and white negatives have no color to work with
+        if (monochrome == 0) {
+            p.rgb = adjustSaturation(p.rgb, saturation);
+            p.rgb = adjustHsl(p.rgb);
+            p.rgb = adjustGrading(p.rgb);
+        } else {
+            p.rgb = toMonochrome(p.rgb);
+            if (paper != 0) {
+                p.rgb = printOnPaper(p.rgb);
+            }
+            p.rgb = applyToning(p.rgb);
+        }
 
-    p.rgb = adjustContrast(p.rgb, contrast);
+        p.rgb = toOutput(p.rgb);
+    }
 
     color = p;
 }
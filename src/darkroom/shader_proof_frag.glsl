@@ -0,0 +1,41 @@
+#version 330 core
+
+in vec2 v_tex_coords;
+out vec4 color;
+
+// The image rendered in the working space
+uniform sampler2D tex;
+// The working space to display colors through the proofed profile, see
+// `proof::build_lut`. Alpha is 0 for colors the profile can't hold.
+uniform sampler2D lut;
+
+uniform int show_gamut;
+
+const float lut_size = 33.0;
+
+const vec4 gamut_color = vec4(0.5, 0.5, 0.5, 1.0);
+
+// Blue picks the slice, the texture filtering blends red and green inside
+// of it, and the two nearest slices are blended by hand
+vec4 lookup(vec3 c) {
+    vec3 s = clamp(c, 0.0, 1.0) * (lut_size - 1.0);
+    float b0 = floor(s.b);
+    float b1 = min(b0 + 1.0, lut_size - 1.0);
+
+    float y = (s.g + 0.5) / lut_size;
+    vec2 uv0 = vec2((s.r + b0 * lut_size + 0.5) / (lut_size * lut_size), y);
+    vec2 uv1 = vec2((s.r + b1 * lut_size + 0.5) / (lut_size * lut_size), y);
+
+    return mix(texture(lut, uv0), texture(lut, uv1), s.b - b0);
+}
+
+void main() {
+    vec4 p = texture(tex, v_tex_coords);
+    vec4 proofed = lookup(p.rgb);
+
+    if (show_gamut != 0 && proofed.a < 0.5) {
+        color = gamut_color;
+    } else {
+        color = vec4(proofed.rgb, 1.0);
+    }
+}
@@ -0,0 +1,48 @@
+#version 330 core
+
+in vec2 v_tex_coords;
+out vec4 color;
+
+uniform sampler2D tex;
+// The low bytes of the input, see `InputTexture`
+uniform sampler2D tex_low;
+
+// One texel of the output per point, see `SampleUniform`
+uniform vec2 points[8];
+// Whether the texture is the linear input split in two
+uniform int split;
+
+// Half the side of the square averaged around each point, in texels, so a
+// single noisy pixel doesn't throw the reading off
+const int radius = 2;
+
+vec4 fetch(ivec2 texel) {
+    vec4 high = texelFetch(tex, texel, 0);
+    if (split == 0) {
+        return high;
+    }
+
+    return (high * 256.0 + texelFetch(tex_low, texel, 0)) / 257.0;
+}
+
+void main() {
+    int i = clamp(int(v_tex_coords.x * 8.0), 0, 7);
+    ivec2 size = textureSize(tex, 0);
+    ivec2 center = ivec2(points[i] * vec2(size));
+
+    vec4 sum = vec4(0.0);
+    for (int y = -radius; y <= radius; y++) {
+        for (int x = -radius; x <= radius; x++) {
+            ivec2 texel = clamp(center + ivec2(x, y), ivec2(0), size - 1);
+            sum += fetch(texel);
+        }
+    }
+
+    float count = float((2 * radius + 1) * (2 * radius + 1));
+    color = sum / count;
+
+    // Read back in 8 bits, which only keeps the shadows with a gamma
+    if (split != 0) {
+        color.rgb = pow(color.rgb, vec3(1.0 / 2.2));
+    }
+}
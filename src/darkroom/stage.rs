@@ -0,0 +1,190 @@
+//! Tracking of what has to be rendered again. Drawing every pass on every
+//! frame keeps the GPU busy for nothing, so each stage of the pipeline
+//! remembers what it was last rendered with and only runs again when that
+//! changes, or when a stage it reads from ran.
+
+use miniquad as mq;
+
+use crate::darkroom::{
+    sampler::SamplePoint,
+    uniform::{
+        ClippingUniform, FragmentUniform, GradingUniform, HslUniform, LensUniform, MasksUniform,
+        MonochromeUniform, OutputUniform, PaperUniform, ProofUniform,
+    },
+};
+
+/// The inputs a stage was last rendered with
+#[derive(Debug, Clone)]
+pub struct Stage<T> {
+    inputs: Option<T>,
+}
+
+impl<T> Default for Stage<T> {
+    fn default() -> Self {
+        Self { inputs: None }
+    }
+}
+
+impl<T: PartialEq> Stage<T> {
+    /// Whether the stage has to run, because its inputs changed or because
+    /// a stage before it ran. The inputs are remembered for the next time.
+    pub fn dirty(&mut self, inputs: T, upstream: bool) -> bool {
+        let dirty = upstream || self.inputs.as_ref() != Some(&inputs);
+        self.inputs = Some(inputs);
+
+        dirty
+    }
+
+    /// Forgets the last run, for when its output was drawn over or isn't
+    /// shown anymore
+    pub fn invalidate(&mut self) {
+        self.inputs = None;
+    }
+}
+
+/// The parts of the develop shader, in the order they run. On screen each
+/// one renders into a texture of its own, so changing a module only runs
+/// its stage and the ones after it, see [`Stages::develop`].
+#[repr(u32)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub enum DevelopStage {
+    /// Lens corrections, the input profile, inverting and white balance
+    Input = 0,
+    /// Exposure, tone recovery, contrast, local adjustments and dodge & burn
+    Tone = 1,
+    /// Color or black and white, and the conversion to the output space
+    Color = 2,
+}
+
+impl DevelopStage {
+    pub const ALL: [DevelopStage; 3] =
+        [DevelopStage::Input, DevelopStage::Tone, DevelopStage::Color];
+}
+
+/// The edits each develop stage reads
+type InputEdits = (LensUniform, [[f32; 4]; 4], u32, [f32; 3], [f32; 3]);
+type ToneEdits = (f32, f32, f32, f32, MasksUniform);
+type ColorEdits = (
+    f32,
+    HslUniform,
+    GradingUniform,
+    MonochromeUniform,
+    PaperUniform,
+    OutputUniform,
+);
+
+/// The stages drawing the image on screen. The develop stages follow each
+/// other, everything else reads from the last one.
+#[derive(Debug, Default)]
+pub struct Stages {
+    /// The edits of the input stage, and whether they were rendered from the
+    /// proxy
+    pub input: Stage<(InputEdits, bool)>,
+    pub tone: Stage<ToneEdits>,
+    pub color: Stage<ColorEdits>,
+    pub proof: Stage<(mq::TextureId, ProofUniform)>,
+    pub clipping: Stage<ClippingUniform>,
+    pub samples: Stage<Vec<SamplePoint>>,
+    /// The snapshot being compared against, which doesn't depend on the
+    /// current edits
+    pub snapshot: Stage<(usize, FragmentUniform)>,
+}
+
+impl Stages {
+    /// The first develop stage that has to run again for the edits, if any.
+    /// The dodge & burn mask is read by the tone stage.
+    pub fn develop(
+        &mut self,
+        uniform: &FragmentUniform,
+        from_proxy: bool,
+        mask_changed: bool,
+    ) -> Option<DevelopStage> {
+        let input = (
+            uniform.lens,
+            uniform.input_matrix,
+            uniform.invert,
+            uniform.film_base,
+            uniform.white_balance,
+        );
+        let tone = (
+            uniform.exposure,
+            uniform.highlights,
+            uniform.shadows,
+            uniform.contrast,
+            uniform.masks,
+        );
+        let color = (
+            uniform.saturation,
+            uniform.hsl,
+            uniform.grading,
+            uniform.monochrome,
+            uniform.paper,
+            uniform.output,
+        );
+
+        // Every stage remembers its edits, even when an earlier one runs
+        let input = self.input.dirty((input, from_proxy), false);
+        let tone = self.tone.dirty(tone, input || mask_changed);
+        let color = self.color.dirty(color, tone);
+
+        DevelopStage::ALL
+            .into_iter()
+            .zip([input, tone, color])
+            .find_map(|(stage, dirty)| dirty.then_some(stage))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn develop_runs_from_the_stage_that_changed() {
+        let mut stages = Stages::default();
+        let mut uniform = FragmentUniform::default();
+        assert_eq!(
+            stages.develop(&uniform, false, false),
+            Some(DevelopStage::Input)
+        );
+        assert_eq!(stages.develop(&uniform, false, false), None);
+
+        uniform.saturation = 1.5;
+        assert_eq!(
+            stages.develop(&uniform, false, false),
+            Some(DevelopStage::Color)
+        );
+        uniform.exposure = 0.5;
+        assert_eq!(
+            stages.develop(&uniform, false, false),
+            Some(DevelopStage::Tone)
+        );
+        assert_eq!(
+            stages.develop(&uniform, false, true),
+            Some(DevelopStage::Tone)
+        );
+        uniform.white_balance = [1.1, 1.0, 0.9];
+        assert_eq!(
+            stages.develop(&uniform, false, false),
+            Some(DevelopStage::Input)
+        );
+        // The proxy has intermediates of its own
+        assert_eq!(
+            stages.develop(&uniform, true, false),
+            Some(DevelopStage::Input)
+        );
+        assert_eq!(stages.develop(&uniform, true, false), None);
+    }
+
+    #[test]
+    fn invalidated_stages_run_again() {
+        let mut stages = Stages::default();
+        let uniform = FragmentUniform::default();
+        stages.develop(&uniform, false, false);
+
+        stages.color.invalidate();
+        assert_eq!(
+            stages.develop(&uniform, false, false),
+            Some(DevelopStage::Color)
+        );
+    }
+}
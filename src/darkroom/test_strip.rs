@@ -0,0 +1,272 @@
+use std::path::{Path, PathBuf};
+
+use image::{Rgba, RgbaImage};
+use miniquad as mq;
+
+use crate::darkroom::{renderer::Renderer, uniform::FragmentUniform};
+
+/// The parameters a test strip can step through
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum StripParameter {
+    Exposure,
+    Contrast,
+    PaperExposure,
+    PaperGrade,
+}
+
+impl StripParameter {
+    pub const ALL: [StripParameter; 4] = [
+        StripParameter::Exposure,
+        StripParameter::Contrast,
+        StripParameter::PaperExposure,
+        StripParameter::PaperGrade,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            StripParameter::Exposure => "Exposure",
+            StripParameter::Contrast => "Contrast",
+            StripParameter::PaperExposure => "Paper exposure",
+            StripParameter::PaperGrade => "Paper grade",
+        }
+    }
+
+    /// A sensible distance between two bands
+    pub fn default_step(&self) -> f32 {
+        match self {
+            StripParameter::Exposure => 1.0 / 3.0,
+            StripParameter::Contrast => 5.0,
+            StripParameter::PaperExposure => 1.0 / 3.0,
+            StripParameter::PaperGrade => 1.0,
+        }
+    }
+
+    fn range(&self) -> (f32, f32) {
+        match self {
+            StripParameter::Exposure => (-3.0, 3.0),
+            StripParameter::Contrast => (-30.0, 30.0),
+            StripParameter::PaperExposure => (-3.0, 3.0),
+            StripParameter::PaperGrade => (-1.0, 5.0),
+        }
+    }
+
+    pub fn get(&self, uniform: &FragmentUniform) -> f32 {
+        match self {
+            StripParameter::Exposure => uniform.exposure,
+            StripParameter::Contrast => uniform.contrast,
+            StripParameter::PaperExposure => uniform.paper.exposure,
+            StripParameter::PaperGrade => uniform.paper.grade,
+        }
+    }
+
+    pub fn set(&self, uniform: &mut FragmentUniform, value: f32) {
+        let (min, max) = self.range();
+        let value = value.clamp(min, max);
+
+        match self {
+            StripParameter::Exposure => uniform.exposure = value,
+            StripParameter::Contrast => uniform.contrast = value,
+            StripParameter::PaperExposure => uniform.paper.exposure = value,
+            StripParameter::PaperGrade => uniform.paper.grade = value,
+        }
+    }
+
+    pub fn format(&self, value: f32) -> String {
+        match self {
+            StripParameter::Exposure => format!("{value:+.2} EV"),
+            StripParameter::Contrast => format!("{value:+.0}"),
+            StripParameter::PaperExposure => format!("{value:+.2} EV"),
+            StripParameter::PaperGrade => crate::darkroom::uniform::paper_grade_name(value),
+        }
+    }
+}
+
+/// What the user asked for, kept around between strips
+#[derive(Debug, Copy, Clone)]
+pub struct StripSettings {
+    pub parameter: StripParameter,
+    pub bands: usize,
+    pub step: f32,
+}
+
+impl Default for StripSettings {
+    fn default() -> Self {
+        Self {
+            parameter: StripParameter::Exposure,
+            bands: 5,
+            step: StripParameter::Exposure.default_step(),
+        }
+    }
+}
+
+/// A rendered test strip: the image split in vertical bands, each one
+/// developed with a different value of the same parameter
+pub struct TestStrip {
+    pub parameter: StripParameter,
+
+    /// The value used for each band, from left to right
+    pub values: Vec<f32>,
+
+    /// The strip as shown on screen
+    pub texture_id: egui::TextureId,
+
+    image: RgbaImage,
+    texture: mq::TextureId,
+}
+
+impl TestStrip {
+    /// Renders every band with the renderer and stitches them side by side.
+    /// The values are centered around the current one.
+    pub fn render(
+        mq_ctx: &mut mq::Context,
+        renderer: &Renderer,
+        uniform: FragmentUniform,
+        settings: StripSettings,
+    ) -> Self {
+        let parameter = settings.parameter;
+        let current = parameter.get(&uniform);
+        let center = (settings.bands as f32 - 1.0) / 2.0;
+
+        let values: Vec<f32> = (0..settings.bands)
+            .map(|i| {
+                let mut u = uniform;
+                parameter.set(&mut u, current + (i as f32 - center) * settings.step);
+                parameter.get(&u)
+            })
+            .collect();
+
+        let (width, height) = renderer.dimensions();
+        let mut image = RgbaImage::new(width, height);
+
+        for (i, value) in values.iter().enumerate() {
+            let mut u = uniform;
+            parameter.set(&mut u, *value);
+            renderer.render(mq_ctx, u);
+            let band = renderer.read_output(mq_ctx);
+
+            let (start, end) = band_columns(width, values.len(), i);
+            for y in 0..height {
+                for x in start..end {
+                    image.put_pixel(x, y, *band.get_pixel(x, y));
+                }
+            }
+        }
+
+        // Put the regular render back in place
+        renderer.render(mq_ctx, uniform);
+
+        let texture = mq_ctx.new_texture_from_rgba8(width as u16, height as u16, &image);
+
+        Self {
+            parameter,
+            values,
+            texture_id: super::mq_to_egui_texture_id(mq_ctx, texture),
+            image,
+            texture,
+        }
+    }
+
+    /// Which band is under a horizontal position, from 0 to 1
+    pub fn band_at(&self, x: f32) -> usize {
+        ((x * self.values.len() as f32) as usize).min(self.values.len() - 1)
+    }
+
+    /// Saves the strip next to the original image, with the value of each
+    /// band written at its bottom
+    pub fn export(&self, image_path: &str) -> Result<PathBuf, image::ImageError> {
+        let mut image = self.image.clone();
+        let (width, height) = image.dimensions();
+        let scale = (height / LABEL_LINES).max(1);
+
+        for (i, value) in self.values.iter().enumerate() {
+            let (start, end) = band_columns(width, self.values.len(), i);
+            let label = self.parameter.format(*value);
+            draw_label(&mut image, &label, (start + end) / 2, height - scale, scale);
+        }
+
+        let path = export_path(image_path);
+        image.save(&path)?;
+
+        Ok(path)
+    }
+
+    pub fn delete(self, mq_ctx: &mut mq::Context) {
+        mq_ctx.delete_texture(self.texture);
+    }
+}
+
+/// The first and one past the last columns of a band
+fn band_columns(width: u32, bands: usize, band: usize) -> (u32, u32) {
+    let start = width as usize * band / bands;
+    let end = width as usize * (band + 1) / bands;
+
+    (start as u32, end as u32)
+}
+
+/// How many lines of labels would fit in the height of a strip
+const LABEL_LINES: u32 = 120;
+
+const GLYPH_WIDTH: u32 = 5;
+const GLYPH_HEIGHT: u32 = 7;
+
+/// The rows of a character, top to bottom, with the leftmost column in the
+/// highest bit. Only what the values of the bands are written with.
+fn glyph(c: char) -> [u8; GLYPH_HEIGHT as usize] {
+    match c {
+        '0' => [0x0e, 0x11, 0x13, 0x15, 0x19, 0x11, 0x0e],
+        '1' => [0x04, 0x0c, 0x04, 0x04, 0x04, 0x04, 0x0e],
+        '2' => [0x0e, 0x11, 0x01, 0x02, 0x04, 0x08, 0x1f],
+        '3' => [0x1f, 0x02, 0x04, 0x02, 0x01, 0x11, 0x0e],
+        '4' => [0x02, 0x06, 0x0a, 0x12, 0x1f, 0x02, 0x02],
+        '5' => [0x1f, 0x10, 0x1e, 0x01, 0x01, 0x11, 0x0e],
+        '6' => [0x06, 0x08, 0x10, 0x1e, 0x11, 0x11, 0x0e],
+        '7' => [0x1f, 0x01, 0x02, 0x04, 0x08, 0x08, 0x08],
+        '8' => [0x0e, 0x11, 0x11, 0x0e, 0x11, 0x11, 0x0e],
+        '9' => [0x0e, 0x11, 0x11, 0x0f, 0x01, 0x02, 0x0c],
+        '+' => [0x00, 0x04, 0x04, 0x1f, 0x04, 0x04, 0x00],
+        '-' => [0x00, 0x00, 0x00, 0x1f, 0x00, 0x00, 0x00],
+        '.' => [0x00, 0x00, 0x00, 0x00, 0x00, 0x0c, 0x0c],
+        'E' => [0x1f, 0x10, 0x10, 0x1e, 0x10, 0x10, 0x1f],
+        'V' => [0x11, 0x11, 0x11, 0x11, 0x11, 0x0a, 0x04],
+        _ => [0; GLYPH_HEIGHT as usize],
+    }
+}
+
+/// Writes white text on a black box, centered on `center` with its bottom
+/// edge at `bottom`. Every dot of a glyph is `scale` pixels wide.
+fn draw_label(image: &mut RgbaImage, text: &str, center: u32, bottom: u32, scale: u32) {
+    let advance = (GLYPH_WIDTH + 1) * scale;
+    let width = advance * text.chars().count() as u32 + scale;
+    let height = (GLYPH_HEIGHT + 2) * scale;
+    let left = center.saturating_sub(width / 2);
+    let top = bottom.saturating_sub(height);
+
+    let mut fill = |x: u32, y: u32, width: u32, height: u32, color: Rgba<u8>| {
+        for y in y..(y + height).min(image.height()) {
+            for x in x..(x + width).min(image.width()) {
+                image.put_pixel(x, y, color);
+            }
+        }
+    };
+
+    fill(left, top, width, height, Rgba([0, 0, 0, 255]));
+
+    for (i, c) in text.chars().enumerate() {
+        let x = left + scale + i as u32 * advance;
+        for (row, bits) in glyph(c).iter().enumerate() {
+            let y = top + scale + row as u32 * scale;
+            for column in 0..GLYPH_WIDTH {
+                if (bits >> (GLYPH_WIDTH - 1 - column)) & 1 == 1 {
+                    fill(x + column * scale, y, scale, scale, Rgba([255; 4]));
+                }
+            }
+        }
+    }
+}
+
+fn export_path(image_path: &str) -> PathBuf {
+    let path = Path::new(image_path);
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+
+    path.with_file_name(format!("{stem}_test_strip.png"))
+}
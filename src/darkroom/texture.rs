@@ -1,6 +1,8 @@
 use image::{DynamicImage, GenericImageView};
 use miniquad::{self as mq, TextureParams};
 
+use crate::darkroom::color::LinearImage;
+
 /// A representation of an image in the GPU
 pub struct Texture {
     /// The ID of the texture
@@ -11,17 +13,18 @@ pub struct Texture {
 }
 
 impl Texture {
-    /// Creates a new Texture with some data on it
-    pub fn input(mq_ctx: &mut mq::Context, data: DynamicImage) -> Self {
+    /// Creates a new Texture with some data on it. Images bigger than what
+    /// the GPU takes have to be split first, see [`super::tile`], None is
+    /// returned for sizes a texture can't even be asked for.
+    pub fn input(mq_ctx: &mut mq::Context, data: DynamicImage) -> Option<Self> {
         let (width, height) = data.dimensions();
-        let data = data.to_rgba8();
-
-        let id = mq_ctx.new_texture_from_rgba8(width as u16, height as u16, &data);
+        let (width16, height16) = texture_size(width, height)?;
+        let id = mq_ctx.new_texture_from_rgba8(width16, height16, &data.to_rgba8());
 
-        Self {
+        Some(Self {
             id,
             size: (width, height),
-        }
+        })
     }
 
     pub fn output(mq_ctx: &mut mq::Context, size: (u32, u32)) -> Self {
@@ -36,3 +39,67 @@ impl Texture {
         Self { id, size }
     }
 }
+
+/// The image being developed, in the GPU. Textures only take 8 bits per
+/// channel, so the 16 bit linear pixels are split into their high and low
+/// bytes and the shader puts them back together. Both are filtered the same
+/// way, so the sum stays exact.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct InputTexture {
+    /// The high bytes of every channel
+    pub high: mq::TextureId,
+    /// The low bytes, or the same texture as `high` when it's gamma encoded
+    pub low: mq::TextureId,
+
+    /// The dimensions
+    pub size: (u32, u32),
+
+    /// Whether it holds 8 bit values with a 2.2 gamma instead of linear
+    /// ones, for images that couldn't be read into the working space
+    pub gamma: bool,
+}
+
+impl InputTexture {
+    /// Uploads linear pixels. Images bigger than what the GPU takes have to
+    /// be split first, see [`super::tile`], None is returned for sizes a
+    /// texture can't even be asked for.
+    pub fn linear(mq_ctx: &mut mq::Context, pixels: &LinearImage) -> Option<Self> {
+        let (width, height) = pixels.dimensions();
+        let (width16, height16) = texture_size(width, height)?;
+        let (high, low): (Vec<u8>, Vec<u8>) = pixels
+            .iter()
+            .map(|c| ((c >> 8) as u8, (c & 0xff) as u8))
+            .unzip();
+
+        Some(Self {
+            high: mq_ctx.new_texture_from_rgba8(width16, height16, &high),
+            low: mq_ctx.new_texture_from_rgba8(width16, height16, &low),
+            size: (width, height),
+            gamma: false,
+        })
+    }
+
+    /// An 8 bit texture uploaded elsewhere, taken as gamma encoded working
+    /// space values
+    pub fn gamma(id: mq::TextureId, size: (u32, u32)) -> Self {
+        Self {
+            high: id,
+            low: id,
+            size,
+            gamma: true,
+        }
+    }
+
+    /// Frees the textures uploaded by [`InputTexture::linear`]
+    pub fn delete(&self, mq_ctx: &mut mq::Context) {
+        if !self.gamma {
+            mq_ctx.delete_texture(self.high);
+            mq_ctx.delete_texture(self.low);
+        }
+    }
+}
+
+/// Texture sizes are `u16`, images with a side longer than that don't fit
+fn texture_size(width: u32, height: u32) -> Option<(u16, u16)> {
+    Some((u16::try_from(width).ok()?, u16::try_from(height).ok()?))
+}
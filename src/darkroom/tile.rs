@@ -0,0 +1,318 @@
+//! Rendering of images too big for a single texture. They're split into
+//! tiles, each uploaded with a margin around it so corrections that read
+//! neighboring pixels still find them, then rendered on their own and put
+//! back together.
+
+use image::{imageops, math::Rect, RgbaImage};
+use miniquad as mq;
+
+use crate::darkroom::{
+    color::LinearImage,
+    uniform::{LensUniform, TileUniform},
+};
+
+/// Side of the tiles, unless the driver can't take textures twice as big
+const TILE_SIZE: u32 = 4096;
+/// Extra pixels uploaded around each tile, on top of what the lens
+/// corrections move
+const TILE_MARGIN: u32 = 16;
+/// Points checked along each side of a tile to find where the lens
+/// corrections read from
+const EDGE_STEPS: u32 = 16;
+/// What every GL implementation has to support, in case the driver can't
+/// be asked
+const MIN_TEXTURE_SIZE: u32 = 2048;
+
+/// The biggest texture the driver takes. Textures sizes are passed around as
+/// `u16`, so it's never more than that. It doesn't change, so it's asked once
+/// when the darkroom opens.
+pub fn max_texture_size() -> u32 {
+    let mut size = 0;
+    unsafe { mq::gl::glGetIntegerv(mq::gl::GL_MAX_TEXTURE_SIZE, &mut size) };
+
+    (size.max(0) as u32).clamp(MIN_TEXTURE_SIZE, u16::MAX as u32)
+}
+
+/// Whether an image has to be rendered in tiles
+pub fn needs_tiling(size: (u32, u32), max_texture_size: u32) -> bool {
+    size.0 > max_texture_size || size.1 > max_texture_size
+}
+
+/// Side of the tiles, leaving room in a texture for the source around them
+fn tile_size(max_texture_size: u32) -> u32 {
+    TILE_SIZE.min(max_texture_size / 2)
+}
+
+/// A piece of the image to render
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Tile {
+    /// The pixels the tile renders
+    pub region: Rect,
+    /// The pixels uploaded to render them, around the region
+    pub source: Rect,
+}
+
+impl Tile {
+    /// Tells the shader where the tile is
+    pub fn uniform(&self, size: (u32, u32)) -> TileUniform {
+        let (width, height) = (size.0 as f32, size.1 as f32);
+        let to_uv = |rect: Rect| {
+            [
+                rect.x as f32 / width,
+                rect.y as f32 / height,
+                rect.width as f32 / width,
+                rect.height as f32 / height,
+            ]
+        };
+
+        TileUniform {
+            image_size: [width, height],
+            region: to_uv(self.region),
+            source: to_uv(self.source),
+        }
+    }
+}
+
+/// Splits an image into tiles, with sources big enough for the lens
+/// corrections to find every pixel they read
+pub fn tiles(size: (u32, u32), lens: &LensUniform, max_texture_size: u32) -> Vec<Tile> {
+    let (width, height) = size;
+    let side = tile_size(max_texture_size);
+    let mut tiles = vec![];
+
+    for y in (0..height).step_by(side as usize) {
+        for x in (0..width).step_by(side as usize) {
+            let region = Rect {
+                x,
+                y,
+                width: side.min(width - x),
+                height: side.min(height - y),
+            };
+            let source = source_of(region, size, lens, max_texture_size);
+
+            tiles.push(Tile { region, source });
+        }
+    }
+
+    tiles
+}
+
+/// Renders every tile with `render`, which gets the pixels of the source
+/// and the tile, and returns the pixels of the region. Gives up on the first
+/// tile that can't be rendered.
+pub fn render_tiled(
+    image: &LinearImage,
+    lens: &LensUniform,
+    max_texture_size: u32,
+    mut render: impl FnMut(LinearImage, &Tile) -> Option<RgbaImage>,
+) -> Option<RgbaImage> {
+    let (width, height) = image.dimensions();
+    let mut output = RgbaImage::new(width, height);
+
+    for tile in tiles((width, height), lens, max_texture_size) {
+        let Rect {
+            x,
+            y,
+            width,
+            height,
+        } = tile.source;
+        let source = imageops::crop_imm(image, x, y, width, height).to_image();
+
+        let rendered = render(source, &tile)?;
+        imageops::replace(
+            &mut output,
+            &rendered,
+            tile.region.x as i64,
+            tile.region.y as i64,
+        );
+    }
+
+    Some(output)
+}
+
+/// Where the pixels of a region come from once the lens is corrected. The
+/// corrections are continuous, so following the outline of the region is
+/// enough to bound what's inside.
+fn source_of(region: Rect, size: (u32, u32), lens: &LensUniform, max_texture_size: u32) -> Rect {
+    let (width, height) = (size.0 as f32, size.1 as f32);
+    let half_short = width.min(height) / 2.0;
+
+    let (mut min_x, mut min_y) = (region.x as f32, region.y as f32);
+    let (mut max_x, mut max_y) = (
+        (region.x + region.width) as f32,
+        (region.y + region.height) as f32,
+    );
+
+    let step = 1.0 / EDGE_STEPS as f32;
+    let outline = (0..=EDGE_STEPS).flat_map(|i| {
+        let t = i as f32 * step;
+        [[t, 0.0], [t, 1.0], [0.0, t], [1.0, t]]
+    });
+    for [u, v] in outline {
+        let x = region.x as f32 + u * region.width as f32;
+        let y = region.y as f32 + v * region.height as f32;
+
+        // Same as sampleLens in the shader
+        let d = [
+            (x - width / 2.0) / half_short,
+            (y - height / 2.0) / half_short,
+        ];
+        let r2 = d[0] * d[0] + d[1] * d[1];
+        let scale = 1.0 + lens.k1 * r2 + lens.k2 * r2 * r2;
+
+        for ca in [0.0, lens.ca_red, lens.ca_blue] {
+            let s = scale * (1.0 + ca);
+            let sx = width / 2.0 + d[0] * s * half_short;
+            let sy = height / 2.0 + d[1] * s * half_short;
+
+            min_x = min_x.min(sx);
+            min_y = min_y.min(sy);
+            max_x = max_x.max(sx);
+            max_y = max_y.max(sy);
+        }
+    }
+
+    let margin = TILE_MARGIN as f32;
+    let x0 = (min_x - margin).floor().clamp(0.0, width) as u32;
+    let y0 = (min_y - margin).floor().clamp(0.0, height) as u32;
+    let x1 = (max_x + margin).ceil().clamp(0.0, width) as u32;
+    let y1 = (max_y + margin).ceil().clamp(0.0, height) as u32;
+
+    // A lens bending that far would need more than a texture, read what fits
+    // around the region
+    let max = max_texture_size;
+    let x0 = x0.max((region.x + region.width).saturating_sub(max));
+    let y0 = y0.max((region.y + region.height).saturating_sub(max));
+    Rect {
+        x: x0,
+        y: y0,
+        width: (x1 - x0).min(max),
+        height: (y1 - y0).min(max),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Tiles of 1024 px, the last row and column only partly filled
+    const MAX_TEXTURE_SIZE: u32 = 2048;
+    const SIZE: (u32, u32) = (2500, 1300);
+
+    fn contains(outer: Rect, inner: Rect) -> bool {
+        outer.x <= inner.x
+            && outer.y <= inner.y
+            && inner.x + inner.width <= outer.x + outer.width
+            && inner.y + inner.height <= outer.y + outer.height
+    }
+
+    fn in_image(rect: Rect, size: (u32, u32)) -> bool {
+        rect.x + rect.width <= size.0 && rect.y + rect.height <= size.1
+    }
+
+    #[test]
+    fn tiles_cover_the_image_once() {
+        let tiles = tiles(SIZE, &LensUniform::default(), MAX_TEXTURE_SIZE);
+        assert_eq!(tiles.len(), 3 * 2);
+
+        let mut covered = vec![0; SIZE.0 as usize * SIZE.1 as usize];
+        for tile in &tiles {
+            let region = tile.region;
+            assert!(in_image(region, SIZE));
+            for y in region.y..region.y + region.height {
+                for x in region.x..region.x + region.width {
+                    covered[(y * SIZE.0 + x) as usize] += 1;
+                }
+            }
+        }
+        assert!(covered.iter().all(|&count| count == 1));
+    }
+
+    #[test]
+    fn sources_overlap_by_the_margin() {
+        for tile in tiles(SIZE, &LensUniform::default(), MAX_TEXTURE_SIZE) {
+            let (region, source) = (tile.region, tile.source);
+            assert!(contains(source, region));
+
+            // The margin, unless the image ends first
+            assert_eq!(source.x, region.x.saturating_sub(TILE_MARGIN));
+            assert_eq!(source.y, region.y.saturating_sub(TILE_MARGIN));
+            assert_eq!(
+                source.x + source.width,
+                (region.x + region.width + TILE_MARGIN).min(SIZE.0)
+            );
+            assert_eq!(
+                source.y + source.height,
+                (region.y + region.height + TILE_MARGIN).min(SIZE.1)
+            );
+        }
+    }
+
+    #[test]
+    fn edge_tiles_are_clamped() {
+        let tiles = tiles(SIZE, &LensUniform::default(), MAX_TEXTURE_SIZE);
+
+        let last = tiles.last().unwrap().region;
+        assert_eq!((last.x, last.y), (2048, 1024));
+        assert_eq!((last.width, last.height), (2500 - 2048, 1300 - 1024));
+        for tile in &tiles {
+            assert!(in_image(tile.source, SIZE));
+        }
+    }
+
+    #[test]
+    fn distorted_sources_stay_in_bounds() {
+        let lenses =
+            [(0.05, 0.0), (-0.05, 0.01), (0.4, 0.2), (-0.4, -0.2)].map(|(k1, k2)| LensUniform {
+                k1,
+                k2,
+                ca_red: 0.002,
+                ca_blue: -0.002,
+                ..Default::default()
+            });
+
+        for lens in &lenses {
+            for tile in tiles(SIZE, lens, MAX_TEXTURE_SIZE) {
+                let source = tile.source;
+                assert!(in_image(source, SIZE), "{lens:?}");
+                assert!(source.width <= MAX_TEXTURE_SIZE && source.height <= MAX_TEXTURE_SIZE);
+                assert!(contains(source, tile.region), "{lens:?}");
+            }
+        }
+    }
+
+    #[test]
+    fn distorted_sources_hold_what_the_lens_reads() {
+        let lens = LensUniform {
+            k1: 0.05,
+            k2: -0.01,
+            ..Default::default()
+        };
+        let (width, height) = (SIZE.0 as f32, SIZE.1 as f32);
+        let half_short = width.min(height) / 2.0;
+
+        for tile in tiles(SIZE, &lens, MAX_TEXTURE_SIZE) {
+            let Rect {
+                x,
+                y,
+                width: w,
+                height: h,
+            } = tile.region;
+            let source = tile.source;
+
+            for (x, y) in [(x, y), (x + w / 2, y + h / 2), (x + w - 1, y + h - 1)] {
+                let d = [
+                    (x as f32 - width / 2.0) / half_short,
+                    (y as f32 - height / 2.0) / half_short,
+                ];
+                let r2 = d[0] * d[0] + d[1] * d[1];
+                let scale = 1.0 + lens.k1 * r2 + lens.k2 * r2 * r2;
+                let sx = (width / 2.0 + d[0] * scale * half_short).clamp(0.0, width - 1.0);
+                let sy = (height / 2.0 + d[1] * scale * half_short).clamp(0.0, height - 1.0);
+
+                assert!(source.x as f32 <= sx && sx < (source.x + source.width) as f32);
+                assert!(source.y as f32 <= sy && sy < (source.y + source.height) as f32);
+            }
+        }
+    }
+}
@@ -1,17 +1,62 @@
 use cgmath::{Deg, Matrix4, SquareMatrix};
+use miniquad as mq;
 use serde::{Deserialize, Serialize};
 
+use crate::darkroom::{
+    color::{self, RgbSpace, Transfer},
+    sampler::MAX_SAMPLES,
+    stage::DevelopStage,
+};
+
+/// Names of the hue bands of the HSL module, in the same order as the shader
+pub const HSL_BANDS: [&str; 8] = [
+    "Red", "Orange", "Yellow", "Green", "Aqua", "Blue", "Purple", "Magenta",
+];
+
 // We need this for Rust to store our data correctly for the shaders
 #[repr(C)]
 // This is so we can store this in a buffer
-#[derive(Debug, Copy, Clone, Serialize, Deserialize)]
+#[derive(Debug, Copy, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
 pub struct FragmentUniform {
     pub contrast: f32,
     pub saturation: f32,
-    pub brightness: f32,
+    /// In stops, applied in linear light
+    pub exposure: f32,
+    /// Compresses (negative) or expands (positive) the brightest tones, from -1 to 1
+    pub highlights: f32,
+    /// Lifts (positive) or deepens (negative) the darkest tones, from -1 to 1
+    pub shadows: f32,
+    /// Correction for the camera and light the image was scanned with, in
+    /// linear light before anything else. Only the upper 3x3 part is used.
+    pub input_matrix: [[f32; 4]; 4],
     // GLSL doesn't support bools in uniforms so we'll have to trick it
     pub invert: u32,
     pub temperature: f32,
+    /// Color of the unexposed film, divided out before inverting so the
+    /// orange mask of color negatives goes away. It's kept as picked, with
+    /// the 2.2 gamma of the working space.
+    pub film_base: [f32; 3],
+    /// Gains for each channel, applied in linear light right after inverting
+    pub white_balance: [f32; 3],
+    pub hsl: HslUniform,
+    pub grading: GradingUniform,
+    pub monochrome: MonochromeUniform,
+    pub paper: PaperUniform,
+    pub masks: MasksUniform,
+    pub lens: LensUniform,
+    /// Depends on where the image goes rather than on the edits
+    #[serde(skip)]
+    pub output: OutputUniform,
+    /// Set by the renderer, depends on the part of the image being rendered
+    #[serde(skip)]
+    pub tile: TileUniform,
+    /// Set by the renderer, depends on the texture it reads
+    #[serde(skip)]
+    pub input: InputUniform,
+    /// Set by the renderer, which develop stages the pass runs
+    #[serde(skip)]
+    pub stages: StagesUniform,
 }
 
 impl Default for FragmentUniform {
@@ -19,13 +64,534 @@ impl Default for FragmentUniform {
         Self {
             contrast: 0.0,
             saturation: 1.0,
-            brightness: 0.0,
+            exposure: 0.0,
+            highlights: 0.0,
+            shadows: 0.0,
+            input_matrix: Matrix4::identity().into(),
             invert: 0,
             temperature: 5500.0,
+            film_base: [1.0; 3],
+            white_balance: [1.0; 3],
+            hsl: HslUniform::default(),
+            grading: GradingUniform::default(),
+            monochrome: MonochromeUniform::default(),
+            paper: PaperUniform::default(),
+            masks: MasksUniform::default(),
+            lens: LensUniform::default(),
+            output: OutputUniform::default(),
+            tile: TileUniform::default(),
+            input: InputUniform::default(),
+            stages: StagesUniform::default(),
+        }
+    }
+}
+
+/// Edits used to have a brightness offset, added to the gamma encoded
+/// values from -0.25 to 0.25. This is the exposure that moves middle gray
+/// the same way, in stops.
+pub fn exposure_from_brightness(brightness: f32) -> f32 {
+    let gray = 0.5;
+    2.2 * ((gray + brightness).max(0.01) / gray).log2()
+}
+
+impl FragmentUniform {
+    /// The layout of the uniforms as the shader sees them. The order has to
+    /// match the fields of the struct, as they're uploaded as a single table.
+    pub fn uniform_descs() -> Vec<mq::UniformDesc> {
+        vec![
+            mq::UniformDesc::new("contrast", mq::UniformType::Float1),
+            mq::UniformDesc::new("saturation", mq::UniformType::Float1),
+            mq::UniformDesc::new("exposure", mq::UniformType::Float1),
+            mq::UniformDesc::new("highlights", mq::UniformType::Float1),
+            mq::UniformDesc::new("shadows", mq::UniformType::Float1),
+            mq::UniformDesc::new("input_matrix", mq::UniformType::Mat4),
+            mq::UniformDesc::new("invert", mq::UniformType::Int1),
+            mq::UniformDesc::new("temperature", mq::UniformType::Float1),
+            mq::UniformDesc::new("film_base", mq::UniformType::Float3),
+            mq::UniformDesc::new("white_balance", mq::UniformType::Float3),
+            mq::UniformDesc::new("hsl_hue", mq::UniformType::Float1).array(HSL_BANDS.len()),
+            mq::UniformDesc::new("hsl_saturation", mq::UniformType::Float1).array(HSL_BANDS.len()),
+            mq::UniformDesc::new("hsl_luminance", mq::UniformType::Float1).array(HSL_BANDS.len()),
+            mq::UniformDesc::new("grading_shadows", mq::UniformType::Float3),
+            mq::UniformDesc::new("grading_midtones", mq::UniformType::Float3),
+            mq::UniformDesc::new("grading_highlights", mq::UniformType::Float3),
+            mq::UniformDesc::new("grading_global", mq::UniformType::Float3),
+            mq::UniformDesc::new("grading_balance", mq::UniformType::Float1),
+            mq::UniformDesc::new("monochrome", mq::UniformType::Int1),
+            mq::UniformDesc::new("mixer", mq::UniformType::Float3),
+            mq::UniformDesc::new("toning", mq::UniformType::Int1),
+            mq::UniformDesc::new("toning_strength", mq::UniformType::Float1),
+            mq::UniformDesc::new("toning_split_hues", mq::UniformType::Float2),
+            mq::UniformDesc::new("paper", mq::UniformType::Int1),
+            mq::UniformDesc::new("paper_exposure", mq::UniformType::Float1),
+            mq::UniformDesc::new("paper_grade", mq::UniformType::Float1),
+            mq::UniformDesc::new("paper_split_grade", mq::UniformType::Int1),
+            mq::UniformDesc::new("paper_soft_exposure", mq::UniformType::Float1),
+            mq::UniformDesc::new("paper_hard_exposure", mq::UniformType::Float1),
+            mq::UniformDesc::new("mask_kind", mq::UniformType::Int1).array(MAX_MASKS),
+            mq::UniformDesc::new("mask_invert", mq::UniformType::Int1).array(MAX_MASKS),
+            mq::UniformDesc::new("mask_geometry", mq::UniformType::Float4).array(MAX_MASKS),
+            mq::UniformDesc::new("mask_feather", mq::UniformType::Float1).array(MAX_MASKS),
+            mq::UniformDesc::new("mask_adjustments", mq::UniformType::Float4).array(MAX_MASKS),
+            mq::UniformDesc::new("lens_k1", mq::UniformType::Float1),
+            mq::UniformDesc::new("lens_k2", mq::UniformType::Float1),
+            mq::UniformDesc::new("lens_vignetting", mq::UniformType::Float1),
+            mq::UniformDesc::new("lens_ca_red", mq::UniformType::Float1),
+            mq::UniformDesc::new("lens_ca_blue", mq::UniformType::Float1),
+            mq::UniformDesc::new("output_matrix", mq::UniformType::Mat4),
+            mq::UniformDesc::new("output_transfer", mq::UniformType::Int1),
+            mq::UniformDesc::new("output_gamma", mq::UniformType::Float1),
+            mq::UniformDesc::new("image_size", mq::UniformType::Float2),
+            mq::UniformDesc::new("tile_region", mq::UniformType::Float4),
+            mq::UniformDesc::new("tile_source", mq::UniformType::Float4),
+            mq::UniformDesc::new("input_gamma", mq::UniformType::Int1),
+            mq::UniformDesc::new("first_stage", mq::UniformType::Int1),
+            mq::UniformDesc::new("last_stage", mq::UniformType::Int1),
+        ]
+    }
+}
+
+/// Per hue band adjustments, every value goes from -1 to 1
+#[repr(C)]
+#[derive(Debug, Copy, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct HslUniform {
+    /// Shifts the hue towards its neighbouring bands
+    pub hue: [f32; 8],
+    pub saturation: [f32; 8],
+    pub luminance: [f32; 8],
+}
+
+/// Split toning / color grading. Each tonal range gets a tint, stored as
+/// `[hue in degrees, strength from 0 to 1, luminance from -1 to 1]`.
+#[repr(C)]
+#[derive(Debug, Copy, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct GradingUniform {
+    pub shadows: [f32; 3],
+    pub midtones: [f32; 3],
+    pub highlights: [f32; 3],
+    /// Applied evenly over the whole tonal range
+    pub global: [f32; 3],
+    /// Moves the split between shadows and highlights, from -1 to 1
+    pub balance: f32,
+}
+
+/// Black and white conversion. When enabled, the color specific stages are
+/// skipped and the image is built from a weighted mix of the channels.
+#[repr(C)]
+#[derive(Debug, Copy, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct MonochromeUniform {
+    pub enabled: u32,
+    /// How much each of the red, green and blue channels contribute
+    pub mixer: [f32; 3],
+    /// One of [`Toning`]
+    pub toning: u32,
+    pub toning_strength: f32,
+    /// Hues of the shadows and highlights for split toning, in degrees
+    pub split_hues: [f32; 2],
+}
+
+impl Default for MonochromeUniform {
+    fn default() -> Self {
+        Self {
+            enabled: 0,
+            mixer: ContrastFilter::None.mixer(),
+            toning: Toning::None as u32,
+            toning_strength: 0.5,
+            split_hues: [220.0, 40.0],
+        }
+    }
+}
+
+/// Lowest and highest contrast grades of variable contrast paper. Grade 00
+/// is stored as -1.
+pub const PAPER_GRADE_MIN: f32 = -1.0;
+pub const PAPER_GRADE_MAX: f32 = 5.0;
+
+/// Emulates printing a black and white negative on variable contrast paper
+#[repr(C)]
+#[derive(Debug, Copy, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct PaperUniform {
+    pub enabled: u32,
+    /// Exposure time, in stops relative to the one that renders middle grey
+    pub exposure: f32,
+    /// Contrast grade, from [`PAPER_GRADE_MIN`] to [`PAPER_GRADE_MAX`]
+    pub grade: f32,
+    /// Expose separately through the softest and the hardest filter instead
+    /// of using a single grade
+    pub split_grade: u32,
+    pub soft_exposure: f32,
+    pub hard_exposure: f32,
+}
+
+impl Default for PaperUniform {
+    fn default() -> Self {
+        Self {
+            enabled: 0,
+            exposure: 0.0,
+            grade: 2.0,
+            split_grade: 0,
+            soft_exposure: -1.0,
+            hard_exposure: -1.0,
+        }
+    }
+}
+
+/// How grades are usually written on the filters
+pub fn paper_grade_name(grade: f32) -> String {
+    if grade < 0.0 {
+        "00".to_string()
+    } else if grade.fract() == 0.0 {
+        format!("{grade:.0}")
+    } else {
+        format!("{grade:.1}")
+    }
+}
+
+/// How many local adjustments an image can have
+pub const MAX_MASKS: usize = 8;
+
+/// The shape of a local adjustment
+#[repr(u32)]
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum MaskKind {
+    None = 0,
+    /// Fades from the first point to the second one
+    Linear = 1,
+    /// An ellipse, fading towards its edge
+    Radial = 2,
+}
+
+impl MaskKind {
+    pub fn from_u32(kind: u32) -> Self {
+        match kind {
+            1 => MaskKind::Linear,
+            2 => MaskKind::Radial,
+            _ => MaskKind::None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            MaskKind::None => "None",
+            MaskKind::Linear => "Linear",
+            MaskKind::Radial => "Radial",
+        }
+    }
+}
+
+/// Local adjustments through gradient masks. Positions are relative to the
+/// image, from 0 to 1 with the origin on the top left corner.
+#[repr(C)]
+#[derive(Debug, Copy, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct MasksUniform {
+    /// One of [`MaskKind`], unused slots are [`MaskKind::None`]
+    pub kinds: [u32; MAX_MASKS],
+    /// Apply the adjustments outside of the mask instead
+    pub invert: [u32; MAX_MASKS],
+    /// Linear masks are `[start x, start y, end x, end y]`, radial ones are
+    /// `[center x, center y, radius x, radius y]`
+    pub geometry: [[f32; 4]; MAX_MASKS],
+    /// How soft the edge of radial masks is, from 0 to 1
+    pub feather: [f32; MAX_MASKS],
+    /// `[exposure in stops, contrast, temperature, saturation]`
+    pub adjustments: [[f32; 4]; MAX_MASKS],
+}
+
+impl MasksUniform {
+    /// Takes the first free slot for a new mask, returning its index
+    pub fn add(&mut self, kind: MaskKind) -> Option<usize> {
+        let i = self
+            .kinds
+            .iter()
+            .position(|k| *k == MaskKind::None as u32)?;
+
+        self.kinds[i] = kind as u32;
+        self.invert[i] = 0;
+        self.geometry[i] = match kind {
+            MaskKind::Radial => [0.5, 0.5, 0.25, 0.25],
+            _ => [0.5, 0.0, 0.5, 0.5],
+        };
+        self.feather[i] = 0.5;
+        self.adjustments[i] = [0.0; 4];
+
+        Some(i)
+    }
+
+    pub fn remove(&mut self, i: usize) {
+        self.kinds[i] = MaskKind::None as u32;
+    }
+
+    pub fn kind(&self, i: usize) -> MaskKind {
+        MaskKind::from_u32(self.kinds[i])
+    }
+}
+
+/// Lens corrections. Radii are 1 at half of the shorter side of the image,
+/// like lensfun measures them, so the values can be copied from there.
+#[repr(C)]
+#[derive(Debug, Copy, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct LensUniform {
+    /// Radial distortion, a pixel at radius r comes from
+    /// `r * (1 + k1 * r^2 + k2 * r^4)`. Negative values fix barrel
+    /// distortion, positive values fix pincushion.
+    pub k1: f32,
+    pub k2: f32,
+    /// How much light the lens loses towards the corners, as the falloff
+    /// `1 + vignetting * r^2` with r being 1 at the corners. It's divided
+    /// out in linear light.
+    pub vignetting: f32,
+    /// Lateral chromatic aberration, how much bigger the red and blue
+    /// channels are than the green one
+    pub ca_red: f32,
+    pub ca_blue: f32,
+}
+
+/// Conversion from the working space to the space the image is shown or
+/// exported in, as the last step of the shader
+#[repr(C)]
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct OutputUniform {
+    /// Linear working space RGB to linear output RGB. Only the upper 3x3
+    /// part is used, it's a 4x4 matrix because that's what uniforms support.
+    pub matrix: [[f32; 4]; 4],
+    /// 0 for the sRGB curve, 1 for a plain gamma
+    pub transfer: u32,
+    pub gamma: f32,
+}
+
+impl Default for OutputUniform {
+    fn default() -> Self {
+        Self::new(&color::SRGB)
+    }
+}
+
+impl OutputUniform {
+    pub fn new(space: &RgbSpace) -> Self {
+        let matrix = color::conversion(&color::WORKING_SPACE, space);
+        let (transfer, gamma) = match space.transfer {
+            Transfer::Srgb => (0, 2.4),
+            Transfer::Gamma(gamma) => (1, gamma),
+        };
+
+        Self {
+            matrix: Matrix4::from(matrix).into(),
+            transfer,
+            gamma,
+        }
+    }
+}
+
+/// Which part of the image a render covers. Rectangles are x, y, width and
+/// height in texture coordinates of the whole image.
+#[repr(C)]
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct TileUniform {
+    /// Size of the whole image in pixels
+    pub image_size: [f32; 2],
+    /// What the render covers
+    pub region: [f32; 4],
+    /// What the input texture holds
+    pub source: [f32; 4],
+}
+
+impl Default for TileUniform {
+    fn default() -> Self {
+        Self::whole((1, 1))
+    }
+}
+
+impl TileUniform {
+    /// The whole image, in a single texture
+    pub fn whole(size: (u32, u32)) -> Self {
+        Self {
+            image_size: [size.0 as f32, size.1 as f32],
+            region: [0.0, 0.0, 1.0, 1.0],
+            source: [0.0, 0.0, 1.0, 1.0],
+        }
+    }
+}
+
+/// How the shader reads the input texture
+#[repr(C)]
+#[derive(Debug, Copy, Clone, Default, PartialEq)]
+pub struct InputUniform {
+    /// The input is gamma encoded in a single texture rather than split in
+    /// linear light, see [`super::texture::InputTexture`]
+    pub gamma: u32,
+}
+
+/// The develop stages a pass runs, both included, see
+/// [`super::stage::DevelopStage`]. Stages after the first one read what the
+/// one before left in an intermediate texture.
+#[repr(C)]
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct StagesUniform {
+    pub first: u32,
+    pub last: u32,
+}
+
+impl StagesUniform {
+    pub fn only(stage: DevelopStage) -> Self {
+        Self {
+            first: stage as u32,
+            last: stage as u32,
+        }
+    }
+}
+
+impl Default for StagesUniform {
+    /// All of them in one pass
+    fn default() -> Self {
+        Self {
+            first: DevelopStage::Input as u32,
+            last: DevelopStage::Color as u32,
+        }
+    }
+}
+
+/// The colored filters used in front of the lens with black and white film
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum ContrastFilter {
+    None,
+    Yellow,
+    Orange,
+    Red,
+    Green,
+}
+
+impl ContrastFilter {
+    pub const ALL: [ContrastFilter; 5] = [
+        ContrastFilter::None,
+        ContrastFilter::Yellow,
+        ContrastFilter::Orange,
+        ContrastFilter::Red,
+        ContrastFilter::Green,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            ContrastFilter::None => "None",
+            ContrastFilter::Yellow => "Yellow",
+            ContrastFilter::Orange => "Orange",
+            ContrastFilter::Red => "Red",
+            ContrastFilter::Green => "Green",
+        }
+    }
+
+    /// Channel mixer weights that roughly match the response of panchromatic
+    /// film behind this filter
+    pub fn mixer(&self) -> [f32; 3] {
+        match self {
+            ContrastFilter::None => [0.30, 0.59, 0.11],
+            ContrastFilter::Yellow => [0.45, 0.47, 0.08],
+            ContrastFilter::Orange => [0.62, 0.34, 0.04],
+            ContrastFilter::Red => [0.85, 0.15, 0.0],
+            ContrastFilter::Green => [0.20, 0.72, 0.08],
+        }
+    }
+}
+
+/// Chemical toners applied after the black and white conversion
+#[repr(u32)]
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Toning {
+    None = 0,
+    Selenium = 1,
+    Sepia = 2,
+    SplitTone = 3,
+}
+
+impl Toning {
+    pub const ALL: [Toning; 4] = [
+        Toning::None,
+        Toning::Selenium,
+        Toning::Sepia,
+        Toning::SplitTone,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Toning::None => "None",
+            Toning::Selenium => "Selenium",
+            Toning::Sepia => "Sepia",
+            Toning::SplitTone => "Split tone",
+        }
+    }
+}
+
+/// Settings of the clipping warning. It's only used for display, so it isn't
+/// stored with the edits.
+#[repr(C)]
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct ClippingUniform {
+    /// Anything at or above this is shown in red
+    pub highlight_threshold: f32,
+    /// Anything at or below this is shown in blue
+    pub shadow_threshold: f32,
+    /// Check every channel on its own instead of the luminance
+    pub per_channel: u32,
+}
+
+impl Default for ClippingUniform {
+    fn default() -> Self {
+        Self {
+            highlight_threshold: 0.99,
+            shadow_threshold: 0.01,
+            per_channel: 0,
         }
     }
 }
 
+impl ClippingUniform {
+    pub fn uniform_descs() -> Vec<mq::UniformDesc> {
+        vec![
+            mq::UniformDesc::new("highlight_threshold", mq::UniformType::Float1),
+            mq::UniformDesc::new("shadow_threshold", mq::UniformType::Float1),
+            mq::UniformDesc::new("per_channel", mq::UniformType::Int1),
+        ]
+    }
+}
+
+/// Settings of the soft proofing pass, display only like the clipping warning
+#[repr(C)]
+#[derive(Debug, Copy, Clone, Default, PartialEq)]
+pub struct ProofUniform {
+    /// Paint the colors the proofed profile can't hold in grey
+    pub show_gamut: u32,
+}
+
+impl ProofUniform {
+    pub fn uniform_descs() -> Vec<mq::UniformDesc> {
+        vec![mq::UniformDesc::new("show_gamut", mq::UniformType::Int1)]
+    }
+}
+
+/// Where to read colors from for the color sampler, see [`super::sampler`]
+#[repr(C)]
+#[derive(Debug, Copy, Clone, Default, PartialEq)]
+pub struct SampleUniform {
+    pub points: [[f32; 2]; MAX_SAMPLES],
+    /// The texture is the linear input split in two, see
+    /// [`super::texture::InputTexture`]. The readings are gamma encoded so
+    /// 8 bits are enough to read them back.
+    pub split: u32,
+}
+
+impl SampleUniform {
+    pub fn uniform_descs() -> Vec<mq::UniformDesc> {
+        vec![
+            mq::UniformDesc::new("points", mq::UniformType::Float2).array(MAX_SAMPLES),
+            mq::UniformDesc::new("split", mq::UniformType::Int1),
+        ]
+    }
+}
+
 #[repr(C)]
 #[derive(Debug, Copy, Clone)]
 pub struct VertexUniform {
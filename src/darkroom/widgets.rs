@@ -0,0 +1,86 @@
+use egui::{ecolor::Hsva, epaint::Mesh, Color32, Pos2, Response, Sense, Shape, Stroke, Ui, Vec2};
+
+const WHEEL_DIAMETER: f32 = 120.0;
+
+/// How many segments to use when drawing the rim of the wheel
+const WHEEL_SEGMENTS: u32 = 64;
+
+/// A hue / saturation picker, drawn as a disc where the angle is the hue and
+/// the distance from the center is the saturation
+pub struct ColorWheel<'a> {
+    /// Hue, in degrees
+    hue: &'a mut f32,
+
+    /// From 0 to 1
+    saturation: &'a mut f32,
+}
+
+impl<'a> ColorWheel<'a> {
+    pub fn new(hue: &'a mut f32, saturation: &'a mut f32) -> Self {
+        Self { hue, saturation }
+    }
+}
+
+impl egui::Widget for ColorWheel<'_> {
+    fn ui(self, ui: &mut Ui) -> Response {
+        let (rect, mut response) =
+            ui.allocate_exact_size(Vec2::splat(WHEEL_DIAMETER), Sense::click_and_drag());
+        let center = rect.center();
+        let radius = WHEEL_DIAMETER / 2.0;
+
+        if let Some(pos) = response.interact_pointer_pos() {
+            let delta = pos - center;
+            // Holding shift only changes the strength, keeping the hue as it was
+            if !ui.input(|i| i.modifiers.shift) {
+                *self.hue = (-delta.y).atan2(delta.x).to_degrees().rem_euclid(360.0);
+            }
+            *self.saturation = (delta.length() / radius).clamp(0.0, 1.0);
+            response.mark_changed();
+        }
+
+        if response.double_clicked() {
+            *self.saturation = 0.0;
+            response.mark_changed();
+        }
+
+        if ui.is_rect_visible(rect) {
+            let painter = ui.painter();
+
+            let mut mesh = Mesh::default();
+            mesh.colored_vertex(center, Color32::GRAY);
+            for i in 0..=WHEEL_SEGMENTS {
+                let hue = i as f32 / WHEEL_SEGMENTS as f32;
+                let color = Hsva::new(hue, 1.0, 1.0, 1.0);
+                mesh.colored_vertex(
+                    wheel_position(center, radius, hue * 360.0, 1.0),
+                    color.into(),
+                );
+
+                if i > 0 {
+                    mesh.add_triangle(0, i, i + 1);
+                }
+            }
+            painter.add(Shape::mesh(mesh));
+
+            let stroke = ui.visuals().widgets.noninteractive.bg_stroke;
+            painter.circle_stroke(center, radius, stroke);
+
+            let handle = wheel_position(center, radius, *self.hue, *self.saturation);
+            painter.circle(
+                handle,
+                4.0,
+                Color32::TRANSPARENT,
+                Stroke::new(2.0, Color32::WHITE),
+            );
+            painter.circle_stroke(handle, 5.5, Stroke::new(1.0, Color32::BLACK));
+        }
+
+        response.on_hover_text("Drag to pick a tint, shift to only change its strength")
+    }
+}
+
+/// The point on the wheel for a given hue, in degrees, and saturation
+fn wheel_position(center: Pos2, radius: f32, hue: f32, saturation: f32) -> Pos2 {
+    let angle = hue.to_radians();
+    center + saturation * radius * Vec2::new(angle.cos(), -angle.sin())
+}
@@ -1,13 +1,18 @@
 #![allow(clippy::new_without_default)]
 
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
-use polodb_core::bson::doc;
+use polodb_core::bson::{doc, Bson, Document};
 use serde::{Deserialize, Serialize};
 
-use crate::darkroom;
+use crate::darkroom::{self, brush::BrushStroke, sampler::SamplePoint};
+use crate::lighttable::preset::Preset;
 
 const IMAGE_COLLECTION: &str = "image";
+const PRESET_COLLECTION: &str = "preset";
+const ROLL_COLLECTION: &str = "roll";
+const INPUT_PROFILE_COLLECTION: &str = "input_profile";
+const CAMERA_CORRECTION_COLLECTION: &str = "camera_correction";
 
 pub struct Database {
     db: polodb_core::Database,
@@ -17,7 +22,39 @@ impl Database {
     pub fn new() -> Self {
         let db = polodb_core::Database::open_file("emulse.db").unwrap();
 
-        Self { db }
+        let database = Self { db };
+        if let Err(err) = database.migrate_images() {
+            log::error!("couldn't bring old edits up to date: {err}");
+        }
+
+        database
+    }
+
+    /// Brings edits saved by older versions up to date the first time the
+    /// catalog opens, see [`migrate_image`]
+    fn migrate_images(&self) -> polodb_core::Result<()> {
+        let collection = self.db.collection::<Document>(IMAGE_COLLECTION);
+        let mut legacy = vec![];
+        for document in collection.find(None)? {
+            let mut document = document?;
+            if migrate_image(&mut document) {
+                legacy.push(document);
+            }
+        }
+        if legacy.is_empty() {
+            return Ok(());
+        }
+
+        let mut session = self.db.start_session()?;
+        session.start_transaction(None)?;
+        for document in legacy {
+            let id = document.get("_id").cloned().unwrap_or(Bson::Null);
+            collection.delete_one_with_session(doc! { "_id": id }, &mut session)?;
+            collection.insert_one_with_session(document, &mut session)?;
+        }
+        session.commit_transaction()?;
+
+        Ok(())
     }
 
     pub fn insert_images(
@@ -40,20 +77,372 @@ impl Database {
             .collect()
     }
 
+    /// The edits of one version of an image, if it has any
+    pub fn get_image(&self, key: &ImageKey) -> polodb_core::Result<Option<Image>> {
+        let versions = self.get_images_in_path(PathBuf::from(&key.path))?;
+
+        Ok(versions
+            .into_iter()
+            .find(|image| image.version == key.version))
+    }
+
+    /// The edits of several versions at once, in a single query. Versions
+    /// without edits are left out.
+    pub fn get_images_by_key(&self, keys: &[ImageKey]) -> polodb_core::Result<Vec<Image>> {
+        let mut paths: Vec<&str> = keys.iter().map(|key| key.path.as_str()).collect();
+        paths.sort_unstable();
+        paths.dedup();
+
+        let images: Vec<Image> = self
+            .db
+            .collection(IMAGE_COLLECTION)
+            .find(doc! {
+                "path": {
+                    "$in": paths,
+                },
+            })?
+            .collect::<polodb_core::Result<_>>()?;
+
+        Ok(images
+            .into_iter()
+            .filter(|image| keys.contains(&image.key()))
+            .collect())
+    }
+
+    /// Stores the edits of an image, replacing the ones it had before
+    pub fn save_image(&self, image: &Image) -> polodb_core::Result<()> {
+        self.save_images(std::slice::from_ref(image))
+    }
+
+    /// Stores the edits of several images in one go, replacing the ones they
+    /// had before. Every version of a file lives in its own document, and
+    /// the ones that aren't being saved are left alone. Either all of them
+    /// are saved or none is.
+    pub fn save_images(&self, images: &[Image]) -> polodb_core::Result<()> {
+        let collection = self.db.collection::<Image>(IMAGE_COLLECTION);
+
+        let mut session = self.db.start_session()?;
+        session.start_transaction(None)?;
+        for image in images {
+            collection.delete_one_with_session(key_filter(&image.key()), &mut session)?;
+            collection.insert_one_with_session(image, &mut session)?;
+        }
+        session.commit_transaction()?;
+
+        Ok(())
+    }
+
+    /// Removes a single version of an image, leaving the others alone
+    pub fn delete_version(&self, key: &ImageKey) -> polodb_core::Result<()> {
+        self.db
+            .collection::<Image>(IMAGE_COLLECTION)
+            .delete_one(key_filter(key))?;
+
+        Ok(())
+    }
+
+    pub fn get_presets(&self) -> polodb_core::Result<Vec<Preset>> {
+        let mut presets: Vec<Preset> = self
+            .db
+            .collection(PRESET_COLLECTION)
+            .find(None)?
+            .collect::<polodb_core::Result<_>>()?;
+        presets.sort_by_key(|preset| preset.name.to_lowercase());
+
+        Ok(presets)
+    }
+
+    /// Stores a preset, replacing the one with the same name if any
+    pub fn save_preset(&self, preset: &Preset) -> polodb_core::Result<()> {
+        let collection = self.db.collection::<Preset>(PRESET_COLLECTION);
+
+        let mut session = self.db.start_session()?;
+        session.start_transaction(None)?;
+        collection.delete_one_with_session(
+            doc! {
+                "name": preset.name.as_str(),
+            },
+            &mut session,
+        )?;
+        collection.insert_one_with_session(preset, &mut session)?;
+        session.commit_transaction()?;
+
+        Ok(())
+    }
+
+    pub fn delete_preset(
+        &self,
+        name: &str,
+    ) -> Result<polodb_core::results::DeleteResult, polodb_core::Error> {
+        self.db
+            .collection::<Preset>(PRESET_COLLECTION)
+            .delete_one(doc! {
+                "name": name,
+            })
+    }
+
+    /// The settings of a roll, or the defaults if it has none yet
+    pub fn get_roll(&self, path: &str) -> polodb_core::Result<Roll> {
+        let roll = self.db.collection::<Roll>(ROLL_COLLECTION).find_one(doc! {
+            "path": path,
+        })?;
+
+        Ok(roll.unwrap_or_else(|| Roll {
+            path: path.to_string(),
+            ..Default::default()
+        }))
+    }
+
+    pub fn save_roll(&self, roll: &Roll) -> polodb_core::Result<()> {
+        let collection = self.db.collection::<Roll>(ROLL_COLLECTION);
+
+        let mut session = self.db.start_session()?;
+        session.start_transaction(None)?;
+        collection.delete_one_with_session(
+            doc! {
+                "path": roll.path.as_str(),
+            },
+            &mut session,
+        )?;
+        collection.insert_one_with_session(roll, &mut session)?;
+        session.commit_transaction()?;
+
+        Ok(())
+    }
+
+    pub fn get_input_profiles(&self) -> polodb_core::Result<Vec<InputProfile>> {
+        let mut profiles: Vec<InputProfile> = self
+            .db
+            .collection(INPUT_PROFILE_COLLECTION)
+            .find(None)?
+            .collect::<polodb_core::Result<_>>()?;
+        profiles.sort_by_key(|profile| profile.name.to_lowercase());
+
+        Ok(profiles)
+    }
+
+    /// The profile assigned to the roll an image belongs to, if any
+    pub fn get_input_profile_for(
+        &self,
+        image_path: &str,
+    ) -> polodb_core::Result<Option<InputProfile>> {
+        let Some(name) = self.get_roll(&roll_path(image_path))?.input_profile else {
+            return Ok(None);
+        };
+
+        self.db
+            .collection::<InputProfile>(INPUT_PROFILE_COLLECTION)
+            .find_one(doc! {
+                "name": name,
+            })
+    }
+
+    /// Stores an input profile, replacing the one with the same name if any
+    pub fn save_input_profile(&self, profile: &InputProfile) -> polodb_core::Result<()> {
+        let collection = self.db.collection::<InputProfile>(INPUT_PROFILE_COLLECTION);
+
+        let mut session = self.db.start_session()?;
+        session.start_transaction(None)?;
+        collection.delete_one_with_session(
+            doc! {
+                "name": profile.name.as_str(),
+            },
+            &mut session,
+        )?;
+        collection.insert_one_with_session(profile, &mut session)?;
+        session.commit_transaction()?;
+
+        Ok(())
+    }
+
+    pub fn get_camera_corrections(&self) -> polodb_core::Result<Vec<CameraCorrection>> {
+        let mut corrections: Vec<CameraCorrection> = self
+            .db
+            .collection(CAMERA_CORRECTION_COLLECTION)
+            .find(None)?
+            .collect::<polodb_core::Result<_>>()?;
+        corrections.sort_by_key(|correction| correction.name().to_lowercase());
+
+        Ok(corrections)
+    }
+
+    /// Stores a correction, replacing the one for the same camera and light
+    pub fn save_camera_correction(&self, correction: &CameraCorrection) -> polodb_core::Result<()> {
+        let collection = self
+            .db
+            .collection::<CameraCorrection>(CAMERA_CORRECTION_COLLECTION);
+
+        let mut session = self.db.start_session()?;
+        session.start_transaction(None)?;
+        collection.delete_one_with_session(
+            doc! {
+                "camera": correction.camera.as_str(),
+                "light": correction.light.as_str(),
+            },
+            &mut session,
+        )?;
+        collection.insert_one_with_session(correction, &mut session)?;
+        session.commit_transaction()?;
+
+        Ok(())
+    }
+
     pub fn delete_image_in_path(
         &self,
         path: PathBuf,
     ) -> Result<polodb_core::results::DeleteResult, polodb_core::Error> {
         self.db
             .collection::<Image>(IMAGE_COLLECTION)
-            .delete_one(doc! {
+            .delete_many(doc! {
                 "path": path.to_string_lossy().to_string(),
             })
     }
 }
 
-#[derive(Debug, Serialize, Deserialize, Default)]
+/// Edits from before virtual copies have no version, they're the original.
+/// Edits from before exposure replaced brightness still have the old offset,
+/// in the image and its snapshots. Returns false if there was nothing to do.
+fn migrate_image(image: &mut Document) -> bool {
+    let mut migrated = !image.contains_key("version");
+    if migrated {
+        image.insert("version", 0_i64);
+    }
+
+    migrated |= match image.get_document_mut("uniform") {
+        Ok(uniform) => migrate_uniform(uniform),
+        Err(_) => false,
+    };
+
+    if let Ok(snapshots) = image.get_array_mut("snapshots") {
+        for snapshot in snapshots {
+            if let Some(uniform) = snapshot
+                .as_document_mut()
+                .and_then(|snapshot| snapshot.get_document_mut("uniform").ok())
+            {
+                migrated |= migrate_uniform(uniform);
+            }
+        }
+    }
+
+    migrated
+}
+
+fn migrate_uniform(uniform: &mut Document) -> bool {
+    let Some(brightness) = uniform.remove("brightness") else {
+        return false;
+    };
+
+    if !uniform.contains_key("exposure") {
+        let brightness = brightness.as_f64().unwrap_or_default() as f32;
+        let exposure = darkroom::uniform::exposure_from_brightness(brightness);
+        uniform.insert("exposure", exposure as f64);
+    }
+
+    true
+}
+
+/// Matches the document of a single version of an image
+fn key_filter(key: &ImageKey) -> Document {
+    doc! {
+        "path": key.path.as_str(),
+        "version": i64::from(key.version),
+    }
+}
+
+/// Identifies one version of an image in the catalog
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct ImageKey {
+    pub path: String,
+    /// 0 is the original, virtual copies count up from 1
+    pub version: u32,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
 pub struct Image {
     pub path: String,
+    /// Several versions of the same file can be edited independently
+    #[serde(default)]
+    pub version: u32,
+    /// Shown on the lighttable to tell virtual copies apart
+    #[serde(default)]
+    pub version_name: String,
+    pub uniform: darkroom::uniform::FragmentUniform,
+    /// Dodge and burn, kept as vectors so they don't depend on the image size
+    #[serde(default)]
+    pub strokes: Vec<BrushStroke>,
+    /// Where the color sampler measures the image
+    #[serde(default)]
+    pub samples: Vec<SamplePoint>,
+    /// Named states of the edits, to go back to or compare against
+    #[serde(default)]
+    pub snapshots: Vec<Snapshot>,
+}
+
+impl Image {
+    /// Empty edits for a version of a file
+    pub fn new(key: &ImageKey) -> Self {
+        Self {
+            path: key.path.clone(),
+            version: key.version,
+            ..Default::default()
+        }
+    }
+
+    pub fn key(&self) -> ImageKey {
+        ImageKey {
+            path: self.path.clone(),
+            version: self.version,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Snapshot {
+    pub name: String,
     pub uniform: darkroom::uniform::FragmentUniform,
+    #[serde(default)]
+    pub strokes: Vec<BrushStroke>,
+}
+
+/// A folder of scans, which share the settings of the scanner they came from
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Roll {
+    pub path: String,
+    /// The name of the [`InputProfile`] the scans are converted from,
+    /// instead of the profile embedded in them
+    #[serde(default)]
+    pub input_profile: Option<String>,
+}
+
+/// An ICC profile describing a scanner or a camera, kept in the catalog so
+/// rolls can refer to it by name
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct InputProfile {
+    pub name: String,
+    pub icc: Vec<u8>,
+}
+
+/// The roll an image belongs to, which is the folder it's in
+pub fn roll_path(image_path: &str) -> String {
+    Path::new(image_path)
+        .parent()
+        .unwrap_or(Path::new(""))
+        .to_string_lossy()
+        .to_string()
+}
+
+/// A color correction for scanning with a camera under a light source,
+/// fitted from a ColorChecker shot in the same setup
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CameraCorrection {
+    pub camera: String,
+    pub light: String,
+    /// As stored in [`crate::darkroom::uniform::FragmentUniform::input_matrix`]
+    pub matrix: [[f32; 4]; 4],
+}
+
+impl CameraCorrection {
+    pub fn name(&self) -> String {
+        format!("{} · {}", self.camera, self.light)
+    }
 }
@@ -1,31 +1,140 @@
 pub mod db;
 pub mod image;
+pub mod module;
+pub mod preset;
+pub mod stitch;
 
 use egui::TextureHandle;
 use mut_rc::MutRc;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
 use std::sync::Arc;
 
 use crate::app::{CurrentView, EmulseState};
+use crate::lighttable::db::{Database, ImageKey};
 use crate::lighttable::image::Image;
+use crate::lighttable::module::EditModule;
+use crate::lighttable::preset::Preset;
+
+/// Long edge of the textures shown on the slides, a few times their size on
+/// screen so they stay sharp on high DPI displays
+const THUMBNAIL_SIZE: u32 = 512;
 
 pub struct LightTable {
     pub images: Vec<Arc<Image>>,
     pub texture_map: HashMap<String, TextureHandle>,
 
     state: MutRc<EmulseState>,
+
+    /// Where the edits are stored
+    db: Rc<Database>,
+
+    /// Names of the versions of every file that has edits, by path
+    versions: HashMap<String, Vec<(u32, String)>>,
+
+    /// The selected versions
+    selection: HashSet<ImageKey>,
+
+    /// The version clicked last, which edits are copied from
+    focused: Option<ImageKey>,
+
+    /// Edits copied from an image, waiting to be pasted
+    clipboard: Option<db::Image>,
+
+    /// Which modules get pasted, remembered between pastes
+    paste_modules: Vec<EditModule>,
+    paste_dialog_open: bool,
+
+    /// Presets in the catalog, reloaded whenever they may have changed
+    presets: Vec<Preset>,
+
+    /// The .icc file to import as an input profile
+    profile_import_path: String,
+
+    /// Feedback about the last action
+    status: String,
+
+    /// The stitch running in the background, if any
+    stitch_job: Option<stitch::StitchJob>,
 }
 
 impl LightTable {
-    pub fn new(state: MutRc<EmulseState>) -> Self {
-        Self {
+    pub fn new(state: MutRc<EmulseState>, db: Rc<Database>) -> Self {
+        let mut light_table = Self {
             images: vec![],
             state,
             texture_map: HashMap::new(),
+            db,
+            versions: HashMap::new(),
+            selection: HashSet::new(),
+            focused: None,
+            clipboard: None,
+            paste_modules: EditModule::global(),
+            paste_dialog_open: false,
+            presets: vec![],
+            profile_import_path: String::new(),
+            status: String::new(),
+            stitch_job: None,
+        };
+        light_table.reload_versions();
+        light_table.reload_presets();
+
+        light_table
+    }
+
+    /// Reads the presets from the catalog again, they're saved in the darkroom
+    pub fn reload_presets(&mut self) {
+        self.presets = self.db.get_presets().unwrap_or_else(|err| {
+            log::error!("couldn't load the presets: {err}");
+            vec![]
+        });
+    }
+
+    /// Reads which versions every file has from the catalog again
+    pub fn reload_versions(&mut self) {
+        let images = match self.db.get_images() {
+            Ok(images) => images,
+            Err(err) => {
+                log::error!("couldn't load the versions of the images: {err}");
+                return;
+            }
+        };
+
+        self.versions.clear();
+        for image in images {
+            self.versions
+                .entry(image.path)
+                .or_default()
+                .push((image.version, image.version_name));
+        }
+        for versions in self.versions.values_mut() {
+            versions.sort_by_key(|(version, _)| *version);
         }
     }
 
+    /// One tile per version of every file. The original is always there,
+    /// even before it has any edits.
+    fn tiles(&self) -> Vec<(Arc<Image>, u32, String)> {
+        let mut tiles = vec![];
+
+        for img in &self.images {
+            let versions = self.versions.get(&img.path);
+            if !versions.is_some_and(|v| v.iter().any(|(version, _)| *version == 0)) {
+                tiles.push((img.clone(), 0, String::new()));
+            }
+
+            for (version, name) in versions.into_iter().flatten() {
+                tiles.push((img.clone(), *version, name.clone()));
+            }
+        }
+
+        tiles
+    }
+
     pub fn ui(&mut self, ctx: &egui::Context) {
+        self.poll_stitch(ctx);
+
         egui::SidePanel::left("left_panel")
             .min_width(200.0)
             .show(ctx, |ui| {
@@ -42,13 +151,21 @@ impl LightTable {
                 });
             });
 
+        egui::TopBottomPanel::top("lighttable_controls").show(ctx, |ui| {
+            ui.horizontal(|ui| self.controls_ui(ui));
+        });
+
+        self.paste_dialog(ctx);
+
         egui::CentralPanel::default().show(ctx, |ui| {
             ui.centered_and_justified(|ui| {
                 egui::ScrollArea::both()
                     .auto_shrink([false, false])
                     .show(ui, |ui| {
+                        let tiles = self.tiles();
+
                         let mut grid_builder = egui_grid::GridBuilder::new().spacing(16.0, 16.0);
-                        for i in 0..self.images.len() {
+                        for i in 0..tiles.len() {
                             if i % 4 == 0 {
                                 grid_builder =
                                     grid_builder.new_row(egui_extras::Size::exact(250.0));
@@ -59,10 +176,10 @@ impl LightTable {
 
                         // Only show after preallocating enough space
                         grid_builder.show(ui, |mut grid| {
-                            for img in self.images.clone() {
+                            for (img, version, name) in tiles {
                                 grid.cell(|ui| {
                                     ui.centered_and_justified(|ui| {
-                                        self.image_slide(ctx, ui, &img);
+                                        self.image_slide(ctx, ui, &img, version, &name);
                                     });
                                 });
                             }
@@ -72,14 +189,22 @@ impl LightTable {
         });
     }
 
-    fn image_slide(&mut self, ctx: &egui::Context, ui: &mut egui::Ui, img: &Image) {
+    fn image_slide(
+        &mut self,
+        ctx: &egui::Context,
+        ui: &mut egui::Ui,
+        img: &Image,
+        version: u32,
+        version_name: &str,
+    ) {
         //TODO: move this to another function, only leave ui stuff here
         if !self.texture_map.contains_key(img.path.as_str()) {
-            // TODO: dynamically infer this, as some files can be in 16 bit
-            let bytes = img.data.as_rgb8().unwrap();
+            // Full size scans can be bigger than a texture, and the slides
+            // are small anyway
+            let thumbnail = img.data.thumbnail(THUMBNAIL_SIZE, THUMBNAIL_SIZE).to_rgb8();
             let data = egui::ColorImage::from_rgb(
-                [img.data.width() as usize, img.data.height() as usize],
-                bytes,
+                [thumbnail.width() as usize, thumbnail.height() as usize],
+                &thumbnail,
             );
             let handle = ctx.load_texture(img.path.clone(), data, Default::default());
             self.texture_map.insert(img.path.to_string(), handle);
@@ -87,8 +212,18 @@ impl LightTable {
 
         let handle = self.texture_map.get(img.path.as_str()).unwrap();
 
+        let key = ImageKey {
+            path: img.path.clone(),
+            version,
+        };
+        let selected = self.selection.contains(&key);
+
         let mut f = egui::Frame::default().inner_margin(32.0).begin(ui);
-        f.frame.fill = egui::Color32::DARK_GRAY;
+        f.frame.fill = if selected {
+            egui::Color32::from_rgb(60, 80, 110)
+        } else {
+            egui::Color32::DARK_GRAY
+        };
         {
             let image = egui::Image::new(handle)
                 .show_loading_spinner(true)
@@ -101,20 +236,521 @@ impl LightTable {
                 .fit_to_exact_size((176.0, 176.0).into());
             let resp = f.content_ui.add(image);
 
-            if resp.hovered() {
+            if resp.hovered() && !selected {
                 f.frame.fill = egui::Color32::GRAY;
             }
 
+            if resp.clicked() {
+                self.click(ui, &key);
+            }
+
+            // Right clicking outside the selection acts on that image alone
+            if resp.secondary_clicked() && !selected {
+                self.selection = HashSet::from([key.clone()]);
+                self.focused = Some(key.clone());
+            }
+            resp.context_menu(|ui| self.context_menu(ui));
+
             if resp.double_clicked() {
                 let _ = self.state.with_mut(|state| {
                     state.selected_image_path = img.path.clone();
+                    state.selected_version = version;
                     state.current_view = CurrentView::Darkroom;
                 });
             }
 
+            let label = if version == 0 {
+                img.path.clone()
+            } else {
+                format!("{} · {version_name}", img.path)
+            };
             f.content_ui
-                .label(egui::RichText::new(img.path.as_str()).color(egui::Color32::WHITE));
+                .label(egui::RichText::new(label).color(egui::Color32::WHITE));
         }
         f.end(ui);
     }
+
+    /// Clicking selects a single image, holding ctrl adds it to the selection
+    /// or takes it out
+    fn click(&mut self, ui: &egui::Ui, key: &ImageKey) {
+        if ui.input(|i| i.modifiers.command) {
+            if !self.selection.remove(key) {
+                self.selection.insert(key.clone());
+            }
+        } else {
+            self.selection = HashSet::from([key.clone()]);
+        }
+
+        self.focused = Some(key.clone());
+    }
+
+    fn controls_ui(&mut self, ui: &mut egui::Ui) {
+        if ui
+            .add_enabled(self.focused.is_some(), egui::Button::new("Copy edits"))
+            .clicked()
+        {
+            self.copy_edits();
+        }
+
+        let can_paste = self.clipboard.is_some() && !self.selection.is_empty();
+        if ui
+            .add_enabled(can_paste, egui::Button::new("Paste"))
+            .clicked()
+        {
+            self.paste_edits();
+        }
+        if ui
+            .add_enabled(can_paste, egui::Button::new("Paste selected…"))
+            .clicked()
+        {
+            self.paste_dialog_open = true;
+        }
+
+        if ui
+            .add_enabled(
+                self.focused.is_some(),
+                egui::Button::new("Sync to all in roll"),
+            )
+            .on_hover_text("Copies the edits of the last clicked image to every image next to it")
+            .clicked()
+        {
+            self.sync_roll();
+        }
+
+        if ui
+            .add_enabled(self.can_stitch(), egui::Button::new("Stitch"))
+            .on_hover_text("Merges overlapping shots of the same negative into a new image")
+            .clicked()
+        {
+            self.stitch_selection();
+        }
+
+        ui.separator();
+        ui.label(format!("{} selected", self.selection.len()));
+        ui.label(&self.status);
+    }
+
+    fn context_menu(&mut self, ui: &mut egui::Ui) {
+        if ui.button("Copy edits").clicked() {
+            self.copy_edits();
+            ui.close_menu();
+        }
+
+        let can_paste = self.clipboard.is_some();
+        if ui
+            .add_enabled(can_paste, egui::Button::new("Paste edits"))
+            .clicked()
+        {
+            self.paste_edits();
+            ui.close_menu();
+        }
+        if ui
+            .add_enabled(can_paste, egui::Button::new("Paste selected…"))
+            .clicked()
+        {
+            self.paste_dialog_open = true;
+            ui.close_menu();
+        }
+
+        if ui.button("Sync to all in roll").clicked() {
+            self.sync_roll();
+            ui.close_menu();
+        }
+
+        ui.separator();
+        if ui.button("Create virtual copy").clicked() {
+            self.create_virtual_copy();
+            ui.close_menu();
+        }
+        let is_copy = self.focused.as_ref().is_some_and(|key| key.version > 0);
+        if ui
+            .add_enabled(is_copy, egui::Button::new("Delete virtual copy"))
+            .clicked()
+        {
+            self.delete_virtual_copy();
+            ui.close_menu();
+        }
+
+        if ui
+            .add_enabled(self.can_stitch(), egui::Button::new("Stitch selected"))
+            .clicked()
+        {
+            self.stitch_selection();
+            ui.close_menu();
+        }
+
+        ui.separator();
+        ui.menu_button("Scanner profile", |ui| self.scanner_profile_menu(ui));
+
+        ui.separator();
+        ui.menu_button("Apply preset", |ui| {
+            if self.presets.is_empty() {
+                ui.label("No presets yet, save one in the darkroom");
+            }
+
+            let mut applied = None;
+            for preset in &self.presets {
+                if ui.button(&preset.name).clicked() {
+                    applied = Some(preset.clone());
+                    ui.close_menu();
+                }
+            }
+            if let Some(preset) = applied {
+                self.apply_preset(&preset);
+            }
+        });
+    }
+
+    /// Assigns an input profile to the roll of the image clicked last, which
+    /// every image in it is converted from instead of its embedded profile
+    fn scanner_profile_menu(&mut self, ui: &mut egui::Ui) {
+        let Some(focused) = self.focused.clone() else {
+            return;
+        };
+
+        let mut roll = match self.db.get_roll(&db::roll_path(&focused.path)) {
+            Ok(roll) => roll,
+            Err(err) => {
+                log::error!("couldn't load the roll of {}: {err}", focused.path);
+                ui.label("Couldn't load the roll");
+                return;
+            }
+        };
+        let profiles = match self.db.get_input_profiles() {
+            Ok(profiles) => profiles,
+            Err(err) => {
+                log::error!("couldn't load the input profiles: {err}");
+                vec![]
+            }
+        };
+
+        ui.label(format!("Every image in {}", file_name(&roll.path)));
+        let mut assigned = roll.input_profile.clone();
+        ui.radio_value(&mut assigned, None, "Embedded or sRGB");
+        for profile in &profiles {
+            ui.radio_value(&mut assigned, Some(profile.name.clone()), &profile.name);
+        }
+
+        if assigned != roll.input_profile {
+            roll.input_profile = assigned;
+            self.status = match self.db.save_roll(&roll) {
+                Ok(()) => format!(
+                    "{} now uses {}",
+                    file_name(&roll.path),
+                    roll.input_profile.as_deref().unwrap_or("embedded profiles")
+                ),
+                Err(err) => {
+                    log::error!("couldn't save the roll {}: {err}", roll.path);
+                    "Couldn't assign the profile".to_string()
+                }
+            };
+        }
+
+        ui.separator();
+        ui.horizontal(|ui| {
+            ui.add(
+                egui::TextEdit::singleline(&mut self.profile_import_path)
+                    .hint_text("path/to/scanner.icc"),
+            );
+            if ui.button("Import").clicked() {
+                self.import_input_profile();
+            }
+        });
+    }
+
+    /// Adds an .icc file to the input profiles, named after the file
+    fn import_input_profile(&mut self) {
+        let path = Path::new(&self.profile_import_path);
+        let icc = match std::fs::read(path) {
+            Ok(icc) => icc,
+            Err(err) => {
+                self.status = format!("Couldn't read {}: {err}", path.display());
+                return;
+            }
+        };
+        if qcms::Profile::new_from_slice(&icc, false).is_none() {
+            self.status = format!("{} isn't an RGB ICC profile", path.display());
+            return;
+        }
+
+        let profile = db::InputProfile {
+            name: path
+                .file_stem()
+                .unwrap_or_default()
+                .to_string_lossy()
+                .to_string(),
+            icc,
+        };
+        self.status = match self.db.save_input_profile(&profile) {
+            Ok(()) => format!("Imported {}", profile.name),
+            Err(err) => {
+                log::error!("couldn't save the input profile {}: {err}", profile.name);
+                "Couldn't import the profile".to_string()
+            }
+        };
+    }
+
+    /// The files of the selected versions, in the order they're shown
+    fn selected_paths(&self) -> Vec<String> {
+        let mut paths: Vec<String> = self.selection.iter().map(|key| key.path.clone()).collect();
+        paths.sort_by_key(|path| path.to_lowercase());
+        paths.dedup();
+
+        paths
+    }
+
+    fn can_stitch(&self) -> bool {
+        self.stitch_job.is_none() && self.selected_paths().len() > 1
+    }
+
+    /// Starts merging the selected shots into a new image next to them, see
+    /// [`LightTable::poll_stitch`]
+    fn stitch_selection(&mut self) {
+        let paths = self.selected_paths();
+        let output = stitched_path(&paths[0]);
+        let job = stitch::StitchJob::start(paths, output);
+
+        self.status = format!("Stitching {} shots: {}", job.shots, job.step.name());
+        self.stitch_job = Some(job);
+    }
+
+    /// Keeps the status up with the running stitch. Once done, the stitched
+    /// image is added to the catalog to be developed like any other.
+    fn poll_stitch(&mut self, ctx: &egui::Context) {
+        let Some(job) = &mut self.stitch_job else {
+            return;
+        };
+        let Some(update) = job.poll() else {
+            self.status = format!("Stitching {} shots: {}", job.shots, job.step.name());
+            ctx.request_repaint_after(std::time::Duration::from_millis(100));
+            return;
+        };
+        let shots = job.shots;
+        self.stitch_job = None;
+
+        let (path, thumbnail) = match update {
+            stitch::StitchUpdate::Done { path, thumbnail } => (path, thumbnail),
+            stitch::StitchUpdate::Failed(err) => {
+                self.status = format!("Couldn't stitch: {err}");
+                return;
+            }
+            stitch::StitchUpdate::Progress(_) => return,
+        };
+
+        let key = ImageKey {
+            path: path.to_string_lossy().to_string(),
+            version: 0,
+        };
+        if let Err(err) = self.db.save_image(&db::Image::new(&key)) {
+            log::error!("couldn't add {} to the catalog: {err}", key.path);
+        }
+
+        // Only the slide reads the pixels, the darkroom opens the file
+        self.images.push(Arc::new(Image {
+            data: thumbnail,
+            path: key.path.clone(),
+        }));
+        self.images.sort_by_key(|image| image.path.to_lowercase());
+        self.reload_versions();
+
+        self.status = format!("Stitched {shots} shots into {}", file_name(&key.path));
+        self.selection = HashSet::from([key.clone()]);
+        self.focused = Some(key);
+    }
+
+    /// Picks which modules to paste before pasting them
+    fn paste_dialog(&mut self, ctx: &egui::Context) {
+        let mut open = self.paste_dialog_open;
+        let mut pasted = false;
+
+        egui::Window::new("Paste edits")
+            .open(&mut open)
+            .collapsible(false)
+            .resizable(false)
+            .show(ctx, |ui| {
+                module::modules_ui(ui, &mut self.paste_modules);
+
+                ui.separator();
+                let label = format!("Paste onto {} images", self.selection.len());
+                if ui.button(label).clicked() {
+                    pasted = true;
+                }
+            });
+
+        if pasted {
+            self.paste_edits();
+            open = false;
+        }
+        self.paste_dialog_open = open;
+    }
+
+    /// The edits of the version clicked last
+    fn focused_record(&mut self) -> Option<db::Image> {
+        let key = self.focused.clone()?;
+
+        match self.db.get_image(&key) {
+            Ok(record) => Some(record.unwrap_or_else(|| db::Image::new(&key))),
+            Err(err) => {
+                log::error!("couldn't load the edits of {}: {err}", key.path);
+                self.status = format!("Couldn't load the edits of {}", file_name(&key.path));
+                None
+            }
+        }
+    }
+
+    fn copy_edits(&mut self) {
+        if let Some(record) = self.focused_record() {
+            self.status = format!("Copied the edits of {}", file_name(&record.path));
+            self.clipboard = Some(record);
+        }
+    }
+
+    /// Adds a version of the file clicked last, starting from its edits
+    fn create_virtual_copy(&mut self) {
+        let Some(mut record) = self.focused_record() else {
+            return;
+        };
+
+        let last = self
+            .versions
+            .get(&record.path)
+            .and_then(|versions| versions.iter().map(|(version, _)| *version).max())
+            .unwrap_or(0);
+        record.version = last + 1;
+        record.version_name = format!("Copy {}", record.version);
+        record.snapshots.clear();
+
+        self.status = match self.db.save_image(&record) {
+            Ok(()) => format!("Created {}", record.version_name),
+            Err(err) => {
+                log::error!("couldn't create a virtual copy of {}: {err}", record.path);
+                "Couldn't create the virtual copy".to_string()
+            }
+        };
+        self.reload_versions();
+    }
+
+    fn delete_virtual_copy(&mut self) {
+        let Some(key) = self.focused.take().filter(|key| key.version > 0) else {
+            return;
+        };
+
+        if let Err(err) = self.db.delete_version(&key) {
+            log::error!("couldn't delete the virtual copy of {}: {err}", key.path);
+            self.status = "Couldn't delete the virtual copy".to_string();
+        }
+        self.selection.remove(&key);
+        self.reload_versions();
+    }
+
+    fn paste_edits(&mut self) {
+        let Some(source) = self.clipboard.clone() else {
+            return;
+        };
+
+        let targets: Vec<ImageKey> = self.selection.iter().cloned().collect();
+        self.paste_onto(&source, targets);
+    }
+
+    /// Copies the edits of the last clicked image onto every image in the
+    /// same folder
+    fn sync_roll(&mut self) {
+        // The clipboard is left alone, it may hold edits being pasted elsewhere
+        let Some(source) = self.focused_record() else {
+            return;
+        };
+
+        let roll = Path::new(&source.path).parent();
+        let targets = self
+            .tiles()
+            .into_iter()
+            .filter(|(img, _, _)| Path::new(&img.path).parent() == roll)
+            .map(|(img, version, _)| ImageKey {
+                path: img.path.clone(),
+                version,
+            })
+            .collect();
+        self.paste_onto(&source, targets);
+    }
+
+    /// Applies the modules being pasted to every target, saving all of them
+    /// at once
+    fn paste_onto(&mut self, source: &db::Image, targets: Vec<ImageKey>) {
+        let targets = targets
+            .into_iter()
+            .filter(|key| *key != source.key())
+            .collect();
+        let modules = self.paste_modules.clone();
+        self.status = self.edit_images(targets, |record| {
+            module::copy_modules(&modules, source, record);
+        });
+    }
+
+    fn apply_preset(&mut self, preset: &Preset) {
+        let targets = self.selection.iter().cloned().collect();
+        self.status = self.edit_images(targets, |record| preset.apply(record));
+        self.status = format!("{}: {}", preset.name, self.status);
+    }
+
+    /// Changes the edits of every target, saving all of them at once.
+    /// Returns what happened, for the status line.
+    fn edit_images(&self, targets: Vec<ImageKey>, edit: impl Fn(&mut db::Image)) -> String {
+        let mut existing = match self.db.get_images_by_key(&targets) {
+            Ok(images) => images
+                .into_iter()
+                .map(|image| (image.key(), image))
+                .collect::<HashMap<_, _>>(),
+            Err(err) => {
+                log::error!("couldn't load the edits to change: {err}");
+                return "Couldn't load the edits".to_string();
+            }
+        };
+
+        let records: Vec<db::Image> = targets
+            .into_iter()
+            .map(|key| {
+                let mut record = existing
+                    .remove(&key)
+                    .unwrap_or_else(|| db::Image::new(&key));
+                edit(&mut record);
+                record
+            })
+            .collect();
+
+        if records.is_empty() {
+            return "No images to change".to_string();
+        }
+
+        match self.db.save_images(&records) {
+            Ok(()) => format!("Changed the edits of {} images", records.len()),
+            Err(err) => {
+                log::error!("couldn't save the edits: {err}");
+                "Couldn't save the edits".to_string()
+            }
+        }
+    }
+}
+
+/// Where a stitch of shots starting with `first` goes, without replacing
+/// an earlier one
+fn stitched_path(first: &str) -> PathBuf {
+    let first = Path::new(first);
+    let stem = first.file_stem().unwrap_or_default().to_string_lossy();
+
+    let mut path = first.with_file_name(format!("{stem}_stitched.tif"));
+    let mut count = 1;
+    while path.exists() {
+        count += 1;
+        path = first.with_file_name(format!("{stem}_stitched_{count}.tif"));
+    }
+
+    path
+}
+
+fn file_name(path: &str) -> String {
+    Path::new(path)
+        .file_name()
+        .unwrap_or_default()
+        .to_string_lossy()
+        .to_string()
 }
@@ -0,0 +1,128 @@
+use serde::{Deserialize, Serialize};
+
+use crate::lighttable::db;
+
+/// Groups of edit settings that can be copied from one image to another on
+/// their own
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum EditModule {
+    /// Exposure, contrast, highlights and shadows
+    Tone,
+    /// Saturation and white balance
+    Color,
+    /// Camera correction, inversion and film base
+    Negative,
+    Hsl,
+    Grading,
+    BlackAndWhite,
+    Paper,
+    LocalAdjustments,
+    DodgeBurn,
+    /// Distortion, vignetting and chromatic aberration
+    Lens,
+}
+
+impl EditModule {
+    pub const ALL: [EditModule; 10] = [
+        EditModule::Tone,
+        EditModule::Color,
+        EditModule::Negative,
+        EditModule::Hsl,
+        EditModule::Grading,
+        EditModule::BlackAndWhite,
+        EditModule::Paper,
+        EditModule::LocalAdjustments,
+        EditModule::DodgeBurn,
+        EditModule::Lens,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            EditModule::Tone => "Tone",
+            EditModule::Color => "Color",
+            EditModule::Negative => "Negative",
+            EditModule::Hsl => "HSL",
+            EditModule::Grading => "Color grading",
+            EditModule::BlackAndWhite => "Black & white",
+            EditModule::Paper => "Paper",
+            EditModule::LocalAdjustments => "Local adjustments",
+            EditModule::DodgeBurn => "Dodge & burn",
+            EditModule::Lens => "Lens correction",
+        }
+    }
+
+    /// Whether the settings depend on what's in the frame, so they rarely
+    /// make sense on another image
+    pub fn is_local(&self) -> bool {
+        matches!(self, EditModule::LocalAdjustments | EditModule::DodgeBurn)
+    }
+
+    /// Every module that applies to the whole frame
+    pub fn global() -> Vec<EditModule> {
+        Self::ALL.into_iter().filter(|m| !m.is_local()).collect()
+    }
+
+    /// Copies the settings of this module, leaving everything else as it was
+    pub fn copy(&self, from: &db::Image, to: &mut db::Image) {
+        let (src, dst) = (&from.uniform, &mut to.uniform);
+
+        match self {
+            EditModule::Tone => {
+                dst.exposure = src.exposure;
+                dst.contrast = src.contrast;
+                dst.highlights = src.highlights;
+                dst.shadows = src.shadows;
+            }
+            EditModule::Color => {
+                dst.saturation = src.saturation;
+                dst.temperature = src.temperature;
+                dst.white_balance = src.white_balance;
+            }
+            EditModule::Negative => {
+                dst.input_matrix = src.input_matrix;
+                dst.invert = src.invert;
+                dst.film_base = src.film_base;
+            }
+            EditModule::Hsl => dst.hsl = src.hsl,
+            EditModule::Grading => dst.grading = src.grading,
+            EditModule::BlackAndWhite => dst.monochrome = src.monochrome,
+            EditModule::Paper => dst.paper = src.paper,
+            EditModule::LocalAdjustments => dst.masks = src.masks,
+            EditModule::DodgeBurn => to.strokes = from.strokes.clone(),
+            EditModule::Lens => dst.lens = src.lens,
+        }
+    }
+}
+
+/// Copies the settings of several modules at once
+pub fn copy_modules(modules: &[EditModule], from: &db::Image, to: &mut db::Image) {
+    for module in modules {
+        module.copy(from, to);
+    }
+}
+
+/// One checkbox per module, for picking which ones to copy
+pub fn modules_ui(ui: &mut egui::Ui, modules: &mut Vec<EditModule>) {
+    for module in EditModule::ALL {
+        let mut enabled = modules.contains(&module);
+        if ui.checkbox(&mut enabled, module.name()).changed() {
+            if enabled {
+                modules.push(module);
+            } else {
+                modules.retain(|m| *m != module);
+            }
+        }
+    }
+
+    ui.horizontal(|ui| {
+        if ui.small_button("All").clicked() {
+            *modules = EditModule::ALL.to_vec();
+        }
+        if ui.small_button("Global").clicked() {
+            *modules = EditModule::global();
+        }
+        if ui.small_button("None").clicked() {
+            modules.clear();
+        }
+    });
+}
@@ -0,0 +1,81 @@
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::darkroom::{brush::BrushStroke, uniform::FragmentUniform};
+use crate::lighttable::{
+    db,
+    module::{self, EditModule},
+};
+
+/// Where exported presets go, next to the catalog
+const PRESET_DIR: &str = "presets";
+
+/// A named set of edits that can be applied to any image. Only the modules
+/// it was saved with are applied, the rest of the edits are left alone.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Preset {
+    pub name: String,
+    pub modules: Vec<EditModule>,
+    pub uniform: FragmentUniform,
+    #[serde(default)]
+    pub strokes: Vec<BrushStroke>,
+}
+
+impl Preset {
+    /// Takes the chosen modules from the edits of an image
+    pub fn new(name: String, modules: Vec<EditModule>, edits: &db::Image) -> Self {
+        let mut saved = db::Image::default();
+        module::copy_modules(&modules, edits, &mut saved);
+
+        Self {
+            name,
+            modules,
+            uniform: saved.uniform,
+            strokes: saved.strokes,
+        }
+    }
+
+    pub fn apply(&self, to: &mut db::Image) {
+        let edits = db::Image {
+            uniform: self.uniform,
+            strokes: self.strokes.clone(),
+            ..Default::default()
+        };
+
+        module::copy_modules(&self.modules, &edits, to);
+    }
+
+    /// Writes the preset as a JSON file in the presets folder
+    pub fn export(&self) -> io::Result<PathBuf> {
+        fs::create_dir_all(PRESET_DIR)?;
+
+        let path = Path::new(PRESET_DIR).join(format!("{}.json", file_stem(&self.name)));
+        let json = serde_json::to_string_pretty(self)?;
+        fs::write(&path, json)?;
+
+        Ok(path)
+    }
+
+    pub fn import(path: &Path) -> io::Result<Self> {
+        let json = fs::read_to_string(path)?;
+
+        Ok(serde_json::from_str(&json)?)
+    }
+}
+
+/// The name of a preset, without anything that can't go in a file name
+fn file_stem(name: &str) -> String {
+    name.chars()
+        .map(|c| {
+            if c.is_alphanumeric() || c == '-' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect()
+}
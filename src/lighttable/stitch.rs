@@ -0,0 +1,921 @@
+//! Merging of overlapping shots of the same negative into a single image.
+//! Corners are found in every shot and matched between them, which tells
+//! how the shots sit relative to each other. They're then drawn onto one
+//! canvas, fading into each other where they overlap. Shots are kept in 16
+//! bits all the way, and the whole thing runs on its own thread, see
+//! [`StitchJob`]. Only one shot is held at full size at a time, corners are
+//! found on small copies and the shots are read again to be drawn.
+
+use std::{
+    fmt,
+    io::Cursor,
+    path::{Path, PathBuf},
+    sync::mpsc,
+    thread,
+};
+
+use image::{
+    codecs::tiff::TiffEncoder, imageops, DynamicImage, ExtendedColorType, ImageBuffer,
+    ImageDecoder, ImageEncoder, ImageReader, Luma, Rgb,
+};
+use rayon::prelude::*;
+
+use crate::darkroom::export::{self, ExportError};
+use crate::lighttable::THUMBNAIL_SIZE;
+
+/// Shots and stitched images, 16 bits per channel
+pub type Rgb16Image = ImageBuffer<Rgb<u16>, Vec<u16>>;
+type Gray16Image = ImageBuffer<Luma<u16>, Vec<u16>>;
+
+/// Long edge of the copies corners are looked for in
+const DETECTION_SIZE: u32 = 1024;
+/// Most corners kept per shot, the strongest ones
+const MAX_FEATURES: usize = 800;
+/// Corners have to be the strongest this far around them, in pixels of the
+/// detection copy
+const FEATURE_SPACING: i32 = 6;
+/// Patches describing a corner are this many samples on each side of it,
+/// this many pixels apart
+const PATCH_RADIUS: i32 = 4;
+const PATCH_STEP: i32 = 2;
+/// How much closer the best match has to be than the second best
+const MATCH_RATIO: f32 = 0.8;
+const RANSAC_ITERATIONS: usize = 2000;
+/// How far a match can land from where the alignment puts it, in pixels of
+/// the detection copy
+const INLIER_DISTANCE: f32 = 2.0;
+/// Matches needed to trust an alignment
+const MIN_INLIERS: usize = 12;
+/// The camera barely moves between shots, alignments that scale the image
+/// more than this are wrong
+const MAX_SCALE_CHANGE: f32 = 1.25;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum StitchError {
+    TooFewShots,
+    /// No other shot overlaps enough with the one at this index
+    NoOverlap(usize),
+}
+
+impl fmt::Display for StitchError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StitchError::TooFewShots => write!(f, "at least two shots are needed"),
+            StitchError::NoOverlap(i) => {
+                write!(f, "shot {} doesn't overlap enough with the others", i + 1)
+            }
+        }
+    }
+}
+
+/// What a stitch is busy with
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Step {
+    Reading,
+    FindingCorners,
+    Aligning,
+    Blending,
+    Saving,
+}
+
+impl Step {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Step::Reading => "reading the shots",
+            Step::FindingCorners => "finding corners",
+            Step::Aligning => "aligning",
+            Step::Blending => "blending the seams",
+            Step::Saving => "saving",
+        }
+    }
+}
+
+/// What a [`StitchJob`] reports back
+#[derive(Debug)]
+pub enum StitchUpdate {
+    Progress(Step),
+    /// The stitched image was saved at the path. Only a thumbnail comes
+    /// back, the darkroom reads the file itself.
+    Done {
+        path: PathBuf,
+        thumbnail: DynamicImage,
+    },
+    Failed(String),
+}
+
+/// A stitch running on its own thread, so the lighttable keeps responding
+pub struct StitchJob {
+    /// How many shots are being stitched
+    pub shots: usize,
+    /// The last step reported
+    pub step: Step,
+    updates: mpsc::Receiver<StitchUpdate>,
+}
+
+impl StitchJob {
+    /// Starts stitching the files, saving the result at `output`
+    pub fn start(paths: Vec<String>, output: PathBuf) -> Self {
+        let (sender, updates) = mpsc::channel();
+        let shots = paths.len();
+
+        thread::spawn(move || {
+            // Nobody is listening anymore if the lighttable went away
+            let progress = |step| {
+                let _ = sender.send(StitchUpdate::Progress(step));
+            };
+            let update = match stitch_files(&paths, &output, &progress) {
+                Ok(thumbnail) => StitchUpdate::Done {
+                    path: output,
+                    thumbnail,
+                },
+                Err(err) => StitchUpdate::Failed(err),
+            };
+            let _ = sender.send(update);
+        });
+
+        Self {
+            shots,
+            step: Step::Reading,
+            updates,
+        }
+    }
+
+    /// How the stitch ended, once it has. Progress is kept in
+    /// [`StitchJob::step`] until then.
+    pub fn poll(&mut self) -> Option<StitchUpdate> {
+        loop {
+            match self.updates.try_recv() {
+                Ok(StitchUpdate::Progress(step)) => self.step = step,
+                Ok(update) => return Some(update),
+                Err(mpsc::TryRecvError::Empty) => return None,
+                Err(mpsc::TryRecvError::Disconnected) => {
+                    return Some(StitchUpdate::Failed("the stitch stopped".to_string()))
+                }
+            }
+        }
+    }
+}
+
+/// Reads, stitches and saves the files, returning a thumbnail of the result
+fn stitch_files(
+    paths: &[String],
+    output: &Path,
+    progress: &dyn Fn(Step),
+) -> Result<DynamicImage, String> {
+    let read = |path: &String| match image::open(path) {
+        Ok(shot) => Ok(shot.to_rgb16()),
+        Err(err) => Err(format!("couldn't read {path}: {err}")),
+    };
+    let detections = paths
+        .iter()
+        .map(|path| {
+            progress(Step::Reading);
+            let shot = read(path)?;
+            progress(Step::FindingCorners);
+            Ok(detect(&shot))
+        })
+        .collect::<Result<Vec<_>, String>>()?;
+
+    progress(Step::Aligning);
+    let transforms = place(&detections).map_err(|err| err.to_string())?;
+
+    progress(Step::Blending);
+    let sizes: Vec<_> = detections.iter().map(|detection| detection.size).collect();
+    let mut canvas = Canvas::new(&sizes, &transforms);
+    for (path, transform) in paths.iter().zip(&transforms) {
+        canvas.add(&read(path)?, transform);
+    }
+    let stitched = canvas.finish();
+
+    progress(Step::Saving);
+    let icc = paths
+        .first()
+        .and_then(|path| embedded_profile(Path::new(path)));
+    if let Err(err) = save(&stitched, output, icc.as_deref()) {
+        log::error!("couldn't save {}: {err}", output.display());
+        return Err(format!("couldn't save the stitched image: {err}"));
+    }
+
+    Ok(DynamicImage::ImageRgb16(stitched).thumbnail(THUMBNAIL_SIZE, THUMBNAIL_SIZE))
+}
+
+/// A point of one shot and where it is in another
+type PointPair = ([f32; 2], [f32; 2]);
+
+/// Rotation, scale and translation. Points are taken as complex numbers
+/// `x + iy`, mapped to `(a + ib) * p + (tx + ity)`.
+#[derive(Debug, Copy, Clone, PartialEq)]
+struct Similarity {
+    a: f32,
+    b: f32,
+    tx: f32,
+    ty: f32,
+}
+
+impl Similarity {
+    const IDENTITY: Similarity = Similarity::scale(1.0);
+
+    const fn scale(s: f32) -> Self {
+        Self {
+            a: s,
+            b: 0.0,
+            tx: 0.0,
+            ty: 0.0,
+        }
+    }
+
+    fn apply(&self, [x, y]: [f32; 2]) -> [f32; 2] {
+        [
+            self.a * x - self.b * y + self.tx,
+            self.b * x + self.a * y + self.ty,
+        ]
+    }
+
+    /// This transform followed by `next`
+    fn then(&self, next: &Similarity) -> Self {
+        let [tx, ty] = next.apply([self.tx, self.ty]);
+
+        Self {
+            a: next.a * self.a - next.b * self.b,
+            b: next.a * self.b + next.b * self.a,
+            tx,
+            ty,
+        }
+    }
+
+    fn inverse(&self) -> Self {
+        let norm = self.a * self.a + self.b * self.b;
+        let (a, b) = (self.a / norm, -self.b / norm);
+
+        Self {
+            a,
+            b,
+            tx: -(a * self.tx - b * self.ty),
+            ty: -(b * self.tx + a * self.ty),
+        }
+    }
+
+    /// The transform taking the first points closest to the second ones,
+    /// in the least squares sense. Two pairs are enough.
+    fn fit(pairs: &[PointPair]) -> Option<Self> {
+        let n = pairs.len() as f32;
+        let mean = |point: fn(&PointPair) -> [f32; 2]| {
+            pairs
+                .iter()
+                .map(point)
+                .fold([0.0, 0.0], |sum, p| [sum[0] + p[0] / n, sum[1] + p[1] / n])
+        };
+        let from_mean = mean(|pair| pair.0);
+        let to_mean = mean(|pair| pair.1);
+
+        let (mut a, mut b, mut spread) = (0.0, 0.0, 0.0);
+        for (from, to) in pairs {
+            let [x, y] = [from[0] - from_mean[0], from[1] - from_mean[1]];
+            let [u, v] = [to[0] - to_mean[0], to[1] - to_mean[1]];
+            a += x * u + y * v;
+            b += x * v - y * u;
+            spread += x * x + y * y;
+        }
+        if spread < f32::EPSILON {
+            return None;
+        }
+
+        let mut transform = Self {
+            a: a / spread,
+            b: b / spread,
+            tx: 0.0,
+            ty: 0.0,
+        };
+        let [x, y] = transform.apply(from_mean);
+        transform.tx = to_mean[0] - x;
+        transform.ty = to_mean[1] - y;
+
+        Some(transform)
+    }
+
+    fn plausible(&self) -> bool {
+        let scale = (self.a * self.a + self.b * self.b).sqrt();
+        (1.0 / MAX_SCALE_CHANGE..=MAX_SCALE_CHANGE).contains(&scale)
+    }
+}
+
+/// A corner and the patch around it, normalized so it matches under
+/// different exposures
+struct Feature {
+    position: [f32; 2],
+    descriptor: Vec<f32>,
+}
+
+/// The corners of a shot, found on a scaled down copy
+struct Detection {
+    features: Vec<Feature>,
+    /// Detection copy pixels per shot pixel
+    scale: f32,
+    /// The size of the shot itself
+    size: (u32, u32),
+}
+
+/// How one shot sits in another
+#[derive(Debug, Copy, Clone)]
+struct Alignment {
+    /// From the pixels of one shot to the pixels of the other
+    transform: Similarity,
+    /// Matches agreeing with it, the more the more it can be trusted
+    inliers: usize,
+}
+
+/// Where every shot goes on the stitched image, the first one staying where
+/// it is. The shots can be in any order and layout, as long as each one
+/// overlaps with another.
+fn place(detections: &[Detection]) -> Result<Vec<Similarity>, StitchError> {
+    let count = detections.len();
+    if count < 2 {
+        return Err(StitchError::TooFewShots);
+    }
+
+    // How every shot sits in every other one, by index of the pair
+    let pairs: Vec<(usize, usize)> = (0..count)
+        .flat_map(|i| (i + 1..count).map(move |j| (i, j)))
+        .collect();
+    let alignments: Vec<Option<Alignment>> = pairs
+        .par_iter()
+        .map(|&(i, j)| align(&detections[j], &detections[i]))
+        .collect();
+
+    // Place the shots one at a time, always through the strongest alignment
+    // with one that's already placed
+    let mut placed: Vec<Option<Similarity>> = vec![None; count];
+    placed[0] = Some(Similarity::IDENTITY);
+    while let Some(next) = placed.iter().position(Option::is_none) {
+        let best = pairs
+            .iter()
+            .zip(&alignments)
+            .filter_map(|(&(i, j), alignment)| {
+                let alignment = alignment.as_ref()?;
+                match (placed[i], placed[j]) {
+                    // The alignment takes j into i
+                    (Some(into), None) => Some((j, alignment.transform.then(&into), alignment)),
+                    (None, Some(into)) => {
+                        Some((i, alignment.transform.inverse().then(&into), alignment))
+                    }
+                    _ => None,
+                }
+            })
+            .max_by_key(|(_, _, alignment)| alignment.inliers);
+
+        let Some((shot, transform, _)) = best else {
+            return Err(StitchError::NoOverlap(next));
+        };
+        placed[shot] = Some(transform);
+    }
+
+    Ok(placed.into_iter().flatten().collect())
+}
+
+/// Saves a stitched image as a TIFF, with the profile of the shots if they
+/// have one
+pub fn save(image: &Rgb16Image, path: &Path, icc: Option<&[u8]>) -> Result<(), ExportError> {
+    let (width, height) = image.dimensions();
+    let mut tiff = Cursor::new(vec![]);
+    // Encoders take 16 bit samples as native endian bytes
+    TiffEncoder::new(&mut tiff).write_image(
+        bytemuck::cast_slice(image.as_raw()),
+        width,
+        height,
+        ExtendedColorType::Rgb16,
+    )?;
+
+    let mut bytes = tiff.into_inner();
+    if let Some(icc) = icc {
+        bytes = export::embed_in_tiff(bytes, icc)?;
+    }
+    std::fs::write(path, bytes)?;
+
+    Ok(())
+}
+
+/// The profile embedded in an image file, if any
+pub fn embedded_profile(path: &Path) -> Option<Vec<u8>> {
+    let mut decoder = ImageReader::open(path)
+        .ok()?
+        .with_guessed_format()
+        .ok()?
+        .into_decoder()
+        .ok()?;
+
+    decoder.icc_profile().ok()?
+}
+
+fn detect(shot: &Rgb16Image) -> Detection {
+    let (width, height) = shot.dimensions();
+    let scale = (DETECTION_SIZE as f32 / width.max(height) as f32).min(1.0);
+
+    let gray = imageops::grayscale(shot);
+    let gray = imageops::resize(
+        &gray,
+        ((width as f32 * scale) as u32).max(1),
+        ((height as f32 * scale) as u32).max(1),
+        imageops::FilterType::Triangle,
+    );
+    let gray = imageops::blur(&gray, 1.0);
+
+    Detection {
+        features: features(&gray),
+        scale,
+        size: (width, height),
+    }
+}
+
+/// Harris corners, each described by the patch around it
+fn features(gray: &Gray16Image) -> Vec<Feature> {
+    let (width, height) = (gray.width() as i32, gray.height() as i32);
+    let value = |x: i32, y: i32| gray.get_pixel(x as u32, y as u32).0[0] as f32 / u16::MAX as f32;
+
+    // Corners too close to the edge don't have a full patch around them
+    let margin = PATCH_RADIUS * PATCH_STEP + 2;
+    if width <= 2 * margin || height <= 2 * margin {
+        return vec![];
+    }
+
+    let index = |x: i32, y: i32| (y * width + x) as usize;
+    let mut gradients = vec![[0.0; 2]; (width * height) as usize];
+    for y in 1..height - 1 {
+        for x in 1..width - 1 {
+            gradients[index(x, y)] = [
+                (value(x + 1, y) - value(x - 1, y)) / 2.0,
+                (value(x, y + 1) - value(x, y - 1)) / 2.0,
+            ];
+        }
+    }
+
+    let mut response = vec![0.0; (width * height) as usize];
+    for y in margin..height - margin {
+        for x in margin..width - margin {
+            let (mut xx, mut yy, mut xy) = (0.0, 0.0, 0.0);
+            for wy in -2..=2 {
+                for wx in -2..=2 {
+                    let [dx, dy] = gradients[index(x + wx, y + wy)];
+                    xx += dx * dx;
+                    yy += dy * dy;
+                    xy += dx * dy;
+                }
+            }
+
+            let trace = xx + yy;
+            response[index(x, y)] = xx * yy - xy * xy - 0.04 * trace * trace;
+        }
+    }
+
+    let strongest = |x: i32, y: i32, r: f32| {
+        (-FEATURE_SPACING..=FEATURE_SPACING).all(|wy| {
+            (-FEATURE_SPACING..=FEATURE_SPACING).all(|wx| {
+                let (px, py) = (x + wx, y + wy);
+                (wx == 0 && wy == 0)
+                    || px < 0
+                    || py < 0
+                    || px >= width
+                    || py >= height
+                    || response[index(px, py)] < r
+            })
+        })
+    };
+
+    let mut corners = vec![];
+    for y in margin..height - margin {
+        for x in margin..width - margin {
+            let r = response[index(x, y)];
+            if r > 0.0 && strongest(x, y, r) {
+                corners.push((r, x, y));
+            }
+        }
+    }
+    corners.sort_by(|a, b| b.0.total_cmp(&a.0));
+    corners.truncate(MAX_FEATURES);
+
+    corners
+        .into_iter()
+        .filter_map(|(_, x, y)| {
+            let mut descriptor = vec![];
+            for wy in -PATCH_RADIUS..=PATCH_RADIUS {
+                for wx in -PATCH_RADIUS..=PATCH_RADIUS {
+                    descriptor.push(value(x + wx * PATCH_STEP, y + wy * PATCH_STEP));
+                }
+            }
+
+            let mean = descriptor.iter().sum::<f32>() / descriptor.len() as f32;
+            descriptor.iter_mut().for_each(|v| *v -= mean);
+            let norm = descriptor.iter().map(|v| v * v).sum::<f32>().sqrt();
+            // Flat patches match anything
+            if norm < 1e-3 {
+                return None;
+            }
+            descriptor.iter_mut().for_each(|v| *v /= norm);
+
+            Some(Feature {
+                position: [x as f32, y as f32],
+                descriptor,
+            })
+        })
+        .collect()
+}
+
+/// How `from` sits in `to`, in shot pixels, if they overlap enough
+fn align(from: &Detection, to: &Detection) -> Option<Alignment> {
+    let matches = match_features(&from.features, &to.features);
+    if matches.len() < MIN_INLIERS {
+        return None;
+    }
+
+    let inliers_of = |transform: &Similarity| -> Vec<PointPair> {
+        matches
+            .iter()
+            .filter(|(source, target)| {
+                let [x, y] = transform.apply(*source);
+                (x - target[0]).hypot(y - target[1]) < INLIER_DISTANCE
+            })
+            .copied()
+            .collect()
+    };
+
+    let mut random = Random(0x2545_F491_4F6C_DD1D);
+    let mut best: Vec<PointPair> = vec![];
+    for _ in 0..RANSAC_ITERATIONS {
+        let i = random.below(matches.len());
+        let j = random.below(matches.len());
+        if i == j {
+            continue;
+        }
+
+        let Some(transform) = Similarity::fit(&[matches[i], matches[j]]) else {
+            continue;
+        };
+        if !transform.plausible() {
+            continue;
+        }
+
+        let inliers = inliers_of(&transform);
+        if inliers.len() > best.len() {
+            best = inliers;
+        }
+    }
+
+    if best.len() < MIN_INLIERS {
+        return None;
+    }
+    let transform = Similarity::fit(&best)?;
+    let inliers = inliers_of(&transform).len();
+
+    // Back from the detection copies to the shots
+    let transform = Similarity::scale(from.scale)
+        .then(&transform)
+        .then(&Similarity::scale(1.0 / to.scale));
+
+    Some(Alignment { transform, inliers })
+}
+
+/// Pairs of positions of features that look alike, keeping only the ones
+/// clearly closer to each other than to anything else
+fn match_features(from: &[Feature], to: &[Feature]) -> Vec<PointPair> {
+    from.par_iter()
+        .filter_map(|feature| {
+            let mut best = (f32::MAX, 0);
+            let mut second = f32::MAX;
+            for (i, other) in to.iter().enumerate() {
+                let dot: f32 = feature
+                    .descriptor
+                    .iter()
+                    .zip(&other.descriptor)
+                    .map(|(a, b)| a * b)
+                    .sum();
+                // Both are unit vectors
+                let distance = 2.0 - 2.0 * dot;
+
+                if distance < best.0 {
+                    second = best.0;
+                    best = (distance, i);
+                } else if distance < second {
+                    second = distance;
+                }
+            }
+
+            (best.0 < MATCH_RATIO * MATCH_RATIO * second)
+                .then(|| (feature.position, to[best.1].position))
+        })
+        .collect()
+}
+
+/// The stitched image, drawn one shot at a time. Where shots overlap, each
+/// pixel weighs by how far it is from the edge of its shot, so the seams
+/// fade away. Colors are summed along with their weights and only divided
+/// once every shot is in.
+struct Canvas {
+    width: u32,
+    height: u32,
+    /// Where the canvas starts, in pixels of the first shot
+    origin: Similarity,
+    /// The weighted sum of every channel, then the sum of the weights
+    pixels: Vec<[f32; 4]>,
+}
+
+impl Canvas {
+    /// A canvas big enough for shots of these sizes, placed by the transforms
+    fn new(sizes: &[(u32, u32)], transforms: &[Similarity]) -> Self {
+        let corners = sizes.iter().zip(transforms).flat_map(|(size, transform)| {
+            let (width, height) = (size.0 as f32, size.1 as f32);
+            [[0.0, 0.0], [width, 0.0], [0.0, height], [width, height]].map(|p| transform.apply(p))
+        });
+        let (mut min, mut max) = ([f32::MAX; 2], [f32::MIN; 2]);
+        for [x, y] in corners {
+            min = [min[0].min(x), min[1].min(y)];
+            max = [max[0].max(x), max[1].max(y)];
+        }
+
+        let width = (max[0] - min[0]).ceil() as u32;
+        let height = (max[1] - min[1]).ceil() as u32;
+        Self {
+            width,
+            height,
+            origin: Similarity {
+                tx: min[0],
+                ty: min[1],
+                ..Similarity::IDENTITY
+            },
+            pixels: vec![[0.0; 4]; width as usize * height as usize],
+        }
+    }
+
+    fn add(&mut self, shot: &Rgb16Image, transform: &Similarity) {
+        // From the canvas back to the shot
+        let to_shot = self.origin.then(&transform.inverse());
+        let (w, h) = (shot.width() as f32, shot.height() as f32);
+
+        self.pixels
+            .par_chunks_mut(self.width as usize)
+            .enumerate()
+            .for_each(|(y, row)| {
+                for (x, pixel) in row.iter_mut().enumerate() {
+                    let [sx, sy] = to_shot.apply([x as f32 + 0.5, y as f32 + 0.5]);
+                    let weight = sx.min(w - sx).min(sy).min(h - sy);
+                    if weight <= 0.0 {
+                        continue;
+                    }
+
+                    let color = sample(shot, sx - 0.5, sy - 0.5);
+                    for (sum, c) in pixel.iter_mut().zip(color) {
+                        *sum += c * weight;
+                    }
+                    pixel[3] += weight;
+                }
+            });
+    }
+
+    /// The blended colors, black where no shot reaches
+    fn finish(self) -> Rgb16Image {
+        let mut image = Rgb16Image::new(self.width, self.height);
+        image
+            .par_chunks_exact_mut(3)
+            .zip(self.pixels.par_iter())
+            .for_each(|(pixel, [r, g, b, total])| {
+                if *total > 0.0 {
+                    for (c, sum) in pixel.iter_mut().zip([r, g, b]) {
+                        *c = (sum / total).round() as u16;
+                    }
+                }
+            });
+
+        image
+    }
+}
+
+/// Bilinear sample, clamped to the edges
+fn sample(image: &Rgb16Image, x: f32, y: f32) -> [f32; 3] {
+    let max_x = image.width() as f32 - 1.0;
+    let max_y = image.height() as f32 - 1.0;
+    let (x, y) = (x.clamp(0.0, max_x), y.clamp(0.0, max_y));
+
+    let (x0, y0) = (x.floor(), y.floor());
+    let (x1, y1) = ((x0 + 1.0).min(max_x), (y0 + 1.0).min(max_y));
+    let (fx, fy) = (x - x0, y - y0);
+
+    let at = |x: f32, y: f32| image.get_pixel(x as u32, y as u32).0.map(|c| c as f32);
+    let (a, b, c, d) = (at(x0, y0), at(x1, y0), at(x0, y1), at(x1, y1));
+
+    [0, 1, 2].map(|i| {
+        let top = a[i] + (b[i] - a[i]) * fx;
+        let bottom = c[i] + (d[i] - c[i]) * fx;
+        top + (bottom - top) * fy
+    })
+}
+
+/// Xorshift, so alignments come out the same every time
+struct Random(u64);
+
+impl Random {
+    fn below(&mut self, n: usize) -> usize {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+
+        (self.0 % n as u64) as usize
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Overlapping rectangles of random colors, plenty of corners that
+    /// don't repeat
+    fn scene(width: u32, height: u32) -> Rgb16Image {
+        let mut random = Random(0x2545_f491_4f6c_dd1d);
+        let mut scene = Rgb16Image::from_pixel(width, height, Rgb([u16::MAX / 2; 3]));
+        for _ in 0..300 {
+            let (x, y) = (random.below(width as usize), random.below(height as usize));
+            let (w, h) = (8 + random.below(60), 8 + random.below(60));
+            let color = Rgb([0, 0, 0].map(|_| random.below(u16::MAX as usize) as u16));
+            for y in y..(y + h).min(height as usize) {
+                for x in x..(x + w).min(width as usize) {
+                    scene.put_pixel(x as u32, y as u32, color);
+                }
+            }
+        }
+
+        scene
+    }
+
+    fn crop(image: &Rgb16Image, x: u32, y: u32, width: u32, height: u32) -> Rgb16Image {
+        imageops::crop_imm(image, x, y, width, height).to_image()
+    }
+
+    fn assert_close(a: &Similarity, b: &Similarity, tolerance: f32) {
+        let close = (a.a - b.a).abs() < tolerance
+            && (a.b - b.b).abs() < tolerance
+            && (a.tx - b.tx).abs() < tolerance
+            && (a.ty - b.ty).abs() < tolerance;
+        assert!(close, "{a:?} isn't {b:?}");
+    }
+
+    fn rotation(angle: f32, tx: f32, ty: f32) -> Similarity {
+        Similarity {
+            a: 1.1 * angle.cos(),
+            b: 1.1 * angle.sin(),
+            tx,
+            ty,
+        }
+    }
+
+    #[test]
+    fn inverse_undoes_the_transform() {
+        let transform = rotation(0.3, 12.0, -40.0);
+
+        assert_close(
+            &transform.then(&transform.inverse()),
+            &Similarity::IDENTITY,
+            1e-4,
+        );
+        assert_close(
+            &transform.inverse().then(&transform),
+            &Similarity::IDENTITY,
+            1e-4,
+        );
+    }
+
+    #[test]
+    fn then_applies_in_order() {
+        let (first, second) = (rotation(0.3, 12.0, -40.0), rotation(-1.2, 3.0, 7.0));
+        let point = [25.0, -8.0];
+
+        let [x, y] = first.then(&second).apply(point);
+        let [u, v] = second.apply(first.apply(point));
+        assert!((x - u).abs() < 1e-3 && (y - v).abs() < 1e-3);
+    }
+
+    #[test]
+    fn fit_recovers_the_transform() {
+        let transform = rotation(0.3, 12.0, -40.0);
+        let pairs: Vec<_> = [[0.0, 0.0], [100.0, 20.0], [-30.0, 70.0], [50.0, 50.0]]
+            .into_iter()
+            .map(|point| (point, transform.apply(point)))
+            .collect();
+
+        assert_close(&Similarity::fit(&pairs).unwrap(), &transform, 1e-3);
+        assert_eq!(Similarity::fit(&[pairs[0], pairs[0]]), None);
+    }
+
+    #[test]
+    fn overlapping_crops_align() {
+        let scene = scene(700, 400);
+        let left = detect(&crop(&scene, 0, 0, 450, 400));
+        let right = detect(&crop(&scene, 200, 30, 450, 370));
+
+        let alignment = align(&right, &left).unwrap();
+        let expected = Similarity {
+            tx: 200.0,
+            ty: 30.0,
+            ..Similarity::IDENTITY
+        };
+        assert_close(&alignment.transform, &expected, 1.0);
+        assert!((alignment.transform.a - 1.0).abs() < 0.005);
+        assert!(alignment.inliers >= MIN_INLIERS);
+    }
+
+    #[test]
+    fn stitched_crops_fit_back_together() {
+        let scene = scene(700, 400);
+        let shots = [crop(&scene, 0, 0, 450, 400), crop(&scene, 200, 0, 500, 400)];
+
+        let directory = std::env::temp_dir().join(format!("emulse-stitch-{}", std::process::id()));
+        std::fs::create_dir_all(&directory).unwrap();
+        let paths: Vec<String> = shots
+            .iter()
+            .enumerate()
+            .map(|(i, shot)| {
+                let path = directory.join(format!("shot{i}.tif"));
+                save(shot, &path, None).unwrap();
+                path.to_string_lossy().into_owned()
+            })
+            .collect();
+        let output = directory.join("stitched.tif");
+
+        let steps = std::cell::RefCell::new(vec![]);
+        let stitched = stitch_files(&paths, &output, &|step| steps.borrow_mut().push(step))
+            .map(|_| image::open(&output).unwrap().to_rgb16());
+        std::fs::remove_dir_all(&directory).unwrap();
+        let stitched = stitched.unwrap();
+
+        let (read, find) = (Step::Reading, Step::FindingCorners);
+        assert_eq!(
+            *steps.borrow(),
+            [
+                read,
+                find,
+                read,
+                find,
+                Step::Aligning,
+                Step::Blending,
+                Step::Saving
+            ]
+        );
+
+        // Within a pixel of the scene, whichever way it rounds
+        let (width, height) = stitched.dimensions();
+        assert!((699..=701).contains(&width), "{width} wide");
+        assert!((399..=401).contains(&height), "{height} high");
+
+        // The first shot stays in place, so the scene starts where the
+        // stitched image does. Pixels that far from the edges of the
+        // rectangles come out the same even if the alignment is a little off.
+        let flat = |x: u32, y: u32| {
+            let color = scene.get_pixel(x, y);
+            (x - 2..=x + 2).all(|x| (y - 2..=y + 2).all(|y| scene.get_pixel(x, y) == color))
+        };
+        let mut compared = 0;
+        for y in (2..height.min(400) - 2).step_by(7) {
+            for x in (2..width.min(700) - 2).step_by(7) {
+                if !flat(x, y) {
+                    continue;
+                }
+                let expected = scene.get_pixel(x, y).0;
+                let pixel = stitched.get_pixel(x, y).0;
+                for (c, expected) in pixel.into_iter().zip(expected) {
+                    assert!(
+                        c.abs_diff(expected) <= 16,
+                        "{pixel:?} isn't {expected:?} at {x}, {y}"
+                    );
+                }
+                compared += 1;
+            }
+        }
+        assert!(compared > 1000, "only {compared} pixels compared");
+    }
+
+    #[test]
+    fn blend_covers_every_shot() {
+        let shots = [
+            Rgb16Image::from_pixel(100, 50, Rgb([1000; 3])),
+            Rgb16Image::from_pixel(100, 50, Rgb([3000; 3])),
+        ];
+        let transforms = [
+            Similarity::IDENTITY,
+            Similarity {
+                tx: 60.0,
+                ty: -20.0,
+                ..Similarity::IDENTITY
+            },
+        ];
+
+        let mut canvas = Canvas::new(&[(100, 50), (100, 50)], &transforms);
+        for (shot, transform) in shots.iter().zip(&transforms) {
+            canvas.add(shot, transform);
+        }
+        let canvas = canvas.finish();
+        assert_eq!(canvas.dimensions(), (160, 70));
+        // Only the first shot, only the second, and where they overlap
+        assert_eq!(canvas.get_pixel(10, 60).0, [1000; 3]);
+        assert_eq!(canvas.get_pixel(150, 10).0, [3000; 3]);
+        let overlap = canvas.get_pixel(80, 40).0[0];
+        assert!(1000 < overlap && overlap < 3000);
+        // Outside of both
+        assert_eq!(canvas.get_pixel(10, 5).0, [0; 3]);
+    }
+}